@@ -1,10 +1,14 @@
-#[macro_use]
 extern crate cft_ray_tracer as raytracer;
 
 use image::{ImageBuffer, Pixel, Rgb};
 
 use raytracer::{
+    aov::Aov,
+    display::{Display, ToneMap},
+    film::{Film, Filter},
+    image::ExrPixelType,
     light, material,
+    medium::HomogeneousMedium,
     object::{Object, Cube, Sphere, Square, World},
     Camera, Color, Vec3,
 };
@@ -26,22 +30,39 @@ fn main() {
         0.9,
     )));
 
+    // a spot light through thin fog, with a ball in the beam casting a shadow shaft
+    world.set_fog(HomogeneousMedium::new((0.02, 0.02, 0.02), (0.3, 0.3, 0.3)).with_asymmetry(0.3));
+    world.add_light(
+        light::SpotLight::new((-0.3, 0., 0.95), (0., 0., -1.))
+            .with_cone(15., 20.)
+            .with_intensity(4.),
+    );
+    world.add_obj(Object::new(Sphere::new((-0.3, 0., 0.5), 0.1), d));
+
     let camera =
         Camera::new(Vec3::new(0.8, 0.0, 0.0), Vec3::new(0., 0., 0.0)).with_sample_rate(SAMPLE_RATE);
-    let raw = Mutex::new(vec![(vec3!(0, 0, 0), 0); (WIDTH * HEIGHT) as usize]);
+    let film = Film::new(WIDTH as usize, HEIGHT as usize).with_filter(Filter::Mitchell {
+        radius: 2.,
+        b: 1. / 3.,
+        c: 1. / 3.,
+    })
+    .with_aovs(&Aov::ALL);
+    let film = Mutex::new(film);
     camera
-        .emit_rays(WIDTH, HEIGHT)
-        .map(|(w, h, ray)| (w, h, world.trace(&ray, 10)))
-        .for_each(|(w, h, p)| {
-            let mut raw = raw.lock().expect("fail to lock raw array");
-            raw[(h * WIDTH + w) as usize].0 += p;
-            raw[(h * WIDTH + w) as usize].1 += 1;
+        .emit_samples(WIDTH, HEIGHT)
+        .map(|(x, y, ray)| (x, y, world.trace_aovs(&ray, 10)))
+        .for_each(|(x, y, p)| {
+            let mut film = film.lock().expect("fail to lock film");
+            film.add_aov_sample(x, y, &p);
         });
-    let raw: Vec<_> = raw
-        .into_inner()
-        .unwrap()
-        .into_iter()
-        .map(|(pixel, count)| vec3_to_rgb(pixel / (count as f64)))
+    let film = film.into_inner().unwrap();
+    film.save_layers("test.exr", ExrPixelType::Float).unwrap();
+    let image = film.image();
+    let display = Display::new().with_tone_map(ToneMap::Aces);
+    let raw: Vec<_> = image
+        .pixels()
+        .iter()
+        .map(|&c| vec3_to_rgb(c, &display))
         .collect();
     let img = ImageBuffer::from_fn(WIDTH as u32, HEIGHT as u32, |w, h| {
         raw[(h * WIDTH as u32 + w) as usize]
@@ -49,10 +70,7 @@ fn main() {
     img.save("test.jpg").unwrap();
 }
 
-fn vec3_to_rgb(c: Color) -> Rgb<u8> {
-    let r = (255.99 * max!(0., min!(1., c.x)).sqrt()) as u8;
-    let g = (255.99 * max!(0., min!(1., c.y)).sqrt()) as u8;
-    let b = (255.99 * max!(0., min!(1., c.z)).sqrt()) as u8;
-    *Rgb::from_slice(&[r, g, b])
+fn vec3_to_rgb(c: Color, display: &Display) -> Rgb<u8> {
+    *Rgb::from_slice(&display.encode(c))
 }
 
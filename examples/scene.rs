@@ -11,6 +11,8 @@ use threadpool::ThreadPool;
 
 use raytracer::{
     Camera, Color,
+    display::{Display, ToneMap},
+    image::Image,
     light,
     material, object::{Object, Sphere, World},
     util::ChunkIter,
@@ -108,10 +110,18 @@ fn main() {
         duration.as_nanos() / (WIDTH * HEIGHT * SAMPLE_RATE) as u128
     );
 
-    let raw: Vec<_> = raw
+    let pixels = raw
         .lock()
         .iter()
-        .map(|pixel| vec3_to_rgb(*pixel / SAMPLE_RATE as f64))
+        .map(|pixel| *pixel / SAMPLE_RATE as f64)
+        .collect();
+    let image = Image::from_pixels(WIDTH as usize, HEIGHT as usize, pixels);
+    image.save("test.exr").unwrap();
+    let display = Display::new().with_tone_map(ToneMap::Hable);
+    let raw: Vec<_> = image
+        .pixels()
+        .iter()
+        .map(|&c| vec3_to_rgb(c, &display))
         .collect();
     let img = ImageBuffer::from_fn(WIDTH as u32, HEIGHT as u32, |w, h| {
         raw[(h * WIDTH as u32 + w) as usize]
@@ -119,11 +129,8 @@ fn main() {
     img.save("test.jpg").unwrap();
 }
 
-fn vec3_to_rgb(c: Color) -> Rgb<u8> {
-    let r = (255.99 * max!(0., min!(1., c.x)).sqrt()) as u8;
-    let g = (255.99 * max!(0., min!(1., c.y)).sqrt()) as u8;
-    let b = (255.99 * max!(0., min!(1., c.z)).sqrt()) as u8;
-    *Rgb::from_slice(&[r, g, b])
+fn vec3_to_rgb(c: Color, display: &Display) -> Rgb<u8> {
+    *Rgb::from_slice(&display.encode(c))
 }
 
 
@@ -0,0 +1,215 @@
+// arbitrary output variables, auxiliary passes of a render for compositing and denoising
+
+use crate::util::Color;
+
+/// auxiliary pass recorded next to the beauty image, all taken at the first surface a camera ray hits.
+/// passes are black where the ray hits nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Aov {
+    /// distance from the camera along the ray in every channel
+    Depth,
+    /// world space position of the hit point
+    Position,
+    /// shading normal in world space
+    Normal,
+    /// surface color, see `Material::albedo`
+    Albedo,
+    /// index of the object in the world plus one in every channel
+    ObjectId,
+    /// index of the first object sharing the material plus one in every channel
+    MaterialId,
+    /// light reaching the eye after a single bounce, including lights sampled by the material
+    Direct,
+    /// light reaching the eye after two or more bounces
+    Indirect,
+    /// lights seen directly and surfaces glowing by themselves
+    Emission,
+    /// fraction of the light at the hit point which is blocked, weighted by the light intensities
+    Shadow,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 10] = [
+        Aov::Depth,
+        Aov::Position,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::ObjectId,
+        Aov::MaterialId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Emission,
+        Aov::Shadow,
+    ];
+
+    /// layer name of the pass in output files.
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+            Aov::Shadow => "shadow",
+        }
+    }
+
+    /// whether samples are averaged by the film filter. ids would blend into meaningless
+    /// values at edges, so their pixels keep the sample closest to the center instead.
+    pub fn is_filtered(self) -> bool {
+        !matches!(self, Aov::ObjectId | Aov::MaterialId)
+    }
+}
+
+/// beauty radiance and all passes of one camera ray, see `World::trace_aovs`.
+/// `direct`, `indirect` and `emission` add up to `beauty`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AovSample {
+    pub beauty: Color,
+    pub depth: f64,
+    pub position: Color,
+    pub normal: Color,
+    pub albedo: Color,
+    pub object_id: usize,
+    pub material_id: usize,
+    pub direct: Color,
+    pub indirect: Color,
+    pub emission: Color,
+    pub shadow: f64,
+}
+
+impl AovSample {
+    /// sample of a ray which hits nothing.
+    pub fn empty() -> Self {
+        let black = (0., 0., 0.).into();
+        AovSample {
+            beauty: black,
+            depth: 0.,
+            position: black,
+            normal: black,
+            albedo: black,
+            object_id: 0,
+            material_id: 0,
+            direct: black,
+            indirect: black,
+            emission: black,
+            shadow: 0.,
+        }
+    }
+
+    /// value of the pass `aov` as a color.
+    pub fn get(&self, aov: Aov) -> Color {
+        let gray = |x: f64| Color::new(x, x, x);
+        match aov {
+            Aov::Depth => gray(self.depth),
+            Aov::Position => self.position,
+            Aov::Normal => self.normal,
+            Aov::Albedo => self.albedo,
+            Aov::ObjectId => gray(self.object_id as f64),
+            Aov::MaterialId => gray(self.material_id as f64),
+            Aov::Direct => self.direct,
+            Aov::Indirect => self.indirect,
+            Aov::Emission => self.emission,
+            Aov::Shadow => gray(self.shadow),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        film::Film,
+        light::{LightShape, PointLight, UniformLightSampler},
+        material::{LambertianModel, PhongModel},
+        object::{Object, Square, World},
+        ray::Ray,
+    };
+
+    #[test]
+    fn test_aovs() {
+        let square =
+            |x: f64, z: f64| Square::new(vec3!(x, 0, z), vec3!(1, 0, 0), vec3!(0, 1, 0), 2.);
+        let mut world = World::empty();
+        world.add_obj(Object::new(square(0., 0.), PhongModel::new()));
+        let mut other = Object::new(square(4., 0.), PhongModel::new());
+        other.material = world.objects[0].material.clone();
+        world.add_obj(other);
+        world.add_light(PointLight::new(vec3!(4, 0, 5)));
+
+        let sample = world.trace_aovs(&Ray::new(vec3!(0, 0, 2), vec3!(0, 0, -1)), 5);
+        assert_abs_diff_eq!(sample.depth, 2., epsilon = 1e-6);
+        assert_abs_diff_eq!(sample.position, vec3!(0, 0, 0), epsilon = 1e-6);
+        assert_abs_diff_eq!(sample.normal, vec3!(0, 0, 1));
+        assert_eq!((sample.object_id, sample.material_id), (1, 1));
+        assert_abs_diff_eq!(sample.shadow, 0.);
+        // phong only gathers light from the light sources
+        assert!(sample.direct.x > 0.);
+        assert_abs_diff_eq!(sample.indirect + sample.emission, vec3!(0, 0, 0));
+        assert_abs_diff_eq!(sample.beauty, sample.direct);
+
+        // the second square shares the material, and a third one shadows it
+        world.add_obj(Object::new(square(4., 3.), PhongModel::new()));
+        let sample = world.trace_aovs(&Ray::new(vec3!(4, 0, 2), vec3!(0, 0, -1)), 5);
+        assert_eq!((sample.object_id, sample.material_id), (2, 1));
+        assert_abs_diff_eq!(sample.shadow, 1.);
+        let miss = world.trace_aovs(&Ray::new(vec3!(0, 0, 2), vec3!(0, 0, 1)), 5);
+        assert_eq!(miss, AovSample::empty());
+
+        // ids keep the sample nearest to the pixel center, other passes are filtered
+        let mut film = Film::new(1, 1).with_aovs(&[Aov::Depth, Aov::ObjectId]);
+        let mut a = AovSample::empty();
+        a.depth = 1.;
+        a.object_id = 1;
+        let mut b = a;
+        b.depth = 3.;
+        b.object_id = 2;
+        film.add_aov_sample(0.1, 0.1, &a);
+        film.add_aov_sample(0.4, 0.6, &b);
+        assert_abs_diff_eq!(film.layer(Aov::Depth).unwrap().get(0, 0), vec3!(2, 2, 2));
+        assert_abs_diff_eq!(film.layer(Aov::ObjectId).unwrap().get(0, 0), vec3!(2, 2, 2));
+        assert!(film.layer(Aov::Normal).is_none());
+    }
+
+    #[test]
+    fn test_aov_lighting() {
+        let square =
+            |z: f64, len: f64| Square::new(vec3!(0, 0, z), vec3!(1, 0, 0), vec3!(0, 1, 0), len);
+        let ray = Ray::new(vec3!(0, 0, 2), vec3!(0, 0, -1));
+        // phong draws one of the lights, which is all direct light
+        let mut world = World::empty();
+        world.add_obj(Object::new(square(0., 2.), PhongModel::new()));
+        world.add_light(PointLight::new(vec3!(-1, 0, 3)));
+        world.add_light(PointLight::new(vec3!(1, 0, 3)));
+        world.set_light_sampler(UniformLightSampler::new(&world.lights));
+        for _ in 0..200 {
+            let sample = world.trace_aovs(&ray, 5);
+            assert_eq!(sample.indirect + sample.emission, vec3!(0, 0, 0));
+            assert!(sample.direct.x > 0.);
+            assert_abs_diff_eq!(sample.beauty, sample.direct);
+        }
+
+        // a diffuse floor under an area light, in a box of diffuse walls
+        let mut world = World::empty();
+        world.add_obj(Object::new(square(0., 2.), LambertianModel::new(0.8)));
+        world.add_obj(Object::new(square(3., 8.), LambertianModel::new(0.8)));
+        world.add_light(LightShape::new(square(1.5, 1.)));
+        let ray = Ray::new(vec3!(0.8, 0, 1), vec3!(0, 0, -1));
+        let (mut direct, mut indirect) = (0., 0.);
+        for _ in 0..1000 {
+            let sample = world.trace_aovs(&ray, 5);
+            for &c in [sample.direct, sample.indirect, sample.emission].iter() {
+                assert!(c.x >= 0. && c.y >= 0. && c.z >= 0.);
+            }
+            let sum = sample.direct + sample.indirect + sample.emission;
+            assert_abs_diff_eq!(sample.beauty, sum, epsilon = 1e-9);
+            direct += sample.direct.x;
+            indirect += sample.indirect.x;
+        }
+        assert!(direct > 0. && indirect > 0.);
+    }
+}
@@ -0,0 +1,443 @@
+use rand::prelude::*;
+
+use crate::{
+    ray::{Ray, RayDifferential},
+    util::*,
+};
+
+pub use self::{aperture::*, lens::*, stereo::*};
+
+mod aperture;
+mod lens;
+mod stereo;
+
+/// how a camera maps positions on the image to rays.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// pinhole or thin lens set up by the field of view, focus distance and aperture.
+    Perspective,
+    /// parallel rays along the sight through a view `width` units wide.
+    Orthographic { width: f64 },
+    /// full sphere of directions, with longitude along the width and latitude along the height.
+    /// the sight is at the center of the image.
+    Equirectangular,
+    /// six 90 degree views side by side, facing front, right, back, left, up and down.
+    /// side faces keep the camera up at the top, up and down faces have the front at the bottom
+    /// and the top respectively.
+    CubeMap,
+    /// fisheye with a field of view of `fov` degree across the image circle,
+    /// which touches the top and the bottom of the image. rays outside of it have zero weight.
+    Fisheye { fov: f64, mapping: FisheyeMapping },
+}
+
+/// how the distance from the center of a fisheye image maps to the angle from the sight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FisheyeMapping {
+    /// distance proportional to the angle
+    Equidistant,
+    /// equal areas on the image cover equal solid angles
+    Equisolid,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Camera {
+    pub pos: Vec3,
+    up: Vec3,
+    sight: Vec3,
+    sample_rate: u64,
+    focus_dist: f64,
+    aperture: f64,
+    aperture_shape: ApertureShape,
+    squeeze: f64,
+    tilt: (f64, f64),
+    fov: f64,
+    aspect: f64,
+    shift: (f64, f64),
+    projection: Projection,
+    differentials: bool,
+}
+
+impl Camera {
+    pub fn with_sample_rate(mut self, rate: u64) -> Self {
+        self.sample_rate = rate;
+        self
+    }
+
+    pub fn with_focus_dist(mut self, focus_dist: f64) -> Self {
+        self.focus_dist = focus_dist;
+        self
+    }
+
+    pub fn with_aperture(mut self, aperture: f64) -> Self {
+        self.aperture = aperture;
+        self
+    }
+
+    pub fn with_aperture_shape(mut self, shape: ApertureShape) -> Self {
+        self.aperture_shape = shape;
+        self
+    }
+
+    /// narrow the aperture horizontally by `squeeze`, like an anamorphic lens,
+    /// which stretches out of focus highlights into upright ovals.
+    pub fn with_anamorphic(mut self, squeeze: f64) -> Self {
+        self.squeeze = squeeze;
+        self
+    }
+
+    /// turn the plane in focus away from facing the camera, like a tilt lens.
+    /// positive `tilt` and `swing` in degree move its top and right part farther away.
+    pub fn with_tilt(mut self, tilt: f64, swing: f64) -> Self {
+        self.tilt = (tilt, swing);
+        self
+    }
+
+    pub fn with_fov(mut self, deg: f64) -> Self {
+        self.fov = deg / 180. * PI;
+        self
+    }
+
+    pub fn with_aspect(mut self, aspect: f64) -> Self {
+        self.aspect = aspect;
+        self
+    }
+
+    /// move the view right and up by fractions of its width and height, like a shift lens.
+    /// this keeps lines parallel to the image parallel, unlike turning the camera.
+    pub fn with_shift(mut self, x: f64, y: f64) -> Self {
+        self.shift = (x, y);
+        self
+    }
+
+    /// emit rays with differentials, which let textures filter over the pixel footprint.
+    pub fn with_differentials(mut self, enabled: bool) -> Self {
+        self.differentials = enabled;
+        self
+    }
+
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
+    /// adjust this camera to look at `point`.
+    pub fn look(&mut self, point: Vec3) {
+        self.sight = (point - self.pos).unit();
+        let right = self.right();
+        self.up = right.cross(self.sight).unit();
+    }
+
+    /// return up direction of this camera.
+    pub fn up(&self) -> Vec3 {
+        self.up
+    }
+
+    /// return right direction of this camera.
+    pub fn right(&self) -> Vec3 {
+        self.sight.cross(self.up).unit()
+    }
+
+    /// return sight direction of this camera.
+    pub fn sight(&self) -> Vec3 {
+        self.sight
+    }
+
+    /// ray through `(u, v)` in [0, 1]^2 of the image, with `(0, 0)` at the top left corner.
+    pub fn generate_ray(&self, u: f64, v: f64) -> Ray {
+        self.generate(u, v, self.sample_lens())
+    }
+
+    /// like `generate_ray`, with a differential of rays `du` to the right and `dv` down
+    /// through the same point of the lens.
+    pub fn generate_ray_differential(&self, u: f64, v: f64, du: f64, dv: f64) -> Ray {
+        self.generate_differential(u, v, du, dv, self.sample_lens())
+    }
+
+    fn generate_differential(&self, u: f64, v: f64, du: f64, dv: f64, lens: Option<Lens>) -> Ray {
+        let (rx, ry) = (
+            self.generate(u + du, v, lens),
+            self.generate(u, v + dv, lens),
+        );
+        self.generate(u, v, lens)
+            .with_differential(RayDifferential {
+                rx_pos: rx.pos(),
+                rx_dir: rx.dir(),
+                ry_pos: ry.pos(),
+                ry_dir: ry.dir(),
+            })
+    }
+
+    fn is_thin_lens(&self) -> bool {
+        self.aperture > 0. && self.projection == Projection::Perspective
+    }
+
+    fn sample_lens(&self) -> Option<Lens> {
+        if self.is_thin_lens() {
+            Some(self.aperture_shape.sample())
+        } else {
+            None
+        }
+    }
+
+    // ray through `(u, v)` from `lens` on the unit aperture with its weight, if any
+    fn generate(&self, u: f64, v: f64, lens: Option<Lens>) -> Ray {
+        let (x, y) = (u - 0.5, 0.5 - v);
+        match self.projection {
+            Projection::Perspective => {
+                // a screen focus distance away, through which rays from the lens are in focus
+                let fd = self.focus_dist;
+                let vh = 2. * (self.fov / 2.).tan() * fd;
+                let vw = vh * self.aspect;
+                let (x, y) = (x + self.shift.0, y + self.shift.1);
+                let (x, y) = (x * vw, y * vh);
+                let dir = fd * self.sight + x * self.right() + y * self.up();
+                let ((ax, ay), weight) = match lens {
+                    Some(lens) => lens,
+                    None => return Ray::new(self.pos, dir),
+                };
+                // the plane in focus is `y tan(tilt) + x tan(swing)` farther than the screen,
+                // with rays through the lens center meeting it at `t` times `dir`
+                let (tilt, swing) = (self.tilt.0 / 180. * PI, self.tilt.1 / 180. * PI);
+                let depth = fd - y * tilt.tan() - x * swing.tan();
+                if depth <= 0. {
+                    return Ray::new(self.pos, dir);
+                }
+                let to = self.pos + fd / depth * dir;
+
+                let r = self.aperture / 2.;
+                let offset = self.right() * (ax * r / self.squeeze) + self.up() * (ay * r);
+                let from = self.pos + offset;
+                Ray::new(from, to - from).with_weight(weight)
+            }
+            Projection::Orthographic { width } => {
+                let height = width / self.aspect;
+                let (x, y) = (x + self.shift.0, y + self.shift.1);
+                let from = self.pos + x * width * self.right() + y * height * self.up();
+                Ray::new(from, self.sight)
+            }
+            Projection::Equirectangular => {
+                let (lon, lat) = (2. * PI * x, PI * y);
+                let dir = lat.cos() * (lon.sin() * self.right() + lon.cos() * self.sight)
+                    + lat.sin() * self.up();
+                Ray::new(self.pos, dir)
+            }
+            Projection::CubeMap => {
+                let (s, r, up) = (self.sight, self.right(), self.up());
+                let faces = [
+                    (s, r, up),
+                    (r, -s, up),
+                    (-s, -r, up),
+                    (-r, s, up),
+                    (up, r, -s),
+                    (-up, r, s),
+                ];
+                let face = min!((u * 6.).floor(), 5.);
+                let (front, right, up) = faces[max!(face, 0.) as usize];
+                let x = 2. * (u * 6. - face) - 1.;
+                let y = 2. * y;
+                Ray::new(self.pos, front + x * right + y * up)
+            }
+            Projection::Fisheye { fov, mapping } => {
+                let (px, py) = (2. * x * self.aspect, 2. * y);
+                let r = (px * px + py * py).sqrt();
+                if r > 1. {
+                    return Ray::new(self.pos, self.sight).with_weight((0., 0., 0.));
+                }
+                let half = fov / 360. * PI;
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => r * half,
+                    FisheyeMapping::Equisolid => 2. * (r * (half / 2.).sin()).asin(),
+                };
+                let phi = py.atan2(px);
+                let side = phi.cos() * self.right() + phi.sin() * self.up();
+                Ray::new(self.pos, theta.cos() * self.sight + theta.sin() * side)
+            }
+        }
+    }
+
+    /// emit `sample_rate` rays through random points of every pixel.
+    /// with differentials, their offsets shrink as more rays share a pixel.
+    pub fn emit_rays(&self, width: u64, height: u64) -> impl Iterator<Item = (u64, u64, Ray)> + '_ {
+        self.samples(width, height)
+            .map(|(w, h, _, ray)| (w, h, ray))
+    }
+
+    /// like `emit_rays`, with the position of every ray on the image in pixels,
+    /// e.g. for a `Film` to filter them.
+    pub fn emit_samples(
+        &self,
+        width: u64,
+        height: u64,
+    ) -> impl Iterator<Item = (f64, f64, Ray)> + '_ {
+        self.samples(width, height).map(raster)
+    }
+
+    fn samples(&self, width: u64, height: u64) -> impl Iterator<Item = Sample> + '_ {
+        self.samples_through(width, height, move || self.sample_lens())
+    }
+
+    // samples with points on the aperture from `lens`
+    fn samples_through<'a, L>(
+        &'a self,
+        width: u64,
+        height: u64,
+        lens: L,
+    ) -> impl Iterator<Item = Sample> + 'a
+    where
+        L: Fn() -> Option<Lens> + Copy + 'a,
+    {
+        let scale = 1. / (self.sample_rate as f64).sqrt();
+        let (du, dv) = (scale / width as f64, scale / height as f64);
+        emit_samples(width, height, self.sample_rate, move |u, v| {
+            if self.differentials {
+                self.generate_differential(u, v, du, dv, lens())
+            } else {
+                self.generate(u, v, lens())
+            }
+        })
+    }
+
+    /// create a camera which is at `pos` and look at `point`.
+    pub fn new<T: Into<Vec3>>(from: T, to: T) -> Camera {
+        let mut camera = Camera {
+            pos: from.into(),
+            up: Vec3::new(0., 0., 1.),
+            sight: Vec3::new(0., 0., 1.),
+            sample_rate: 1,
+            focus_dist: 1.,
+            aperture: 0.,
+            aperture_shape: ApertureShape::Circle,
+            squeeze: 1.,
+            tilt: (0., 0.),
+            fov: 45.,
+            aspect: 1.,
+            shift: (0., 0.),
+            projection: Projection::Perspective,
+            differentials: false,
+        };
+        camera.look(to.into());
+        camera
+    }
+}
+
+// a point on the aperture of unit radius and the color it lets through
+type Lens = ((f64, f64), Color);
+
+// a ray through a pixel and its offset in the pixel
+type Sample = (u64, u64, (f64, f64), Ray);
+
+// position of a sample on the image in pixels
+fn raster((w, h, (dw, dh), ray): Sample) -> (f64, f64, Ray) {
+    (w as f64 + dw, h as f64 + dh, ray)
+}
+
+// `rate` rays from `generate` through random points of every pixel
+fn emit_samples<'a, F>(
+    width: u64,
+    height: u64,
+    rate: u64,
+    generate: F,
+) -> impl Iterator<Item = Sample> + 'a
+where
+    F: Fn(f64, f64) -> Ray + Copy + 'a,
+{
+    (0..width)
+        .flat_map(move |w| (0..height).map(move |h| (w, h)))
+        .flat_map(move |(w, h)| {
+            (0..rate).map(move |_| {
+                let mut rng = rand::thread_rng();
+                let (rw, rh): (f64, f64) = rng.gen();
+                let u = (w as f64 + rw) / width as f64;
+                let v = (h as f64 + rh) / height as f64;
+                (w, h, (rw, rh), generate(u, v))
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::Image;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_orthographic() {
+        let camera = Camera::new((0., -5., 0.), (0., 0., 0.))
+            .with_aspect(2.)
+            .with_projection(Projection::Orthographic { width: 4. });
+        let center = camera.generate_ray(0.5, 0.5);
+        assert_abs_diff_eq!(center.pos(), vec3!(0, -5, 0));
+        assert_abs_diff_eq!(center.dir(), vec3!(0, 1, 0));
+        // top left corner, 2 units left and 1 up
+        let corner = camera.generate_ray(0., 0.);
+        assert_abs_diff_eq!(corner.pos(), vec3!(-2, -5, 1), epsilon = 1e-9);
+        assert_abs_diff_eq!(corner.dir(), center.dir());
+    }
+
+    #[test]
+    fn test_aperture() {
+        let camera = Camera::new((0., 0., 0.), (0., 1., 0.))
+            .with_focus_dist(2.)
+            .with_aperture(0.5);
+        // a square turned to have its sides along the axes
+        let square = ApertureShape::Polygon {
+            blades: 4,
+            rotation: 45.,
+        };
+        for _ in 0..64 {
+            let ((x, y), _) = square.sample();
+            assert!(x.abs() <= 0.5f64.sqrt() + 1e-9 && y.abs() <= 0.5f64.sqrt() + 1e-9);
+        }
+        // only the left half of the mask lets light through
+        let image = Image::from_pixels(2, 1, vec![vec3!(1, 1, 1), vec3!(0, 0, 0)]);
+        let masked = camera.with_aperture_mask(Arc::new(ApertureMask::new(image)));
+        let squeezed = camera.with_anamorphic(2.);
+        for _ in 0..64 {
+            assert!(masked.generate_ray(0.5, 0.5).pos().x <= 0.);
+            assert!(squeezed.generate_ray(0.5, 0.5).pos().x.abs() <= 0.125);
+        }
+
+        // rays through a point of the image meet on the tilted plane in focus
+        let tilted = camera.with_tilt(30., 0.);
+        let pinhole = tilted.with_aperture(0.).generate_ray(0.5, 0.2);
+        let screen = pinhole.at(2. / pinhole.dir().y);
+        let focus = screen * (2. / (2. - screen.z * 30f64.to_radians().tan()));
+        assert!(focus.y > 2.);
+        for _ in 0..16 {
+            let ray = tilted.generate_ray(0.5, 0.2);
+            let meet = ray.at((focus.y - ray.pos().y) / ray.dir().y);
+            assert_abs_diff_eq!(meet, focus, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_panoramic() {
+        let camera = Camera::new((0., 0., 0.), (0., 1., 0.));
+        let (s, r, up) = (camera.sight(), camera.right(), camera.up());
+
+        let equirect = camera.with_projection(Projection::Equirectangular);
+        assert_abs_diff_eq!(equirect.generate_ray(0.5, 0.5).dir(), s, epsilon = 1e-9);
+        assert_abs_diff_eq!(equirect.generate_ray(0.75, 0.5).dir(), r, epsilon = 1e-9);
+        assert_abs_diff_eq!(equirect.generate_ray(0.5, 0.).dir(), up, epsilon = 1e-9);
+
+        let cube = camera.with_projection(Projection::CubeMap);
+        let center = |face: f64| cube.generate_ray((face + 0.5) / 6., 0.5).dir();
+        assert_abs_diff_eq!(center(0.), s, epsilon = 1e-9);
+        assert_abs_diff_eq!(center(2.), -s, epsilon = 1e-9);
+        assert_abs_diff_eq!(center(5.), -up, epsilon = 1e-9);
+        // the right edge of the front face meets the left edge of the right face
+        let edge = cube.generate_ray(1. / 6. - 1e-9, 0.5).dir();
+        assert_abs_diff_eq!(edge, cube.generate_ray(1. / 6., 0.5).dir(), epsilon = 1e-6);
+
+        for &mapping in [FisheyeMapping::Equidistant, FisheyeMapping::Equisolid].iter() {
+            let fisheye = camera.with_projection(Projection::Fisheye { fov: 180., mapping });
+            assert_abs_diff_eq!(fisheye.generate_ray(0.5, 0.5).dir(), s, epsilon = 1e-9);
+            assert_abs_diff_eq!(fisheye.generate_ray(0.5, 0.).dir(), up, epsilon = 1e-9);
+            assert_abs_diff_eq!(fisheye.generate_ray(0., 0.).weight(), vec3!(0, 0, 0));
+        }
+    }
+}
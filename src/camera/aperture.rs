@@ -0,0 +1,150 @@
+// shapes of the thin lens aperture, which show in out of focus highlights
+
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::{
+    image::Image,
+    ray::Ray,
+    sampling::Distribution2D,
+    util::{gen_point_in_disk, luminance, PI},
+};
+
+use super::{raster, Camera, Lens, Sample};
+
+/// shape of the aperture of a thin lens camera, sized by its aperture diameter.
+/// see `Camera::with_aperture_mask` for apertures from an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApertureShape {
+    Circle,
+    /// regular polygon of `blades` sides inscribed in the aperture circle,
+    /// turned counterclockwise by `rotation` degree.
+    Polygon {
+        blades: u32,
+        rotation: f64,
+    },
+}
+
+impl ApertureShape {
+    /// point on the aperture of unit radius and the color it lets through.
+    pub(crate) fn sample(&self) -> Lens {
+        let white = (1., 1., 1.).into();
+        match self {
+            ApertureShape::Circle => {
+                let p = gen_point_in_disk(1.);
+                ((p.x, p.y), white)
+            }
+            ApertureShape::Polygon { blades, .. } if *blades < 3 => ApertureShape::Circle.sample(),
+            ApertureShape::Polygon { blades, rotation } => {
+                let mut rng = rand::thread_rng();
+                // the triangles between the center and every side are equally likely
+                let n = *blades as f64;
+                let side = (rng.gen::<f64>() * n).floor();
+                let a0 = rotation / 180. * PI + 2. * PI * side / n;
+                let a1 = a0 + 2. * PI / n;
+                let (mut s, mut t): (f64, f64) = rng.gen();
+                if s + t > 1. {
+                    s = 1. - s;
+                    t = 1. - t;
+                }
+                let x = s * a0.cos() + t * a1.cos();
+                let y = s * a0.sin() + t * a1.sin();
+                ((x, y), white)
+            }
+        }
+    }
+}
+
+/// aperture transmission from an image spanning the square around the aperture circle,
+/// with `(0, 0)` at its top left corner.
+/// points are sampled by the luminance of the image and weighted by its color,
+/// so colored masks give colored bokeh.
+#[derive(Debug)]
+pub struct ApertureMask {
+    image: Image,
+    distribution: Distribution2D,
+}
+
+impl ApertureMask {
+    pub fn new(image: Image) -> Self {
+        let (w, h) = (image.width(), image.height());
+        let func: Vec<_> = image.pixels().iter().map(|&c| luminance(c)).collect();
+        ApertureMask {
+            distribution: Distribution2D::new(&func, w, h),
+            image,
+        }
+    }
+
+    fn sample(&self) -> Lens {
+        let (u0, u1): (f64, f64) = rand::thread_rng().gen();
+        let ((u, v), _) = self.distribution.sample(u0, u1);
+        let (w, h) = (self.image.width(), self.image.height());
+        let x = min!((u * w as f64) as usize, w - 1);
+        let y = min!((v * h as f64) as usize, h - 1);
+        let c = self.image.get(x, y);
+        let l = luminance(c);
+        let weight = if l > 0. { c / l } else { c };
+        ((2. * u - 1., 1. - 2. * v), weight)
+    }
+}
+
+impl Camera {
+    /// thin lens camera with the aperture shaped by `mask` instead of `ApertureShape`.
+    pub fn with_aperture_mask(self, mask: Arc<ApertureMask>) -> MaskedCamera {
+        MaskedCamera { camera: self, mask }
+    }
+}
+
+/// a camera with an `ApertureMask`, see `Camera::with_aperture_mask`.
+#[derive(Debug, Clone)]
+pub struct MaskedCamera {
+    camera: Camera,
+    mask: Arc<ApertureMask>,
+}
+
+impl MaskedCamera {
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// ray through `(u, v)` in [0, 1]^2 of the image, with `(0, 0)` at the top left corner.
+    pub fn generate_ray(&self, u: f64, v: f64) -> Ray {
+        self.camera.generate(u, v, self.sample_lens())
+    }
+
+    /// like `generate_ray`, with a differential of rays `du` to the right and `dv` down
+    /// through the same point of the lens.
+    pub fn generate_ray_differential(&self, u: f64, v: f64, du: f64, dv: f64) -> Ray {
+        self.camera
+            .generate_differential(u, v, du, dv, self.sample_lens())
+    }
+
+    fn sample_lens(&self) -> Option<Lens> {
+        if self.camera.is_thin_lens() {
+            Some(self.mask.sample())
+        } else {
+            None
+        }
+    }
+
+    /// emit `sample_rate` rays through random points of every pixel.
+    pub fn emit_rays(&self, width: u64, height: u64) -> impl Iterator<Item = (u64, u64, Ray)> + '_ {
+        self.samples(width, height)
+            .map(|(w, h, _, ray)| (w, h, ray))
+    }
+
+    /// like `emit_rays`, with the position of every ray on the image in pixels.
+    pub fn emit_samples(
+        &self,
+        width: u64,
+        height: u64,
+    ) -> impl Iterator<Item = (f64, f64, Ray)> + '_ {
+        self.samples(width, height).map(raster)
+    }
+
+    fn samples(&self, width: u64, height: u64) -> impl Iterator<Item = Sample> + '_ {
+        self.camera
+            .samples_through(width, height, move || self.sample_lens())
+    }
+}
@@ -0,0 +1,466 @@
+// camera tracing rays through the spherical elements of a real lens,
+// see `A Realistic Camera Model for Computer Graphics` (Kolb et al. 1995) and pbrt's
+// `RealisticCamera` for details.
+//
+// lens space is measured in millimeter, with the film at z = 0 and the scene towards +z.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, ErrorKind},
+    path::Path,
+};
+
+use rand::Rng;
+
+use crate::{material::refract, ray::Ray, util::*};
+
+use super::{emit_samples, raster, Camera, Sample};
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("lens: {}", msg))
+}
+
+/// one spherical surface of a lens prescription, all lengths in millimeter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LensElement {
+    /// radius of curvature, positive with the center towards the film and 0 for the aperture stop
+    pub radius: f64,
+    /// distance along the axis to the next surface, or to the film for the last one
+    pub thickness: f64,
+    /// index of refraction between this surface and the next one, 0 or 1 for air
+    pub ior: f64,
+    /// diameter of the surface
+    pub aperture: f64,
+}
+
+/// the elements of a lens from the front to the film.
+///
+/// lens files have one element per line as `radius thickness ior aperture` in millimeter,
+/// like the ones of pbrt. `#` starts a comment until the end of the line.
+#[derive(Debug, Clone)]
+pub struct LensSystem {
+    elements: Vec<LensElement>,
+    film_diagonal: f64,
+    scale: f64,
+}
+
+impl LensSystem {
+    pub fn new(elements: Vec<LensElement>) -> Self {
+        LensSystem {
+            elements,
+            film_diagonal: 43.27,
+            scale: 0.001,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<LensSystem> {
+        LensSystem::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: BufRead>(r: R) -> io::Result<LensSystem> {
+        let mut elements = Vec::new();
+        for line in r.lines() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("");
+            let numbers = line
+                .split_whitespace()
+                .map(|w| {
+                    w.parse::<f64>()
+                        .map_err(|_| invalid(&format!("bad number `{}`", w)))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            match numbers.as_slice() {
+                [] => continue,
+                &[radius, thickness, ior, aperture] => elements.push(LensElement {
+                    radius,
+                    thickness,
+                    ior,
+                    aperture,
+                }),
+                _ => return Err(invalid("expect radius, thickness, ior and aperture")),
+            }
+        }
+        if elements.is_empty() {
+            return Err(invalid("no lens elements"));
+        }
+        Ok(LensSystem::new(elements))
+    }
+
+    /// diagonal of the film in millimeter, 43.27 for full frame 35mm.
+    pub fn with_film_diagonal(mut self, mm: f64) -> Self {
+        self.film_diagonal = mm;
+        self
+    }
+
+    /// scene units per millimeter, 0.001 for scenes in meter.
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// stop the aperture down to `diameter` millimeter, at most its full size.
+    pub fn with_aperture(mut self, diameter: f64) -> Self {
+        for e in self.elements.iter_mut().filter(|e| e.radius == 0.) {
+            e.aperture = min!(e.aperture, diameter);
+        }
+        self
+    }
+
+    pub fn elements(&self) -> &[LensElement] {
+        &self.elements
+    }
+
+    fn rear_z(&self) -> f64 {
+        self.elements.last().map_or(0., |e| e.thickness)
+    }
+
+    fn rear_radius(&self) -> f64 {
+        self.elements.last().map_or(0., |e| e.aperture / 2.)
+    }
+
+    // z of the surfaces from the front to the back
+    fn surface_z(&self) -> Vec<f64> {
+        let mut z = 0.;
+        let mut zs: Vec<_> = self
+            .elements
+            .iter()
+            .rev()
+            .map(|e| {
+                z += e.thickness;
+                z
+            })
+            .collect();
+        zs.reverse();
+        zs
+    }
+
+    // bend `ray` at surface `i` into the medium with ior `eta_t`, `None` if it is blocked
+    fn interface(&self, i: usize, z: f64, ray: &Ray, eta_i: f64, eta_t: f64) -> Option<Ray> {
+        let e = self.elements[i];
+        let (o, d) = (ray.pos(), ray.dir());
+        let (point, n) = if e.radius == 0. {
+            let t = (z - o.z) / d.z;
+            if t <= 0. {
+                return None;
+            }
+            (ray.at(t), vec3!(0, 0, 1))
+        } else {
+            let center = vec3!(0, 0, z - e.radius);
+            let oc = o - center;
+            let b = oc.dot(d);
+            let c = oc.len2() - e.radius * e.radius;
+            let disc = b * b - c;
+            if disc < 0. {
+                return None;
+            }
+            let (t0, t1) = (-b - disc.sqrt(), -b + disc.sqrt());
+            // the part of the sphere around the vertex faces the film for a positive radius
+            let closer = (d.z > 0.) != (e.radius > 0.);
+            let t = if closer { t0 } else { t1 };
+            if t <= 0. {
+                return None;
+            }
+            let point = ray.at(t);
+            (point, (point - center).unit())
+        };
+        if point.x * point.x + point.y * point.y > e.aperture * e.aperture / 4. {
+            return None;
+        }
+        if e.radius == 0. || eta_i == eta_t {
+            return Some(Ray::new(point, d));
+        }
+        let n = if n.dot(d) > 0. { -n } else { n };
+        let dir = refract(-d, n, eta_t / eta_i)?;
+        Some(Ray::new(point, dir))
+    }
+
+    fn ior(&self, i: usize) -> f64 {
+        match self.elements.get(i) {
+            Some(e) if e.ior != 0. => e.ior,
+            _ => 1.,
+        }
+    }
+
+    /// trace `ray` from the film out of the front of the lens.
+    pub fn trace_from_film(&self, ray: &Ray) -> Option<Ray> {
+        let zs = self.surface_z();
+        let mut ray = *ray;
+        for i in (0..self.elements.len()).rev() {
+            let (eta_i, eta_t) = (self.ior(i), if i > 0 { self.ior(i - 1) } else { 1. });
+            ray = self.interface(i, zs[i], &ray, eta_i, eta_t)?;
+        }
+        Some(ray)
+    }
+
+    /// trace `ray` from the scene through the lens onto the film side.
+    pub fn trace_from_scene(&self, ray: &Ray) -> Option<Ray> {
+        let zs = self.surface_z();
+        let mut ray = *ray;
+        for (i, &z) in zs.iter().enumerate() {
+            let eta_i = if i > 0 { self.ior(i - 1) } else { 1. };
+            ray = self.interface(i, z, &ray, eta_i, self.ior(i))?;
+        }
+        Some(ray)
+    }
+
+    // z of the principal plane and of the focal point of rays parallel to the axis
+    fn cardinal_points(entering: &Ray, leaving: &Ray) -> (f64, f64) {
+        let tf = -leaving.pos().x / leaving.dir().x;
+        let tp = (entering.pos().x - leaving.pos().x) / leaving.dir().x;
+        (leaving.at(tp).z, leaving.at(tf).z)
+    }
+
+    /// focal length in millimeter, from a thick lens approximation.
+    pub fn focal_length(&self) -> Option<f64> {
+        let ((p, f), _) = self.thick_lens()?;
+        Some(p - f)
+    }
+
+    // principal planes and focal points on the film and the scene side
+    fn thick_lens(&self) -> Option<((f64, f64), (f64, f64))> {
+        let x = 0.001 * self.film_diagonal;
+        let front = self.surface_z().first().cloned()? + 1.;
+        let from_scene = Ray::new(vec3!(x, 0, front), vec3!(0, 0, -1));
+        let film_side = Self::cardinal_points(&from_scene, &self.trace_from_scene(&from_scene)?);
+        let from_film = Ray::new(vec3!(x, 0, 0), vec3!(0, 0, 1));
+        let scene_side = Self::cardinal_points(&from_film, &self.trace_from_film(&from_film)?);
+        Some((film_side, scene_side))
+    }
+
+    /// move the film to focus on `distance` scene units in front of it.
+    pub fn focus(mut self, distance: f64) -> Self {
+        if let Some(((p0, f0), (p1, _))) = self.thick_lens() {
+            // solve the thin lens equation between the principal planes for the film offset
+            let f = p0 - f0;
+            let (a, b) = (distance / self.scale - p1, p0);
+            let disc = (a + b) * (a + b - 4. * f);
+            if disc >= 0. {
+                let delta = 0.5 * (b - a + disc.sqrt());
+                if let Some(last) = self.elements.last_mut() {
+                    last.thickness -= delta;
+                }
+            }
+        }
+        self
+    }
+}
+
+// intervals along the film radius with their own exit pupil bounds
+const PUPIL_INTERVALS: usize = 32;
+
+// min and max corners of a rectangle
+type Bounds = ((f64, f64), (f64, f64));
+
+/// camera looking through a `LensSystem`, which gives physically based defocus,
+/// vignetting and distortion.
+///
+/// rays start at the film and are aimed at the bounds of the exit pupil seen from there.
+/// rays stopped inside the lens have zero weight.
+#[derive(Debug, Clone)]
+pub struct RealisticCamera {
+    camera: Camera,
+    lens: LensSystem,
+    // bounds on the rear plane as min and max corners, for film points along +x
+    pupils: Vec<Option<Bounds>>,
+}
+
+impl RealisticCamera {
+    /// look through `lens` focused at the focus distance of `camera`,
+    /// which also gives the position, orientation, aspect and sample rate.
+    pub fn new(camera: Camera, lens: LensSystem) -> Self {
+        let lens = lens.focus(camera.focus_dist);
+        let half = lens.film_diagonal / 2.;
+        let pupils = (0..PUPIL_INTERVALS)
+            .map(|i| {
+                let r0 = half * i as f64 / PUPIL_INTERVALS as f64;
+                let r1 = half * (i + 1) as f64 / PUPIL_INTERVALS as f64;
+                Self::bound_exit_pupil(&lens, r0, r1)
+            })
+            .collect();
+        RealisticCamera {
+            camera,
+            lens,
+            pupils,
+        }
+    }
+
+    pub fn lens(&self) -> &LensSystem {
+        &self.lens
+    }
+
+    // bounds on the rear plane of rays from film points between `r0` and `r1` passing the lens
+    fn bound_exit_pupil(lens: &LensSystem, r0: f64, r1: f64) -> Option<Bounds> {
+        let (n_film, n_grid) = (8, 24);
+        let (z, r) = (lens.rear_z(), 1.5 * lens.rear_radius());
+        let cell = 2. * r / n_grid as f64;
+        let mut bounds: Option<Bounds> = None;
+        for i in 0..n_film {
+            let x = r0 + (r1 - r0) * (i as f64 + 0.5) / n_film as f64;
+            let film = vec3!(x, 0, 0);
+            for j in 0..n_grid * n_grid {
+                let rear = vec3!(
+                    -r + cell * ((j % n_grid) as f64 + 0.5),
+                    -r + cell * ((j / n_grid) as f64 + 0.5),
+                    z
+                );
+                let inside = match bounds {
+                    Some(((x0, y0), (x1, y1))) => {
+                        (x0..=x1).contains(&rear.x) && (y0..=y1).contains(&rear.y)
+                    }
+                    None => false,
+                };
+                if inside || lens.trace_from_film(&Ray::new(film, rear - film)).is_some() {
+                    bounds = Some(bounds.map_or(((rear.x, rear.y), (rear.x, rear.y)), |b| {
+                        let ((x0, y0), (x1, y1)) = b;
+                        (
+                            (min!(x0, rear.x), min!(y0, rear.y)),
+                            (max!(x1, rear.x), max!(y1, rear.y)),
+                        )
+                    }));
+                }
+            }
+        }
+        // grow by a cell so that the edges sampled between grid points are kept
+        bounds.map(|((x0, y0), (x1, y1))| ((x0 - cell, y0 - cell), (x1 + cell, y1 + cell)))
+    }
+
+    fn pupil_area(&self, i: usize) -> f64 {
+        self.pupils[i].map_or(0., |((x0, y0), (x1, y1))| (x1 - x0) * (y1 - y0))
+    }
+
+    /// ray through `(u, v)` in [0, 1]^2 of the image, with `(0, 0)` at the top left corner.
+    pub fn generate_ray(&self, u: f64, v: f64) -> Ray {
+        let camera = &self.camera;
+        let blocked = Ray::new(camera.pos, camera.sight).with_weight((0., 0., 0.));
+        let aspect = camera.aspect;
+        let diagonal = self.lens.film_diagonal;
+        let height = diagonal / (1. + aspect * aspect).sqrt();
+        // the lens turns the image upside down
+        let film = vec3!(-(u - 0.5) * aspect * height, (v - 0.5) * height, 0);
+        let r = (film.x * film.x + film.y * film.y).sqrt();
+        let i = min!(
+            (r / (diagonal / 2.) * PUPIL_INTERVALS as f64) as usize,
+            PUPIL_INTERVALS - 1
+        );
+        let ((x0, y0), (x1, y1)) = match self.pupils[i] {
+            Some(b) => b,
+            None => return blocked,
+        };
+        let (su, sv): (f64, f64) = rand::thread_rng().gen();
+        let (px, py) = (x0 + (x1 - x0) * su, y0 + (y1 - y0) * sv);
+        // pupil bounds are for film points along +x, turn them to this one
+        let (sin, cos) = if r > 0. {
+            (film.y / r, film.x / r)
+        } else {
+            (0., 1.)
+        };
+        let rear = vec3!(cos * px - sin * py, sin * px + cos * py, self.lens.rear_z());
+        let from_film = Ray::new(film, rear - film);
+        let out = match self.lens.trace_from_film(&from_film) {
+            Some(out) => out,
+            None => return blocked,
+        };
+        // natural vignetting, relative to the center of the film
+        let cos_theta = from_film.dir().z;
+        let weight = cos_theta.powi(4) * self.pupil_area(i) / self.pupil_area(0);
+        let to_world = |v: Vec3| v.x * camera.right() + v.y * camera.up() + v.z * camera.sight;
+        let pos = camera.pos + self.lens.scale * to_world(out.pos());
+        Ray::new(pos, to_world(out.dir())).with_weight((weight, weight, weight))
+    }
+
+    /// emit `sample_rate` rays of the camera through random points of every pixel.
+    pub fn emit_rays(&self, width: u64, height: u64) -> impl Iterator<Item = (u64, u64, Ray)> + '_ {
+        self.samples(width, height)
+            .map(|(w, h, _, ray)| (w, h, ray))
+    }
+
+    /// like `emit_rays`, with the position of every ray on the image in pixels.
+    pub fn emit_samples(
+        &self,
+        width: u64,
+        height: u64,
+    ) -> impl Iterator<Item = (f64, f64, Ray)> + '_ {
+        self.samples(width, height).map(raster)
+    }
+
+    fn samples(&self, width: u64, height: u64) -> impl Iterator<Item = Sample> + '_ {
+        emit_samples(width, height, self.camera.sample_rate, move |u, v| {
+            self.generate_ray(u, v)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // a double gauss lens scaled to 50mm, from `Modern Lens Design` by Warren J. Smith
+    const DGAUSS: &str = "
+        # radius thickness ior aperture
+        29.475   3.76   1.67   25.2
+        84.83    0.12   1      25.2
+        19.275   4.025  1.67   23
+        40.77    3.275  1.699  23
+        12.75    5.705  1      18
+        0        4.5    0      17.1
+        -14.495  1.18   1.603  17
+        40.77    6.065  1.658  20
+        -20.385  0.19   1      20
+        437.065  3.22   1.717  20
+        -39.73   40     1      20
+    ";
+
+    #[test]
+    fn test_lens_system() {
+        let lens = LensSystem::read(DGAUSS.as_bytes()).unwrap();
+        assert_eq!(lens.elements().len(), 11);
+        assert_eq!(lens.elements()[5].radius, 0.);
+        let f = lens.focal_length().unwrap();
+        assert!((f - 50.).abs() < 2., "focal length {}", f);
+        assert!(LensSystem::read("1 2 3".as_bytes()).is_err());
+
+        // rays from a point in focus meet again on the film
+        let lens = lens.focus(2.);
+        let front = lens.surface_z()[0];
+        let object = vec3!(0, 0, 2000);
+        let hits: Vec<_> = [(1., 0.), (0., 3.), (-4., 2.)]
+            .iter()
+            .filter_map(|&(x, y)| {
+                let ray = Ray::new(object, vec3!(x, y, front) - object);
+                let out = lens.trace_from_scene(&ray)?;
+                Some(out.at(-out.pos().z / out.dir().z))
+            })
+            .collect();
+        assert_eq!(hits.len(), 3);
+        for p in hits {
+            assert!(p.x.abs() < 0.05 && p.y.abs() < 0.05, "{}", p);
+        }
+    }
+
+    #[test]
+    fn test_realistic_camera() {
+        let lens = LensSystem::read(DGAUSS.as_bytes())
+            .unwrap()
+            .with_aperture(8.);
+        let camera = Camera::new((0., 0., 0.), (0., 1., 0.)).with_focus_dist(2.);
+        let camera = RealisticCamera::new(camera, lens);
+        // about two thirds of the sampled rays make it through the lens
+        let rays: Vec<_> = (0..256).map(|_| camera.generate_ray(0.5, 0.5)).collect();
+        let passed: Vec<_> = rays.iter().filter(|r| r.weight().x > 0.).collect();
+        assert!(passed.len() > 128);
+        for ray in passed {
+            // the center looks ahead and is in focus at the focus distance
+            assert!(ray.dir().y > 0.99);
+            let p = ray.at((2. - ray.pos().y) / ray.dir().y);
+            assert!(p.x.abs() < 1e-3 && p.z.abs() < 1e-3, "{}", p);
+        }
+        // corners get less light
+        let corner = (0..256)
+            .map(|_| camera.generate_ray(0.02, 0.02).weight().x)
+            .sum::<f64>();
+        let center = (0..256)
+            .map(|_| camera.generate_ray(0.5, 0.5).weight().x)
+            .sum::<f64>();
+        assert!(corner < center);
+    }
+}
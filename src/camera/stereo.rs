@@ -0,0 +1,157 @@
+// stereoscopic rendering for 3d displays and vr headsets
+
+use crate::{ray::Ray, util::*};
+
+use super::{emit_samples, raster, Camera, Projection, Sample};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// how the views of both eyes share one image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    /// left eye on the left half
+    SideBySide,
+    /// left eye on the top half
+    OverUnder,
+}
+
+/// a pair of cameras an interocular distance apart, rendered into one image.
+///
+/// perspective eyes look in parallel and shift their views to meet at the convergence distance,
+/// which shows at the depth of the display. an equirectangular camera renders an
+/// omnidirectional stereo (ods) panorama instead, with the eyes circling around its position.
+#[derive(Debug, Clone, Copy)]
+pub struct StereoCamera {
+    camera: Camera,
+    interocular: f64,
+    convergence: f64,
+    layout: StereoLayout,
+}
+
+impl StereoCamera {
+    /// stereo rig around `camera`, which is set up for a single eye.
+    pub fn new(camera: Camera) -> Self {
+        StereoCamera {
+            camera,
+            interocular: 0.065,
+            convergence: 10.,
+            layout: StereoLayout::SideBySide,
+        }
+    }
+
+    pub fn with_interocular(mut self, distance: f64) -> Self {
+        self.interocular = distance;
+        self
+    }
+
+    pub fn with_convergence(mut self, distance: f64) -> Self {
+        self.convergence = distance;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: StereoLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    fn offset(&self, eye: Eye) -> f64 {
+        match eye {
+            Eye::Left => -self.interocular / 2.,
+            Eye::Right => self.interocular / 2.,
+        }
+    }
+
+    /// the camera of one `eye`.
+    pub fn eye(&self, eye: Eye) -> Camera {
+        let mut camera = self.camera;
+        if camera.projection == Projection::Equirectangular {
+            return camera;
+        }
+        let offset = self.offset(eye);
+        camera.pos += offset * camera.right();
+        if camera.projection == Projection::Perspective {
+            // view width at unit distance
+            let width = 2. * (camera.fov / 2.).tan() * camera.aspect;
+            camera.shift.0 -= offset / (self.convergence * width);
+        }
+        camera
+    }
+
+    /// ray through `(u, v)` in [0, 1]^2 of the whole image, with `(0, 0)` at the top left corner.
+    pub fn generate_ray(&self, u: f64, v: f64) -> Ray {
+        let (eye, u, v) = match self.layout {
+            StereoLayout::SideBySide if u < 0.5 => (Eye::Left, 2. * u, v),
+            StereoLayout::SideBySide => (Eye::Right, 2. * u - 1., v),
+            StereoLayout::OverUnder if v < 0.5 => (Eye::Left, u, 2. * v),
+            StereoLayout::OverUnder => (Eye::Right, u, 2. * v - 1.),
+        };
+        let camera = self.eye(eye);
+        let ray = camera.generate_ray(u, v);
+        if camera.projection != Projection::Equirectangular {
+            return ray;
+        }
+        // each direction is seen from the point of a circle where it is tangent
+        let lon = 2. * PI * (u - 0.5);
+        let side = lon.cos() * camera.right() - lon.sin() * camera.sight();
+        let pos = ray.pos() + self.offset(eye) * side;
+        Ray::new(pos, ray.dir()).with_weight(ray.weight())
+    }
+
+    /// emit `sample_rate` rays of the camera through random points of every pixel.
+    pub fn emit_rays(&self, width: u64, height: u64) -> impl Iterator<Item = (u64, u64, Ray)> + '_ {
+        self.samples(width, height)
+            .map(|(w, h, _, ray)| (w, h, ray))
+    }
+
+    /// like `emit_rays`, with the position of every ray on the image in pixels.
+    pub fn emit_samples(
+        &self,
+        width: u64,
+        height: u64,
+    ) -> impl Iterator<Item = (f64, f64, Ray)> + '_ {
+        self.samples(width, height).map(raster)
+    }
+
+    fn samples(&self, width: u64, height: u64) -> impl Iterator<Item = Sample> + '_ {
+        emit_samples(width, height, self.camera.sample_rate, move |u, v| {
+            self.generate_ray(u, v)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stereo() {
+        let camera = Camera::new((0., 0., 0.), (0., 1., 0.)).with_fov(60.);
+        let rig = StereoCamera::new(camera)
+            .with_interocular(0.1)
+            .with_convergence(5.);
+        // both eyes look at the same point at the convergence distance
+        let left = rig.generate_ray(0.25, 0.5);
+        let right = rig.generate_ray(0.75, 0.5);
+        assert_abs_diff_eq!(left.pos(), vec3!(-0.05, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(right.pos(), vec3!(0.05, 0, 0), epsilon = 1e-9);
+        let meet = |r: Ray| r.at(5. / r.dir().y);
+        assert_abs_diff_eq!(meet(left), vec3!(0, 5, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(meet(right), vec3!(0, 5, 0), epsilon = 1e-9);
+
+        let over_under = rig.with_layout(StereoLayout::OverUnder);
+        assert_abs_diff_eq!(over_under.generate_ray(0.5, 0.25).pos(), left.pos());
+
+        // ods rays leave a circle of the interocular distance tangentially
+        let ods = StereoCamera::new(camera.with_projection(Projection::Equirectangular))
+            .with_interocular(0.1);
+        for &(u, v) in [(0.1, 0.5), (0.3, 0.4), (0.45, 0.7)].iter() {
+            let ray = ods.generate_ray(u, v);
+            assert_abs_diff_eq!(ray.pos().len(), 0.05, epsilon = 1e-9);
+            assert_abs_diff_eq!(ray.pos().dot(ray.dir()), 0., epsilon = 1e-9);
+        }
+    }
+}
@@ -0,0 +1,280 @@
+// turning rendered radiance into colors for a display: exposure, white balance,
+// tone mapping and the srgb transfer curve
+
+use crate::{
+    image::Image,
+    spectrum::{blackbody_normalized, integrate_rgb},
+    util::{luminance, Color},
+};
+
+type Matrix = [[f64; 3]; 3];
+
+// bradford adapted between the d60 white of aces and d65 of srgb
+const ACESCG_TO_SRGB: Matrix = [
+    [1.705_050_99, -0.621_792_12, -0.083_258_87],
+    [-0.130_256_42, 1.140_804_74, -0.010_548_32],
+    [-0.024_003_36, -0.128_968_98, 1.152_972_33],
+];
+const SRGB_TO_ACESCG: Matrix = [
+    [0.613_097_40, 0.339_523_15, 0.047_379_45],
+    [0.070_193_72, 0.916_353_88, 0.013_452_40],
+    [0.020_615_60, 0.109_569_78, 0.869_814_64],
+];
+
+// the fit of the aces reference rendering and output transforms by Stephen Hill,
+// working in srgb with the conversions to and from the rrt's space folded in
+const ACES_INPUT: Matrix = [
+    [0.597_19, 0.354_58, 0.048_23],
+    [0.076_00, 0.908_34, 0.015_66],
+    [0.028_40, 0.133_83, 0.837_77],
+];
+const ACES_OUTPUT: Matrix = [
+    [1.604_75, -0.531_08, -0.073_67],
+    [-0.102_08, 1.108_13, -0.006_05],
+    [-0.003_27, -0.072_76, 1.076_02],
+];
+
+fn apply(m: &Matrix, c: Color) -> Color {
+    vec3!(
+        m[0][0] * c.x + m[0][1] * c.y + m[0][2] * c.z,
+        m[1][0] * c.x + m[1][1] * c.y + m[1][2] * c.z,
+        m[2][0] * c.x + m[2][1] * c.y + m[2][2] * c.z
+    )
+}
+
+/// rgb primaries that scene colors and rendered radiance are given in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    /// rec. 709 primaries with a d65 white, as everywhere else in this crate
+    LinearSrgb,
+    /// the wider gamut aces ap1 primaries with a d60 white, common in film pipelines
+    AcesCg,
+}
+
+impl ColorSpace {
+    pub fn to_srgb(self, c: Color) -> Color {
+        match self {
+            ColorSpace::LinearSrgb => c,
+            ColorSpace::AcesCg => apply(&ACESCG_TO_SRGB, c),
+        }
+    }
+
+    pub fn from_srgb(self, c: Color) -> Color {
+        match self {
+            ColorSpace::LinearSrgb => c,
+            ColorSpace::AcesCg => apply(&SRGB_TO_ACESCG, c),
+        }
+    }
+}
+
+/// curve compressing high dynamic range linear srgb into [0, 1].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMap {
+    /// no compression, values above 1 clip
+    Clamp,
+    /// `l (1 + l / white^2) / (1 + l)` of the luminance, which keeps hues and maps `white` to 1.
+    /// see `Photographic Tone Reproduction for Digital Images` (Reinhard et al. 2002)
+    Reinhard { white: f64 },
+    /// the filmic curve of John Hable for Uncharted 2, with a toe and a soft shoulder
+    Hable,
+    /// fit of the aces filmic look, with more contrast and desaturated highlights
+    Aces,
+}
+
+fn hable(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.5, 0.1, 0.2, 0.02, 0.3);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+fn aces_fit(x: f64) -> f64 {
+    let a = x * (x + 0.024_578_6) - 0.000_090_537;
+    let b = x * (0.983_729 * x + 0.432_951) + 0.238_081;
+    a / b
+}
+
+impl ToneMap {
+    pub fn apply(&self, c: Color) -> Color {
+        let c = vec3!(max!(c.x, 0.), max!(c.y, 0.), max!(c.z, 0.));
+        let c = match *self {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard { white } => {
+                let l = luminance(c);
+                if l <= 0. {
+                    return (0., 0., 0.).into();
+                }
+                let mapped = l * (1. + l / (white * white)) / (1. + l);
+                c * (mapped / l)
+            }
+            ToneMap::Hable => {
+                // exposure bias and linear white point of the original
+                let scale = 1. / hable(11.2);
+                let map = |x: f64| hable(2. * x) * scale;
+                vec3!(map(c.x), map(c.y), map(c.z))
+            }
+            ToneMap::Aces => {
+                let c = apply(&ACES_INPUT, c);
+                apply(
+                    &ACES_OUTPUT,
+                    vec3!(aces_fit(c.x), aces_fit(c.y), aces_fit(c.z)),
+                )
+            }
+        };
+        let clamp = |x: f64| max!(0., min!(x, 1.));
+        vec3!(clamp(c.x), clamp(c.y), clamp(c.z))
+    }
+}
+
+/// encode a linear value in [0, 1] with the srgb transfer curve.
+pub fn srgb_encode(x: f64) -> f64 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// decode an srgb encoded value in [0, 1] to linear.
+pub fn srgb_decode(x: f64) -> f64 {
+    if x <= 0.040_45 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// the way from rendered radiance to 8 bit srgb pixels.
+///
+/// colors are converted from the working color space to linear srgb, scaled by the
+/// exposure and the white balance, tone mapped and finally encoded by the srgb curve.
+#[derive(Debug, Clone, Copy)]
+pub struct Display {
+    exposure: f64,
+    balance: Color,
+    tone_map: ToneMap,
+    color_space: ColorSpace,
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Display {
+            exposure: 0.,
+            balance: (1., 1., 1.).into(),
+            tone_map: ToneMap::Clamp,
+            color_space: ColorSpace::LinearSrgb,
+        }
+    }
+
+    /// brighten by `stops`, doubling for each one.
+    pub fn with_exposure(mut self, stops: f64) -> Self {
+        self.exposure = stops;
+        self
+    }
+
+    /// make white objects lit by a black body at `kelvin` look neutral,
+    /// e.g. 3200 for tungsten light. 6500 is about neutral already.
+    pub fn with_white_balance(mut self, kelvin: f64) -> Self {
+        let white = integrate_rgb(|l| blackbody_normalized(l, kelvin));
+        let l = luminance(white);
+        self.balance = vec3!(l / white.x, l / white.y, l / white.z);
+        self
+    }
+
+    pub fn with_tone_map(mut self, tone_map: ToneMap) -> Self {
+        self.tone_map = tone_map;
+        self
+    }
+
+    /// color space of the rendered values, linear srgb by default.
+    pub fn with_color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    /// displayed linear srgb in [0, 1] of a rendered color `c`.
+    pub fn linear(&self, c: Color) -> Color {
+        let c = self.color_space.to_srgb(c) * 2f64.powf(self.exposure) * self.balance;
+        self.tone_map.apply(c)
+    }
+
+    /// 8 bit srgb of a rendered color `c`.
+    pub fn encode(&self, c: Color) -> [u8; 3] {
+        let c = self.linear(c);
+        let byte = |x: f64| (srgb_encode(x) * 255. + 0.5) as u8;
+        [byte(c.x), byte(c.y), byte(c.z)]
+    }
+
+    /// 8 bit srgb of all pixels of `image`, row by row from the top left corner.
+    pub fn encode_image(&self, image: &Image) -> Vec<u8> {
+        image
+            .pixels()
+            .iter()
+            .flat_map(|&c| self.encode(c).to_vec())
+            .collect()
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_srgb() {
+        assert_abs_diff_eq!(srgb_encode(0.), 0.);
+        assert_abs_diff_eq!(srgb_encode(1.), 1., epsilon = 1e-9);
+        assert_abs_diff_eq!(srgb_encode(0.18), 0.461_356, epsilon = 1e-6);
+        for &x in [0.001, 0.2, 0.5, 0.9].iter() {
+            assert_abs_diff_eq!(srgb_decode(srgb_encode(x)), x, epsilon = 1e-9);
+        }
+        let c = vec3!(0.2, 0.5, 0.8);
+        let space = ColorSpace::AcesCg;
+        assert_abs_diff_eq!(space.to_srgb(space.from_srgb(c)), c, epsilon = 1e-6);
+        // white stays white in both
+        let white = vec3!(1, 1, 1);
+        assert_abs_diff_eq!(space.to_srgb(white), white, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_display() {
+        for &op in [
+            ToneMap::Clamp,
+            ToneMap::Reinhard { white: 4. },
+            ToneMap::Hable,
+            ToneMap::Aces,
+        ]
+        .iter()
+        {
+            let display = Display::new().with_tone_map(op);
+            assert_eq!(display.encode(vec3!(0, 0, 0)), [0, 0, 0]);
+            let mut last = 0.;
+            for &x in [0.01, 0.1, 0.5, 1., 3., 100.].iter() {
+                let y = display.linear(vec3!(x, x, x)).y;
+                assert!(y >= last && y <= 1.);
+                last = y;
+            }
+            assert!(last > 0.8);
+        }
+        let reinhard = ToneMap::Reinhard { white: 4. };
+        assert_abs_diff_eq!(
+            reinhard.apply(vec3!(4, 4, 4)),
+            vec3!(1, 1, 1),
+            epsilon = 1e-9
+        );
+
+        let display = Display::new().with_exposure(1.);
+        assert_abs_diff_eq!(display.linear(vec3!(0.25, 0.25, 0.25)).x, 0.5);
+        assert_eq!(display.encode(vec3!(0.5, 0.5, 0.5)), [255, 255, 255]);
+
+        // tungsten light balanced to neutral
+        let tungsten = integrate_rgb(|l| blackbody_normalized(l, 3200.));
+        let c = Display::new()
+            .with_white_balance(3200.)
+            .linear(tungsten * 0.1);
+        assert_abs_diff_eq!(c.x, c.z, epsilon = 1e-9);
+    }
+}
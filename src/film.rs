@@ -0,0 +1,363 @@
+// reconstruction of the image from samples at random positions on it,
+// see `Reconstruction Filters in Computer Graphics` (Mitchell and Netravali 1988)
+
+use std::{io, path::Path};
+
+use crate::{
+    aov::{Aov, AovSample},
+    image::{self, ExrPixelType, Image},
+    util::{Color, PI},
+};
+
+/// weights of samples around a pixel center by their offset in pixels.
+/// all are separable and zero beyond `radius` along both axes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    /// equal weights, which with a radius of 0.5 averages the samples in each pixel
+    Box { radius: f64 },
+    /// weights falling linearly to zero at the radius
+    Tent { radius: f64 },
+    /// gaussian of falloff `alpha`, shifted down to reach zero at the radius.
+    /// smaller values are blurrier, 2 is a good start.
+    Gaussian { radius: f64, alpha: f64 },
+    /// cubic of Mitchell and Netravali, with `b = c = 1/3` recommended.
+    /// larger `b` blurs, larger `c` sharpens with ringing.
+    Mitchell { radius: f64, b: f64, c: f64 },
+    /// sinc windowed by a sinc stretched `tau` times, with 3 a common choice.
+    /// the sharpest one, but ringing around edges.
+    Lanczos { radius: f64, tau: f64 },
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    let x = x.abs();
+    if x < 1e-5 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius, .. }
+            | Filter::Lanczos { radius, .. } => radius,
+        }
+    }
+
+    /// weight of a sample `(x, y)` pixels away from a pixel center.
+    pub fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_1d(x) * self.eval_1d(y)
+    }
+
+    fn eval_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        let radius = self.radius();
+        if x > radius {
+            return 0.;
+        }
+        match *self {
+            Filter::Box { .. } => 1.,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, alpha } => {
+                max!(
+                    0.,
+                    (-alpha * x * x).exp() - (-alpha * radius * radius).exp()
+                )
+            }
+            Filter::Mitchell { radius, b, c } => {
+                // the cubic spans [-2, 2]
+                let x = 2. * x / radius;
+                let y = if x > 1. {
+                    (-b - 6. * c) * x.powi(3)
+                        + (6. * b + 30. * c) * x * x
+                        + (-12. * b - 48. * c) * x
+                        + (8. * b + 24. * c)
+                } else {
+                    (12. - 9. * b - 6. * c) * x.powi(3)
+                        + (-18. + 12. * b + 6. * c) * x * x
+                        + (6. - 2. * b)
+                };
+                y / 6.
+            }
+            Filter::Lanczos { tau, .. } => sinc(x) * sinc(x / tau),
+        }
+    }
+}
+
+// pass recorded by the film next to the beauty image
+#[derive(Debug, Clone)]
+struct Layer {
+    aov: Aov,
+    sums: Vec<Color>,
+    // filter weights, or the squared distance to the pixel center of the kept sample for unfiltered passes
+    weights: Vec<f64>,
+}
+
+impl Layer {
+    fn new(aov: Aov, len: usize) -> Layer {
+        let weight = if aov.is_filtered() { 0. } else { f64::INFINITY };
+        Layer {
+            aov,
+            sums: vec![(0., 0., 0.).into(); len],
+            weights: vec![weight; len],
+        }
+    }
+}
+
+fn resolve(sums: &[Color], weights: &[f64]) -> Vec<Color> {
+    sums.iter()
+        .zip(weights)
+        .map(|(&s, &w)| {
+            if w.abs() > 1e-9 {
+                s / w
+            } else {
+                (0., 0., 0.).into()
+            }
+        })
+        .collect()
+}
+
+/// image accumulating filtered samples, with `(0, 0)` at the top left corner of the top left
+/// pixel and pixel `(x, y)` covering `[x, x + 1) x [y, y + 1)`.
+///
+/// every sample adds to all pixels whose centers are within the filter radius,
+/// and pixels end up as the weighted average of their samples.
+/// passes of `Aov` are recorded in layers of the same size next to the beauty image.
+#[derive(Debug, Clone)]
+pub struct Film {
+    width: usize,
+    height: usize,
+    filter: Filter,
+    sums: Vec<Color>,
+    weights: Vec<f64>,
+    layers: Vec<Layer>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Film {
+        Film {
+            width,
+            height,
+            filter: Filter::default(),
+            sums: vec![(0., 0., 0.).into(); width * height],
+            weights: vec![0.; width * height],
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// record the passes `aovs` from `add_aov_sample`.
+    pub fn with_aovs(mut self, aovs: &[Aov]) -> Self {
+        for &aov in aovs {
+            if !self.aovs().contains(&aov) {
+                self.layers.push(Layer::new(aov, self.width * self.height));
+            }
+        }
+        self
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    /// passes recorded by this film.
+    pub fn aovs(&self) -> Vec<Aov> {
+        self.layers.iter().map(|layer| layer.aov).collect()
+    }
+
+    /// splat `color` of a sample at `(x, y)` in pixels.
+    pub fn add_sample(&mut self, x: f64, y: f64, color: Color) {
+        for (i, w) in self.footprint(x, y) {
+            self.sums[i] += w * color;
+            self.weights[i] += w;
+        }
+    }
+
+    /// splat the beauty and the recorded passes of `sample` at `(x, y)` in pixels.
+    pub fn add_aov_sample(&mut self, x: f64, y: f64, sample: &AovSample) {
+        let footprint = self.footprint(x, y);
+        for &(i, w) in &footprint {
+            self.sums[i] += w * sample.beauty;
+            self.weights[i] += w;
+        }
+        let (px, py) = (x.floor(), y.floor());
+        let nearest = if px >= 0. && py >= 0. && px < self.width as f64 && py < self.height as f64 {
+            let (dx, dy) = (px + 0.5 - x, py + 0.5 - y);
+            Some((py as usize * self.width + px as usize, dx * dx + dy * dy))
+        } else {
+            None
+        };
+        for layer in &mut self.layers {
+            let c = sample.get(layer.aov);
+            if layer.aov.is_filtered() {
+                for &(i, w) in &footprint {
+                    layer.sums[i] += w * c;
+                    layer.weights[i] += w;
+                }
+            } else if let Some((i, d2)) = nearest {
+                if d2 < layer.weights[i] {
+                    layer.sums[i] = c;
+                    layer.weights[i] = d2;
+                }
+            }
+        }
+    }
+
+    // indices and filter weights of the pixels a sample at `(x, y)` adds to
+    fn footprint(&self, x: f64, y: f64) -> Vec<(usize, f64)> {
+        let r = self.filter.radius();
+        // pixels with centers within the radius
+        let range = |p: f64, n: usize| {
+            let lo = max!((p - 0.5 - r).ceil(), 0.);
+            let hi = min!((p - 0.5 + r).floor(), n as f64 - 1.);
+            if lo <= hi {
+                Some((lo as usize, hi as usize))
+            } else {
+                None
+            }
+        };
+        let (x0, x1, y0, y1) = match (range(x, self.width), range(y, self.height)) {
+            (Some((x0, x1)), Some((y0, y1))) => (x0, x1, y0, y1),
+            _ => return Vec::new(),
+        };
+        let mut footprint = Vec::new();
+        for py in y0..=y1 {
+            for px in x0..=x1 {
+                let w = self.filter.eval(px as f64 + 0.5 - x, py as f64 + 0.5 - y);
+                if w != 0. {
+                    footprint.push((py * self.width + px, w));
+                }
+            }
+        }
+        footprint
+    }
+
+    /// add the samples of `other`, e.g. one filled by another thread.
+    pub fn merge(&mut self, other: &Film) {
+        assert_eq!(
+            (self.width, self.height),
+            (other.width, other.height),
+            "film size mismatch"
+        );
+        for (s, o) in self.sums.iter_mut().zip(&other.sums) {
+            *s += *o;
+        }
+        for (w, o) in self.weights.iter_mut().zip(&other.weights) {
+            *w += *o;
+        }
+        assert_eq!(self.aovs(), other.aovs(), "film passes mismatch");
+        for (layer, other) in self.layers.iter_mut().zip(&other.layers) {
+            let pixels = layer.sums.iter_mut().zip(layer.weights.iter_mut());
+            for ((s, w), (&os, &ow)) in pixels.zip(other.sums.iter().zip(&other.weights)) {
+                if layer.aov.is_filtered() {
+                    *s += os;
+                    *w += ow;
+                } else if ow < *w {
+                    *s = os;
+                    *w = ow;
+                }
+            }
+        }
+    }
+
+    /// the filtered image, black where no sample landed.
+    pub fn image(&self) -> Image {
+        let pixels = resolve(&self.sums, &self.weights);
+        Image::from_pixels(self.width, self.height, pixels)
+    }
+
+    /// image of the pass `aov`, if it is recorded.
+    pub fn layer(&self, aov: Aov) -> Option<Image> {
+        let layer = self.layers.iter().find(|layer| layer.aov == aov)?;
+        let pixels = if aov.is_filtered() {
+            resolve(&layer.sums, &layer.weights)
+        } else {
+            layer.sums.clone()
+        };
+        Some(Image::from_pixels(self.width, self.height, pixels))
+    }
+
+    /// save the beauty image and all passes as layers of one OpenEXR file,
+    /// the passes named by `Aov::name`.
+    pub fn save_layers<P: AsRef<Path>>(&self, path: P, pixel_type: ExrPixelType) -> io::Result<()> {
+        let mut images = vec![("", self.image())];
+        for layer in &self.layers {
+            images.extend(self.layer(layer.aov).map(|image| (layer.aov.name(), image)));
+        }
+        let layers: Vec<_> = images.iter().map(|(name, image)| (*name, image)).collect();
+        image::save_exr_layers(path, &layers, pixel_type)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_film() {
+        // the default box filter keeps samples in their own pixel
+        let mut film = Film::new(2, 1);
+        film.add_sample(0.2, 0.5, vec3!(1, 1, 1));
+        film.add_sample(0.9, 0.1, vec3!(3, 3, 3));
+        film.add_sample(1.5, 0.5, vec3!(5, 5, 5));
+        let image = film.image();
+        assert_abs_diff_eq!(image.get(0, 0), vec3!(2, 2, 2));
+        assert_abs_diff_eq!(image.get(1, 0), vec3!(5, 5, 5));
+
+        let filters = [
+            Filter::Tent { radius: 1. },
+            Filter::Gaussian {
+                radius: 1.5,
+                alpha: 2.,
+            },
+            Filter::Mitchell {
+                radius: 2.,
+                b: 1. / 3.,
+                c: 1. / 3.,
+            },
+            Filter::Lanczos {
+                radius: 3.,
+                tau: 3.,
+            },
+        ];
+        for &filter in filters.iter() {
+            assert!(filter.eval(0., 0.) > filter.eval(0.5, 0.));
+            assert_abs_diff_eq!(filter.eval(filter.radius() + 0.1, 0.), 0.);
+            // constant samples stay constant under any normalized filter
+            let mut film = Film::new(4, 4).with_filter(filter);
+            for i in 0..16 * 16 {
+                let (x, y) = ((i % 16) as f64 / 4. + 0.125, (i / 16) as f64 / 4. + 0.125);
+                film.add_sample(x, y, vec3!(0.5, 0.5, 0.5));
+            }
+            for &c in film.image().pixels() {
+                assert_abs_diff_eq!(c, vec3!(0.5, 0.5, 0.5), epsilon = 1e-9);
+            }
+        }
+        // wide filters spread a sample to the neighbors
+        let mut film = Film::new(3, 1).with_filter(Filter::Tent { radius: 1.5 });
+        film.add_sample(1.5, 0.5, vec3!(1, 1, 1));
+        assert_abs_diff_eq!(film.image().get(0, 0), vec3!(1, 1, 1));
+    }
+}
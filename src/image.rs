@@ -0,0 +1,144 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Write},
+    path::Path,
+};
+
+use crate::util::Color;
+
+pub use self::exr::ExrPixelType;
+
+mod exr;
+mod hdr;
+mod pfm;
+
+fn unsupported() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "unsupported image format")
+}
+
+fn empty_image() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "empty image")
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+}
+
+/// a linear floating point RGB image, stored row by row from the top left corner.
+#[derive(Debug, Clone)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![(0., 0., 0.).into(); width * height],
+        }
+    }
+
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<Color>) -> Image {
+        assert_eq!(width * height, pixels.len(), "pixel count mismatch");
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// load a Radiance `.hdr`, an OpenEXR `.exr` or a `.pfm` image.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+        let path = path.as_ref();
+        let ext = extension(path);
+        let mut r = BufReader::new(File::open(path)?);
+        match ext.as_deref() {
+            Some("hdr") | Some("pic") => hdr::read(&mut r),
+            Some("exr") => exr::read(&mut r),
+            Some("pfm") => pfm::read(&mut r),
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// save as a Radiance `.hdr`, a half float OpenEXR `.exr` or a `.pfm` image,
+    /// keeping the linear values without clamping.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let write: fn(&Image, &mut BufWriter<File>) -> io::Result<()> =
+            match extension(path).as_deref() {
+                Some("hdr") | Some("pic") => |img, w| img.write_hdr(w),
+                Some("exr") => |img, w| img.write_exr(w, ExrPixelType::Half),
+                Some("pfm") => |img, w| img.write_pfm(w),
+                _ => return Err(unsupported()),
+            };
+        let mut w = BufWriter::new(File::create(path)?);
+        write(self, &mut w)?;
+        w.flush()
+    }
+
+    pub fn write_hdr<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        hdr::write(w, self)
+    }
+
+    pub fn write_exr<W: Write>(&self, w: &mut W, pixel_type: ExrPixelType) -> io::Result<()> {
+        exr::write_layers(w, &[("", self)], pixel_type)
+    }
+
+    pub fn write_pfm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        pfm::write(w, self)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, c: Color) {
+        self.pixels[y * self.width + x] = c;
+    }
+
+    /// bilinearly sample this image at `(u, v)` in [0, 1]^2, with `v = 0` at the top row.
+    /// coordinates out of range wrap around.
+    pub fn sample(&self, u: f64, v: f64) -> Color {
+        let x = u * self.width as f64 - 0.5;
+        let y = v * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let wrap = |i: f64, n: usize| (i as i64).rem_euclid(n as i64) as usize;
+        let (x0, x1) = (wrap(x0, self.width), wrap(x0 + 1., self.width));
+        let (y0, y1) = (wrap(y0, self.height), wrap(y0 + 1., self.height));
+        (1. - dx) * (1. - dy) * self.get(x0, y0)
+            + dx * (1. - dy) * self.get(x1, y0)
+            + (1. - dx) * dy * self.get(x0, y1)
+            + dx * dy * self.get(x1, y1)
+    }
+}
+
+/// save `layers` of the same size into one OpenEXR file, e.g. for compositing.
+/// a layer named `name` has channels `name.R`, `name.G` and `name.B`,
+/// and one with an empty name plain `R`, `G` and `B` which most viewers show.
+pub fn save_exr_layers<P: AsRef<Path>>(
+    path: P,
+    layers: &[(&str, &Image)],
+    pixel_type: ExrPixelType,
+) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    exr::write_layers(&mut w, layers, pixel_type)?;
+    w.flush()
+}
@@ -0,0 +1,614 @@
+// OpenEXR scanline images with NONE, RLE, ZIPS or ZIP compression
+// see https://www.openexr.com/documentation/openexrfilelayout.pdf for details
+
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::util::Color;
+
+use super::{empty_image, Image};
+
+/// precision of the channels written to OpenEXR files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExrPixelType {
+    /// 16 bit floats, enough for display and half the size
+    Half,
+    /// 32 bit floats
+    Float,
+}
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("exr: {}", msg))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Rle,
+    Zips,
+    Zip,
+}
+
+impl Compression {
+    fn from_u8(v: u8) -> io::Result<Compression> {
+        match v {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Rle),
+            2 => Ok(Compression::Zips),
+            3 => Ok(Compression::Zip),
+            _ => Err(invalid("unsupported compression")),
+        }
+    }
+
+    fn lines_per_block(self) -> usize {
+        match self {
+            Compression::Zip => 16,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Channel {
+    name: String,
+    // 0: uint, 1: half, 2: float
+    pixel_type: i32,
+}
+
+impl Channel {
+    fn size(&self) -> usize {
+        if self.pixel_type == 1 {
+            2
+        } else {
+            4
+        }
+    }
+}
+
+// little endian cursor over the whole file
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = match self.pos.checked_add(n) {
+            Some(end) if end <= self.data.len() => end,
+            _ => return Err(invalid("unexpected end of file")),
+        };
+        let b = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        let b = self.bytes(4)?;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // byte size of the data which follows
+    fn size(&mut self) -> io::Result<usize> {
+        let size = self.i32()?;
+        if size < 0 {
+            return Err(invalid("negative size"));
+        }
+        Ok(size as usize)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let b = self.bytes(8)?;
+        let mut v = [0u8; 8];
+        v.copy_from_slice(b);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let start = self.pos;
+        while self.u8()? != 0 {}
+        Ok(String::from_utf8_lossy(&self.data[start..self.pos - 1]).to_string())
+    }
+}
+
+pub(crate) fn half_to_f32(h: u16) -> f32 {
+    let sign = u32::from(h >> 15) << 31;
+    let exp = u32::from((h >> 10) & 0x1f);
+    let mant = u32::from(h & 0x3ff);
+    let bits = match (exp, mant) {
+        (0, 0) => sign,
+        (0, _) => {
+            // subnormal, renormalize
+            let mut e = 127 - 15 + 1;
+            let mut m = mant;
+            while m & 0x400 == 0 {
+                m <<= 1;
+                e -= 1;
+            }
+            sign | (e << 23) | ((m & 0x3ff) << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (mant << 13),
+        _ => sign | ((exp + 127 - 15) << 23) | (mant << 13),
+    };
+    f32::from_bits(bits)
+}
+
+pub(crate) fn f32_to_half(f: f32) -> u16 {
+    let bits = f.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x7f_ffff;
+    if exp == 0xff {
+        // keep nans nan
+        let nan = if mant != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        // subnormal, with the implicit leading bit shifted in and rounded to nearest even
+        let m = mant | 0x80_0000;
+        let shift = (14 - e) as u32;
+        let half = 1 << (shift - 1);
+        let rest = m & ((1 << shift) - 1);
+        let mut h = m >> shift;
+        if rest > half || (rest == half && h & 1 == 1) {
+            h += 1;
+        }
+        return sign | h as u16;
+    }
+    let mut h = ((e as u32) << 10) | (mant >> 13);
+    let rest = mant & 0x1fff;
+    // rounding may carry into the exponent, up to infinity
+    if rest > 0x1000 || (rest == 0x1000 && h & 1 == 1) {
+        h += 1;
+    }
+    sign | h as u16
+}
+
+// split into odd and even bytes and apply the byte delta predictor
+fn predict(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = data.iter().step_by(2).cloned().collect();
+    out.extend(data.iter().skip(1).step_by(2));
+    let mut prev = out.first().cloned().unwrap_or(0);
+    for b in out.iter_mut().skip(1) {
+        let d = b.wrapping_sub(prev).wrapping_add(128);
+        prev = *b;
+        *b = d;
+    }
+    out
+}
+
+// undo the byte delta predictor and the split into odd and even bytes
+fn reconstruct(mut data: Vec<u8>) -> Vec<u8> {
+    for i in 1..data.len() {
+        data[i] = data[i - 1].wrapping_add(data[i]).wrapping_sub(128);
+    }
+    let half = data.len().div_ceil(2);
+    let mut out = Vec::with_capacity(data.len());
+    for i in 0..half {
+        out.push(data[i]);
+        if half + i < data.len() {
+            out.push(data[half + i]);
+        }
+    }
+    out
+}
+
+fn rle_decompress(data: &[u8], expected: usize) -> io::Result<Vec<u8>> {
+    // runs expand at most 128 times
+    let mut out = Vec::with_capacity(min!(expected, data.len().saturating_mul(128)));
+    let mut i = 0;
+    while i < data.len() {
+        let count = data[i] as i8;
+        i += 1;
+        if count < 0 {
+            let n = (-i32::from(count)) as usize;
+            let run = data.get(i..i + n).ok_or_else(|| invalid("bad rle data"))?;
+            out.extend_from_slice(run);
+            i += n;
+        } else {
+            let v = *data.get(i).ok_or_else(|| invalid("bad rle data"))?;
+            out.extend(std::iter::repeat_n(v, count as usize + 1));
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+fn decompress(compression: Compression, data: &[u8], expected: usize) -> io::Result<Vec<u8>> {
+    // blocks which do not shrink are stored uncompressed
+    if compression == Compression::None || data.len() == expected {
+        return Ok(data.to_vec());
+    }
+    let raw = match compression {
+        Compression::Rle => rle_decompress(data, expected)?,
+        _ => miniz_oxide::inflate::decompress_to_vec_zlib(data)
+            .map_err(|_| invalid("bad zip data"))?,
+    };
+    if raw.len() != expected {
+        return Err(invalid("bad block size"));
+    }
+    Ok(reconstruct(raw))
+}
+
+/// read the `R`, `G` and `B` channels of the first layer, or `Y` for a grayscale image.
+pub(crate) fn read<R: Read>(r: &mut R) -> io::Result<Image> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let mut c = Cursor {
+        data: &data,
+        pos: 0,
+    };
+    if c.bytes(4)? != MAGIC {
+        return Err(invalid("missing magic number"));
+    }
+    let version = c.i32()?;
+    if version & 0x200 != 0 || version & 0x1800 != 0 {
+        return Err(invalid("only single part scanline images are supported"));
+    }
+
+    let mut channels = Vec::new();
+    let mut compression = Compression::None;
+    let mut window = None;
+    loop {
+        let name = c.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let _ty = c.string()?;
+        let size = c.size()?;
+        let end = c.pos + size;
+        match name.as_str() {
+            "channels" => loop {
+                let name = c.string()?;
+                if name.is_empty() {
+                    break;
+                }
+                let pixel_type = c.i32()?;
+                let _linear_and_reserved = c.bytes(4)?;
+                let (xs, ys) = (c.i32()?, c.i32()?);
+                if xs != 1 || ys != 1 {
+                    return Err(invalid("subsampled channels are not supported"));
+                }
+                channels.push(Channel { name, pixel_type });
+            },
+            "compression" => compression = Compression::from_u8(c.u8()?)?,
+            "dataWindow" => window = Some((c.i32()?, c.i32()?, c.i32()?, c.i32()?)),
+            _ => {}
+        }
+        c.pos = end;
+    }
+    let (x0, y0, x1, y1) = window.ok_or_else(|| invalid("missing data window"))?;
+    let width = i64::from(x1) - i64::from(x0) + 1;
+    let height = i64::from(y1) - i64::from(y0) + 1;
+    if width < 1 || height < 1 {
+        return Err(invalid("empty data window"));
+    }
+    let (width, height) = (width as usize, height as usize);
+
+    // channels are stored sorted by name
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    let find = |names: &[&str]| {
+        names
+            .iter()
+            .filter_map(|n| channels.iter().position(|c| c.name == *n))
+            .next()
+    };
+    let targets = [
+        find(&["R", "Y"]).ok_or_else(|| invalid("missing R channel"))?,
+        find(&["G", "Y"]).ok_or_else(|| invalid("missing G channel"))?,
+        find(&["B", "Y"]).ok_or_else(|| invalid("missing B channel"))?,
+    ];
+    let line_size = channels
+        .iter()
+        .try_fold(0usize, |sum, c| {
+            sum.checked_add(c.size().checked_mul(width)?)
+        })
+        .ok_or_else(|| invalid("image too large"))?;
+    width
+        .checked_mul(height)
+        .ok_or_else(|| invalid("image too large"))?;
+
+    let lines = compression.lines_per_block();
+    let blocks = height.div_ceil(lines);
+    let offsets = (0..blocks)
+        .map(|_| c.u64())
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // the header can not be trusted with the allocation, rows are added as blocks are decoded
+    let mut rows: Vec<Option<Vec<Color>>> = vec![None; height];
+    for offset in offsets {
+        c.pos = offset as usize;
+        let y = i64::from(c.i32()?) - i64::from(y0);
+        if y < 0 || y >= height as i64 {
+            return Err(invalid("block outside of the data window"));
+        }
+        let y = y as usize;
+        let size = c.size()?;
+        let n = min!(lines, height.saturating_sub(y));
+        let expected = n
+            .checked_mul(line_size)
+            .ok_or_else(|| invalid("image too large"))?;
+        let block = decompress(compression, c.bytes(size)?, expected)?;
+        if block.len() != expected {
+            return Err(invalid("bad block size"));
+        }
+        for (dy, line) in block.chunks(line_size).enumerate() {
+            let mut values = vec![[0f64; 3]; width];
+            let mut start = 0;
+            for (ci, ch) in channels.iter().enumerate() {
+                for (x, v) in values.iter_mut().enumerate() {
+                    let b = &line[start + x * ch.size()..];
+                    let value = match ch.pixel_type {
+                        0 => f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                        1 => f64::from(half_to_f32(u16::from_le_bytes([b[0], b[1]]))),
+                        _ => f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                    };
+                    for (k, t) in targets.iter().enumerate() {
+                        if *t == ci {
+                            v[k] = value;
+                        }
+                    }
+                }
+                start += ch.size() * width;
+            }
+            let row = values.iter().map(|v| Color::new(v[0], v[1], v[2]));
+            rows[y + dy] = Some(row.collect());
+        }
+    }
+    let mut pixels = Vec::new();
+    for row in rows {
+        pixels.extend(row.ok_or_else(|| invalid("missing scanlines"))?);
+    }
+    Ok(Image::from_pixels(width, height, pixels))
+}
+
+struct Output<'a> {
+    // names sorted as stored, with the image and the component of each
+    channels: Vec<(String, &'a Image, usize)>,
+    pixel_type: ExrPixelType,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Output<'a> {
+    fn new(layers: &[(&str, &'a Image)], pixel_type: ExrPixelType) -> io::Result<Self> {
+        let (width, height) = match layers.first() {
+            Some((_, img)) => (img.width(), img.height()),
+            None => return Err(invalid("no layers to write")),
+        };
+        if width == 0 || height == 0 {
+            return Err(empty_image());
+        }
+        let mut channels = Vec::new();
+        for (layer, img) in layers {
+            if (img.width(), img.height()) != (width, height) {
+                return Err(invalid("layer size mismatch"));
+            }
+            for (i, c) in ["R", "G", "B"].iter().enumerate() {
+                let name = if layer.is_empty() {
+                    c.to_string()
+                } else {
+                    format!("{}.{}", layer, c)
+                };
+                channels.push((name, *img, i));
+            }
+        }
+        channels.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Output {
+            channels,
+            pixel_type,
+            width,
+            height,
+        })
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut h = MAGIC.to_vec();
+        // long names need the flag for up to 255 bytes
+        let long = self.channels.iter().any(|c| c.0.len() > 31);
+        h.extend_from_slice(&(2i32 | if long { 0x400 } else { 0 }).to_le_bytes());
+        let mut attr = |name: &str, ty: &str, value: &[u8]| {
+            for s in [name, ty].iter() {
+                h.extend_from_slice(s.as_bytes());
+                h.push(0);
+            }
+            h.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            h.extend_from_slice(value);
+        };
+        let mut chlist = Vec::new();
+        let ty: i32 = match self.pixel_type {
+            ExrPixelType::Half => 1,
+            ExrPixelType::Float => 2,
+        };
+        for (name, _, _) in self.channels.iter() {
+            chlist.extend_from_slice(name.as_bytes());
+            chlist.push(0);
+            chlist.extend_from_slice(&ty.to_le_bytes());
+            chlist.extend_from_slice(&[0; 4]);
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+        }
+        chlist.push(0);
+        attr("channels", "chlist", &chlist);
+        attr("compression", "compression", &[3]);
+        let mut window = Vec::new();
+        for v in [0, 0, self.width as i32 - 1, self.height as i32 - 1].iter() {
+            window.extend_from_slice(&v.to_le_bytes());
+        }
+        attr("dataWindow", "box2i", &window);
+        attr("displayWindow", "box2i", &window);
+        attr("lineOrder", "lineOrder", &[0]);
+        attr("pixelAspectRatio", "float", &1f32.to_le_bytes());
+        attr("screenWindowCenter", "v2f", &[0; 8]);
+        attr("screenWindowWidth", "float", &1f32.to_le_bytes());
+        h.push(0);
+        h
+    }
+
+    // lines from `y` on of all channels, zip compressed unless that does not shrink them
+    fn block(&self, y: usize) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for y in y..min!(y + Compression::Zip.lines_per_block(), self.height) {
+            for (_, img, i) in self.channels.iter() {
+                for x in 0..self.width {
+                    let c = img.get(x, y);
+                    let v = [c.x, c.y, c.z][*i] as f32;
+                    match self.pixel_type {
+                        ExrPixelType::Half => raw.extend_from_slice(&f32_to_half(v).to_le_bytes()),
+                        ExrPixelType::Float => raw.extend_from_slice(&v.to_le_bytes()),
+                    }
+                }
+            }
+        }
+        let zipped = miniz_oxide::deflate::compress_to_vec_zlib(&predict(&raw), 6);
+        if zipped.len() < raw.len() {
+            zipped
+        } else {
+            raw
+        }
+    }
+}
+
+/// write `layers` of the same size into one zip compressed OpenEXR file.
+/// a layer named `name` has channels `name.R`, `name.G` and `name.B`,
+/// one with an empty name plain `R`, `G` and `B`.
+pub(crate) fn write_layers<W: Write>(
+    w: &mut W,
+    layers: &[(&str, &Image)],
+    pixel_type: ExrPixelType,
+) -> io::Result<()> {
+    let out = Output::new(layers, pixel_type)?;
+    let header = out.header();
+    let lines = Compression::Zip.lines_per_block();
+    let blocks: Vec<_> = (0..out.height)
+        .step_by(lines)
+        .map(|y| out.block(y))
+        .collect();
+    w.write_all(&header)?;
+    // blocks follow the table of their offsets
+    let mut offset = (header.len() + 8 * blocks.len()) as u64;
+    for block in blocks.iter() {
+        w.write_all(&offset.to_le_bytes())?;
+        offset += 8 + block.len() as u64;
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        w.write_all(&((i * lines) as i32).to_le_bytes())?;
+        w.write_all(&(block.len() as i32).to_le_bytes())?;
+        w.write_all(block)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_half_to_f32() {
+        assert_eq!(half_to_f32(0x0000), 0.);
+        assert_eq!(half_to_f32(0x3c00), 1.);
+        assert_eq!(half_to_f32(0xc000), -2.);
+        assert_eq!(half_to_f32(0x7bff), 65504.);
+        assert_eq!(half_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(half_to_f32(0x3555), 0.333_251_95);
+        assert!(half_to_f32(0x7c00).is_infinite());
+        for &h in [
+            0x0000, 0x3c00, 0xc000, 0x7bff, 0x0001, 0x03ff, 0x3555, 0x7c00,
+        ]
+        .iter()
+        {
+            assert_eq!(f32_to_half(half_to_f32(h)), h);
+        }
+        assert_eq!(f32_to_half(1e6), 0x7c00);
+        assert_eq!(f32_to_half(1. + 1. / 4096.), 0x3c00);
+        assert!(half_to_f32(f32_to_half(f32::NAN)).is_nan());
+    }
+
+    #[test]
+    fn test_read_exr() {
+        // uncompressed, with an alpha channel, float green and a data window from (1, 1)
+        let data = include_bytes!("../../tests/data/rgb.exr");
+        let img = read(&mut &data[..]).unwrap();
+        assert_eq!((img.width(), img.height()), (3, 2));
+        assert_abs_diff_eq!(img.get(0, 0), vec3!(0, 0.1, 3), epsilon = 1e-6);
+        assert_abs_diff_eq!(img.get(2, 0), vec3!(1, 0.3, 5), epsilon = 1e-6);
+        assert_abs_diff_eq!(img.get(0, 1), vec3!(2, 1e5, 6), epsilon = 1e-6);
+        assert_abs_diff_eq!(img.get(1, 1), vec3!(-1, 0, 7), epsilon = 1e-6);
+        assert_abs_diff_eq!(img.get(2, 1), vec3!(0.25, -0.5, 8), epsilon = 1e-6);
+
+        // rle compressed grayscale
+        let data = include_bytes!("../../tests/data/gray_rle.exr");
+        let img = read(&mut &data[..]).unwrap();
+        assert_eq!((img.width(), img.height()), (8, 3));
+        let row = |y: usize| (0..8).map(|x| img.get(x, y).x).collect::<Vec<_>>();
+        assert_eq!(row(0), vec![1.; 8]);
+        assert_eq!(row(1), vec![0.5, 0.5, 0.5, 0.5, 2., 2., 2., 2.]);
+        assert_eq!(row(2), vec![0., 0., 0.25, 0.25, 0.75, 0.75, 1., 1.]);
+        assert_eq!(img.get(3, 2), vec3!(0.25, 0.25, 0.25));
+
+        // corrupt files are errors, not panics
+        let data = include_bytes!("../../tests/data/rgb.exr");
+        assert!(read(&mut &data[..data.len() - 1]).is_err());
+        let at = |s: &[u8]| data.windows(s.len()).position(|w| w == s).unwrap();
+        let corrupt = |pos: usize, bytes: &[u8]| {
+            let mut bad = data.to_vec();
+            bad[pos..pos + bytes.len()].copy_from_slice(bytes);
+            read(&mut &bad[..]).is_err()
+        };
+        // the offset table follows the last attribute, a float, and the end of the header
+        let table = at(b"screenWindowWidth") + 18 + 6 + 4 + 4 + 1;
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&data[table..table + 8]);
+        let block = u64::from_le_bytes(offset) as usize;
+        assert!(corrupt(block, &(-1i32).to_le_bytes()));
+        assert!(corrupt(block + 4, &(-1i32).to_le_bytes()));
+        assert!(corrupt(block + 4, &i32::MAX.to_le_bytes()));
+        assert!(corrupt(table, &u64::MAX.to_le_bytes()));
+        assert!(corrupt(at(b"chlist") + 7, &i32::MIN.to_le_bytes()));
+        // a data window ending above its start
+        assert!(corrupt(at(b"box2i") + 6 + 4 + 12, &i32::MIN.to_le_bytes()));
+        // a huge data window is not allocated before blocks cover it
+        let window = at(b"box2i") + 6 + 4;
+        let mut wide = data.to_vec();
+        wide[window..window + 4].copy_from_slice(&i32::MIN.to_le_bytes());
+        wide[window + 8..window + 12].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert!(read(&mut &wide[..]).is_err());
+        // both offsets pointing at the first block leave the second row out
+        assert!(corrupt(table + 8, &offset));
+    }
+
+    #[test]
+    fn test_write_exr() {
+        let pixels = (0..40 * 20)
+            .map(|i| vec3!(i as f64 / 8., 0.5, -(i % 7) as f64))
+            .collect();
+        let img = Image::from_pixels(40, 20, pixels);
+        let dark = Image::new(40, 20);
+        for &ty in [ExrPixelType::Half, ExrPixelType::Float].iter() {
+            let mut data = Vec::new();
+            write_layers(&mut data, &[("", &img), ("albedo", &dark)], ty).unwrap();
+            let back = read(&mut &data[..]).unwrap();
+            assert_eq!((back.width(), back.height()), (40, 20));
+            for (a, b) in img.pixels().iter().zip(back.pixels()) {
+                assert_abs_diff_eq!(*a, *b, epsilon = 1e-3 * a.len());
+            }
+        }
+        assert!(write_layers(
+            &mut Vec::new(),
+            &[("", &img), ("a", &Image::new(1, 1))],
+            ExrPixelType::Half
+        )
+        .is_err());
+    }
+}
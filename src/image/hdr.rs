@@ -0,0 +1,243 @@
+// Radiance RGBE (.hdr) format
+// see http://paulbourke.net/dataformats/pic/ for details
+
+use std::io::{self, BufRead, ErrorKind, Write};
+
+use crate::util::Color;
+
+use super::{empty_image, Image};
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("hdr: {}", msg))
+}
+
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    r.read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Err(invalid("unexpected end of header"));
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+fn rgbe_to_color(rgbe: [u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return (0., 0., 0.).into();
+    }
+    let f = 2f64.powi(i32::from(rgbe[3]) - (128 + 8));
+    vec3!(
+        (f64::from(rgbe[0]) + 0.5) * f,
+        (f64::from(rgbe[1]) + 0.5) * f,
+        (f64::from(rgbe[2]) + 0.5) * f
+    )
+}
+
+fn color_to_rgbe(c: Color) -> [u8; 4] {
+    let (r, g, b) = (max!(c.x, 0.), max!(c.y, 0.), max!(c.z, 0.));
+    let v = max!(r, g, b);
+    if v < 1e-32 || !v.is_finite() {
+        return [0; 4];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256. / 2f64.powi(e);
+    let byte = |x: f64| min!(x * scale, 255.) as u8;
+    [byte(r), byte(g), byte(b), (e + 128) as u8]
+}
+
+// run length encode one component of a scanline, with runs of at least 3 bytes
+fn write_component<W: Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    let run_at = |i: usize| {
+        let n = data[i..]
+            .iter()
+            .take(127)
+            .take_while(|&&b| b == data[i])
+            .count();
+        if n >= 3 {
+            n
+        } else {
+            0
+        }
+    };
+    let mut x = 0;
+    while x < data.len() {
+        let run = run_at(x);
+        if run > 0 {
+            w.write_all(&[128 + run as u8, data[x]])?;
+            x += run;
+            continue;
+        }
+        let start = x;
+        while x < data.len() && x - start < 128 && run_at(x) == 0 {
+            x += 1;
+        }
+        w.write_all(&[(x - start) as u8])?;
+        w.write_all(&data[start..x])?;
+    }
+    Ok(())
+}
+
+fn read_scanline<R: BufRead>(r: &mut R, width: usize) -> io::Result<Vec<[u8; 4]>> {
+    let mut head = [0u8; 4];
+    r.read_exact(&mut head)?;
+    let is_rle =
+        head[0] == 2 && head[1] == 2 && head[2] & 0x80 == 0 && (8..0x8000).contains(&width);
+    if !is_rle {
+        // flat scanline, grown as pixels are read rather than sized by the header
+        let mut line = vec![head];
+        for _ in 1..width {
+            let mut px = [0u8; 4];
+            r.read_exact(&mut px)?;
+            line.push(px);
+        }
+        return Ok(line);
+    }
+    if (usize::from(head[2]) << 8 | usize::from(head[3])) != width {
+        return Err(invalid("scanline width mismatch"));
+    }
+    // each component is run length encoded separately
+    let mut line = vec![[0u8; 4]; width];
+    for c in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut buf = [0u8; 2];
+            r.read_exact(&mut buf[..1])?;
+            let count = usize::from(buf[0]);
+            if count > 128 {
+                let count = count - 128;
+                if x + count > width {
+                    return Err(invalid("bad run length"));
+                }
+                r.read_exact(&mut buf[1..])?;
+                line[x..x + count].iter_mut().for_each(|px| px[c] = buf[1]);
+                x += count;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(invalid("bad run length"));
+                }
+                for px in line[x..x + count].iter_mut() {
+                    r.read_exact(&mut buf[1..])?;
+                    px[c] = buf[1];
+                }
+                x += count;
+            }
+        }
+    }
+    Ok(line)
+}
+
+pub(crate) fn read<R: BufRead>(r: &mut R) -> io::Result<Image> {
+    let magic = read_line(r)?;
+    if !magic.starts_with("#?") {
+        return Err(invalid("missing magic number"));
+    }
+    loop {
+        let line = read_line(r)?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid("only 32-bit_rle_rgbe is supported"));
+        }
+    }
+    // only the standard "-Y height +X width" orientation is supported
+    let size = read_line(r)?;
+    let parts: Vec<_> = size.split_whitespace().collect();
+    let (height, width) = match parts.as_slice() {
+        ["-Y", h, "+X", w] => (h.parse::<usize>(), w.parse::<usize>()),
+        _ => return Err(invalid("unsupported resolution string")),
+    };
+    let (height, width) = match (height, width) {
+        (Ok(h), Ok(w)) if h > 0 && w > 0 => (h, w),
+        _ => return Err(invalid("bad resolution")),
+    };
+    if width.checked_mul(height).is_none() {
+        return Err(invalid("image too large"));
+    }
+
+    let mut pixels = Vec::new();
+    for _ in 0..height {
+        let line = read_scanline(r, width)?;
+        pixels.extend(line.into_iter().map(rgbe_to_color));
+    }
+    Ok(Image::from_pixels(width, height, pixels))
+}
+
+pub(crate) fn write<W: Write>(w: &mut W, img: &Image) -> io::Result<()> {
+    let (width, height) = (img.width(), img.height());
+    if width == 0 || height == 0 {
+        return Err(empty_image());
+    }
+    write!(
+        w,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+    for line in img.pixels().chunks(width) {
+        let rgbe: Vec<_> = line.iter().map(|&c| color_to_rgbe(c)).collect();
+        if !(8..0x8000).contains(&width) {
+            for px in rgbe.iter() {
+                w.write_all(px)?;
+            }
+            continue;
+        }
+        w.write_all(&[2, 2, (width >> 8) as u8, width as u8])?;
+        for c in 0..4 {
+            let component: Vec<_> = rgbe.iter().map(|px| px[c]).collect();
+            write_component(w, &component)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_hdr() {
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n".to_vec();
+        // a run length encoded scanline
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        data.extend_from_slice(&[136, 128]);
+        data.extend_from_slice(&[4, 0, 1, 2, 3, 132, 64]);
+        data.extend_from_slice(&[136, 0]);
+        data.extend_from_slice(&[136, 129]);
+        // a flat scanline
+        for x in 0..8 {
+            data.extend_from_slice(&[x, 0, 0, 136]);
+        }
+        let img = read(&mut &data[..]).unwrap();
+        assert_eq!((img.width(), img.height()), (8, 2));
+        assert_abs_diff_eq!(img.get(0, 0), vec3!(128.5 / 128., 0.5 / 128., 0.5 / 128.));
+        assert_abs_diff_eq!(img.get(3, 0), vec3!(128.5 / 128., 3.5 / 128., 0.5 / 128.));
+        assert_abs_diff_eq!(img.get(7, 0), vec3!(128.5 / 128., 64.5 / 128., 0.5 / 128.));
+        assert_abs_diff_eq!(img.get(5, 1), vec3!(5.5, 0.5, 0.5));
+
+        // forged sizes fail on the missing data instead of allocating for them
+        let header = |size: &str| format!("#?RADIANCE\n\n{}\n", size).into_bytes();
+        for size in ["-Y 0 +X 8", "-Y 8 +X 0", "-Y 2 +X 18446744073709551615"].iter() {
+            assert!(read(&mut &header(size)[..]).is_err());
+        }
+        let mut forged = header("-Y 1 +X 4000000000000");
+        forged.extend_from_slice(&[1, 1, 1, 128]);
+        assert!(read(&mut &forged[..]).is_err());
+    }
+
+    #[test]
+    fn test_write_hdr() {
+        for &width in [3, 40].iter() {
+            let pixels = (0..width * 2)
+                .map(|i| vec3!((i / 5) as f64 * 0.7, 100, 1e-3))
+                .collect();
+            let img = Image::from_pixels(width, 2, pixels);
+            let mut data = Vec::new();
+            write(&mut data, &img).unwrap();
+            let back = read(&mut &data[..]).unwrap();
+            for (a, b) in img.pixels().iter().zip(back.pixels()) {
+                // rgbe keeps 8 bits relative to the brightest component
+                assert_abs_diff_eq!(*a, *b, epsilon = a.x.max(a.y) / 128.);
+            }
+        }
+    }
+}
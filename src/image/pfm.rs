@@ -0,0 +1,128 @@
+// Portable FloatMap (.pfm) format, little or big endian rows of 32 bit floats from the bottom
+// see http://www.pauldebevec.com/Research/HDR/PFM/ for details
+
+use std::io::{self, BufRead, ErrorKind, Write};
+
+use crate::util::Color;
+
+use super::{empty_image, Image};
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("pfm: {}", msg))
+}
+
+// next whitespace separated word of the header
+fn read_word<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut word = Vec::new();
+    loop {
+        let mut b = [0u8; 1];
+        r.read_exact(&mut b)?;
+        if b[0].is_ascii_whitespace() {
+            if word.is_empty() {
+                continue;
+            }
+            return Ok(String::from_utf8_lossy(&word).to_string());
+        }
+        word.push(b[0]);
+    }
+}
+
+pub(crate) fn read<R: BufRead>(r: &mut R) -> io::Result<Image> {
+    let channels = match read_word(r)?.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("missing magic number")),
+    };
+    let mut size = || {
+        read_word(r)?
+            .parse::<usize>()
+            .map_err(|_| invalid("bad size"))
+    };
+    let (width, height) = (size()?, size()?);
+    if width == 0 || height == 0 {
+        return Err(invalid("empty image"));
+    }
+    width
+        .checked_mul(height)
+        .ok_or_else(|| invalid("image too large"))?;
+    let scale = read_word(r)?
+        .parse::<f64>()
+        .map_err(|_| invalid("bad scale"))?;
+    let little = scale < 0.;
+    // rows grow with the data read, a lying header fails at its end instead of allocating
+    let mut rows = Vec::new();
+    let mut buf = [0u8; 4];
+    for _ in 0..height {
+        let mut row = Vec::new();
+        for _ in 0..width {
+            let mut v = [0f64; 3];
+            for c in v.iter_mut().take(channels) {
+                r.read_exact(&mut buf)?;
+                let f = if little {
+                    f32::from_le_bytes(buf)
+                } else {
+                    f32::from_be_bytes(buf)
+                };
+                *c = f64::from(f);
+            }
+            if channels == 1 {
+                v = [v[0]; 3];
+            }
+            row.push(Color::new(v[0], v[1], v[2]));
+        }
+        rows.push(row);
+    }
+    // bottom row first
+    let pixels = rows.into_iter().rev().flatten().collect();
+    Ok(Image::from_pixels(width, height, pixels))
+}
+
+pub(crate) fn write<W: Write>(w: &mut W, img: &Image) -> io::Result<()> {
+    if img.width() == 0 || img.height() == 0 {
+        return Err(empty_image());
+    }
+    write!(w, "PF\n{} {}\n-1.0\n", img.width(), img.height())?;
+    for line in img.pixels().chunks(img.width()).rev() {
+        for c in line {
+            for v in [c.x, c.y, c.z].iter() {
+                w.write_all(&(*v as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pfm() {
+        let pixels = (0..6).map(|i| vec3!(i, -1.5, 1e5)).collect();
+        let img = Image::from_pixels(3, 2, pixels);
+        let mut data = Vec::new();
+        write(&mut data, &img).unwrap();
+        assert!(data.starts_with(b"PF\n3 2\n-1.0\n"));
+        let back = read(&mut &data[..]).unwrap();
+        assert_eq!(back.pixels(), img.pixels());
+
+        // big endian grayscale, bottom row first
+        let mut data = b"Pf 1 2 1.0\n".to_vec();
+        data.extend_from_slice(&2f32.to_be_bytes());
+        data.extend_from_slice(&0.5f32.to_be_bytes());
+        let gray = read(&mut &data[..]).unwrap();
+        assert_abs_diff_eq!(gray.get(0, 0), vec3!(0.5, 0.5, 0.5));
+        assert_abs_diff_eq!(gray.get(0, 1), vec3!(2, 2, 2));
+
+        for header in ["PF -1 2 -1.0\n", "PF 0 0 -1.0\n", "PF 1.5 2 -1.0\n"].iter() {
+            assert!(read(&mut header.as_bytes()).is_err());
+        }
+        // huge sizes run out of data instead of memory
+        let huge = format!("PF {} {} -1.0\n", usize::MAX, 2);
+        assert!(read(&mut huge.as_bytes()).is_err());
+        let huge = format!("Pf {} 1 -1.0\n", u32::MAX);
+        assert!(read(&mut huge.as_bytes()).is_err());
+        let empty = Image::from_pixels(0, 0, Vec::new());
+        assert!(write(&mut Vec::new(), &empty).is_err());
+    }
+}
@@ -3,15 +3,27 @@
 #[macro_use]
 extern crate approx;
 
+pub use camera::Camera;
 pub use light::LightSource;
 pub use material::Material;
 pub use object::Shape;
-pub use ray::{Camera, Ray};
+pub use ray::Ray;
+pub use texture::Texture;
 pub use util::{Color, Vec3};
 
 #[macro_use]
 pub mod util;
+pub mod aov;
+pub mod camera;
+pub mod display;
+pub mod film;
+pub mod image;
 pub mod light;
 pub mod material;
+pub mod medium;
 pub mod object;
 pub mod ray;
+pub mod sampling;
+pub mod spectrum;
+pub mod texture;
+pub mod transform;
@@ -1,11 +1,31 @@
+use std::sync::Arc;
+
 use crate::{
-    object::{Shape, World},
+    medium,
+    object::{Aabb, Shape, World},
     ray::{HitInfo, Ray},
-    util::{Color, Vec3, EPS},
+    texture::Texture,
+    util::{coordinate_system, luminance, Color, Vec3, EPS, PI},
 };
 
+pub use self::{environment::*, sampler::*, sky::*};
+
+mod environment;
+mod sampler;
+mod sky;
+
+/// a light direction drawn by `LightSource::sample`.
+#[derive(Clone, Copy, Debug)]
+pub struct LightSample {
+    /// direction from the light to the hit point
+    pub dir: Vec3,
+    pub intensity: f64,
+    pub color: Color,
+}
+
 pub trait LightSource: Sync + Send {
-    /// light intensity in [0, 1]
+    /// scale of `color` arriving at `hit`, i.e. the irradiance of a white light.
+    /// this is unbounded, bright lights go well above 1.
     fn intensity(&self, hit: &HitInfo) -> f64;
     fn dir_at(&self, hit: &HitInfo) -> Vec3;
     fn color(&self, dir: &HitInfo) -> Color;
@@ -14,10 +34,39 @@ pub trait LightSource: Sync + Send {
         hit.reflect().hit(world).is_some()
     }
 
+    /// draw a direction towards this light for `hit`.
+    /// lights arriving from many directions importance sample one and fold its pdf into `intensity`.
+    fn sample(&self, hit: &HitInfo) -> LightSample {
+        LightSample {
+            dir: self.dir_at(hit),
+            intensity: self.intensity(hit),
+            color: self.color(hit),
+        }
+    }
+
+    fn is_sample_in_shadow(&self, hit: &HitInfo, _sample: &LightSample, world: &World) -> bool {
+        self.is_in_shadow(hit, world)
+    }
+
     fn looked(&self, _ray: &Ray, _world: &World) -> Option<Color> {
         None
     }
 
+    /// extent of the emitted light used by `LightSampler`s, `None` for lights at infinity.
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    /// whether the light comes from a single point or direction, so scattered rays never reach it.
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    /// distance from `hit` to the light, `None` for lights at infinity.
+    fn distance(&self, _hit: &HitInfo) -> Option<f64> {
+        None
+    }
+
     fn illuminate(&self, hit: &HitInfo, world: &World) -> Vec3 {
         if self.is_in_shadow(hit, world) {
             (0., 0., 0.).into()
@@ -27,10 +76,66 @@ pub trait LightSource: Sync + Send {
     }
 }
 
+/// distance attenuation of lights at a position.
+///
+/// light falls off with the inverse square of the distance. distances within `radius` of the light
+/// are clamped to `radius`, and with a `range` the light is smoothly windowed to zero at that distance
+/// like glTF `KHR_lights_punctual` recommends.
+#[derive(Clone, Copy, Debug)]
+pub struct Attenuation {
+    radius: f64,
+    range: Option<f64>,
+}
+
+impl Attenuation {
+    pub fn new() -> Self {
+        Attenuation {
+            radius: 0.,
+            range: None,
+        }
+    }
+
+    pub fn with_radius(mut self, radius: f64) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn with_range(mut self, range: f64) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    pub fn range(&self) -> Option<f64> {
+        self.range
+    }
+
+    /// attenuation factor at `dist` away from the light.
+    pub fn factor(&self, dist: f64) -> f64 {
+        let d2 = max!(dist * dist, self.radius * self.radius, EPS * EPS);
+        let window = self.range.map_or(1., |range| {
+            let w = max!(1. - (dist / range).powi(4), 0.);
+            w * w
+        });
+        window / d2
+    }
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// a light as seen from one hit, with a single light direction sampled up front.
 pub struct LightInfo<'a> {
     light: &'a dyn LightSource,
     hit: &'a HitInfo,
     world: &'a World,
+    sample: LightSample,
 }
 
 impl LightInfo<'_> {
@@ -39,27 +144,49 @@ impl LightInfo<'_> {
         hit: &'a HitInfo,
         world: &'a World,
     ) -> LightInfo<'a> {
-        LightInfo { light, hit, world }
+        let sample = light.sample(hit);
+        LightInfo {
+            light,
+            hit,
+            world,
+            sample,
+        }
     }
 
     pub fn intensity(&self) -> f64 {
-        self.light.intensity(self.hit)
+        self.sample.intensity
     }
 
     pub fn dir(&self) -> Vec3 {
-        self.light.dir_at(self.hit)
+        self.sample.dir
     }
 
     pub fn is_in_shadow(&self) -> bool {
-        self.light.is_in_shadow(self.hit, self.world)
+        self.light
+            .is_sample_in_shadow(self.hit, &self.sample, self.world)
     }
 
     pub fn color(&self) -> Color {
-        self.light.color(self.hit)
+        self.sample.color
+    }
+
+    /// fraction of the light left after passing the media of the world on its way to the hit.
+    pub fn transmittance(&self) -> Color {
+        let volumes = &self.world.volumes;
+        if volumes.is_empty() {
+            return (1., 1., 1.).into();
+        }
+        let to_light = self.hit.spawn(-self.sample.dir);
+        let distance = self.light.distance(self.hit).unwrap_or(f64::INFINITY);
+        medium::transmittance(volumes, &to_light, distance)
     }
 
     pub fn illuminate(&self) -> Vec3 {
-        self.light.illuminate(self.hit, self.world)
+        if self.is_in_shadow() {
+            (0., 0., 0.).into()
+        } else {
+            self.intensity() * self.color() * self.transmittance()
+        }
     }
 }
 
@@ -67,6 +194,7 @@ impl LightInfo<'_> {
 pub struct ParallelLight {
     dir: Vec3,
     light_color: Color,
+    irradiance: f64,
 }
 
 impl ParallelLight {
@@ -74,6 +202,7 @@ impl ParallelLight {
         ParallelLight {
             dir: dir.into(),
             light_color: vec3!(1, 1, 1),
+            irradiance: 1.,
         }
     }
 
@@ -81,11 +210,17 @@ impl ParallelLight {
         self.light_color = color;
         self
     }
+
+    /// set irradiance on surfaces facing the light in W/m^2, or lux for glTF directional lights.
+    pub fn with_irradiance(mut self, irradiance: f64) -> ParallelLight {
+        self.irradiance = irradiance;
+        self
+    }
 }
 
 impl LightSource for ParallelLight {
     fn intensity(&self, _hit: &HitInfo) -> f64 {
-        1.
+        self.irradiance
     }
     fn dir_at(&self, _hit: &HitInfo) -> Vec3 {
         self.dir
@@ -99,39 +234,47 @@ impl LightSource for ParallelLight {
     fn color(&self, _hit: &HitInfo) -> Color {
         self.light_color
     }
+    fn is_delta(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct PointLight {
     pos: Vec3,
     light_color: Color,
+    intensity: f64,
+    attenuation: Attenuation,
 }
 
-// TODO: add intensity decay with distance
 impl LightSource for PointLight {
     fn intensity(&self, hit: &HitInfo) -> f64 {
-        1. / (self.pos - hit.pos()).len2()
+        self.intensity * self.attenuation.factor(self.pos.distance(hit.pos()))
     }
 
     fn dir_at(&self, hit: &HitInfo) -> Vec3 {
         (hit.pos() - self.pos).unit()
     }
     fn is_in_shadow(&self, hit: &HitInfo, world: &World) -> bool {
-        let point = hit.pos();
-        let dir = -self.dir_at(hit);
-        let ray = Ray::new(point, dir);
-        ray.hit(world)
-            .map(|hit| {
-                let l1 = (point - hit.pos()).len2();
-                let l2 = (point - self.pos).len2();
-                l1 + EPS < l2
-            })
-            .unwrap_or(false)
+        is_blocked(hit.pos(), self.pos, world)
     }
 
     fn color(&self, _hit: &HitInfo) -> Color {
         self.light_color
     }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let power = 4. * PI * self.intensity * luminance(self.light_color);
+        Some(LightBounds::omni(Aabb::point(self.pos), power))
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn distance(&self, hit: &HitInfo) -> Option<f64> {
+        Some(self.pos.distance(hit.pos()))
+    }
 }
 
 impl PointLight {
@@ -140,14 +283,199 @@ impl PointLight {
         self
     }
 
+    /// set radiant intensity in W/sr, or candela for glTF point lights.
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    /// set total emitted power in W.
+    pub fn with_power(mut self, power: f64) -> Self {
+        self.intensity = power / (4. * PI);
+        self
+    }
+
+    pub fn with_radius(mut self, radius: f64) -> Self {
+        self.attenuation = self.attenuation.with_radius(radius);
+        self
+    }
+
+    /// fade the light out to zero at `range` away.
+    pub fn with_range(mut self, range: f64) -> Self {
+        self.attenuation = self.attenuation.with_range(range);
+        self
+    }
+
     pub fn new<T: Into<Vec3>>(pos: T) -> Self {
         PointLight {
             pos: pos.into(),
             light_color: vec3!(1, 1, 1),
+            intensity: 1.,
+            attenuation: Attenuation::new(),
         }
     }
 }
 
+// whether anything in `world` lies between `point` and `light_pos`
+fn is_blocked(point: Vec3, light_pos: Vec3, world: &World) -> bool {
+    let ray = Ray::new(point, light_pos - point);
+    ray.hit(world)
+        .map(|hit| {
+            let l1 = (point - hit.pos()).len2();
+            let l2 = (point - light_pos).len2();
+            l1 + EPS < l2
+        })
+        .unwrap_or(false)
+}
+
+/// a point light emitting into a cone around `dir`.
+/// the light is full inside the inner cone and fades out smoothly towards the outer cone.
+#[derive(Clone)]
+pub struct SpotLight {
+    pos: Vec3,
+    dir: Vec3,
+    cos_inner: f64,
+    cos_outer: f64,
+    light_color: Color,
+    intensity: f64,
+    // total power kept across cone changes, when set by `with_power`
+    power: Option<f64>,
+    attenuation: Attenuation,
+    gobo: Option<Arc<dyn Texture>>,
+}
+
+impl SpotLight {
+    pub fn new<T: Into<Vec3>>(pos: T, dir: T) -> Self {
+        SpotLight {
+            pos: pos.into(),
+            dir: dir.into().unit(),
+            cos_inner: (30. / 180. * PI).cos(),
+            cos_outer: (45. / 180. * PI).cos(),
+            light_color: vec3!(1, 1, 1),
+            intensity: 1.,
+            power: None,
+            attenuation: Attenuation::new(),
+            gobo: None,
+        }
+    }
+
+    pub fn with_color(mut self, c: Color) -> Self {
+        self.light_color = c;
+        self
+    }
+
+    /// set inner and outer cone half angles in degree.
+    pub fn with_cone(mut self, inner: f64, outer: f64) -> Self {
+        let outer = max!(outer, inner);
+        self.cos_inner = (inner / 180. * PI).cos();
+        self.cos_outer = (outer / 180. * PI).cos();
+        match self.power {
+            Some(power) => self.with_power(power),
+            None => self,
+        }
+    }
+
+    /// set radiant intensity along the axis in W/sr, or candela for glTF spot lights.
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self.power = None;
+        self
+    }
+
+    /// set total emitted power in W, spread over the cone whenever it is set.
+    pub fn with_power(mut self, power: f64) -> Self {
+        let cone = 2. * PI * (1. - 0.5 * (self.cos_inner + self.cos_outer));
+        self.intensity = power / cone;
+        self.power = Some(power);
+        self
+    }
+
+    pub fn with_radius(mut self, radius: f64) -> Self {
+        self.attenuation = self.attenuation.with_radius(radius);
+        self
+    }
+
+    /// fade the light out to zero at `range` away.
+    pub fn with_range(mut self, range: f64) -> Self {
+        self.attenuation = self.attenuation.with_range(range);
+        self
+    }
+
+    /// project `gobo` along the light axis, mapping the outer cone onto texture coordinates [0, 1]^2.
+    pub fn with_gobo<T: Texture + 'static>(mut self, gobo: T) -> Self {
+        self.gobo = Some(Arc::new(gobo));
+        self
+    }
+
+    /// light falloff in [0, 1] for light leaving in direction `dir`.
+    pub fn falloff(&self, dir: Vec3) -> f64 {
+        let cos = dir.unit().dot(self.dir);
+        if cos >= self.cos_inner {
+            return 1.;
+        }
+        if cos <= self.cos_outer {
+            return 0.;
+        }
+        // smoothstep
+        let t = (cos - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        t * t * (3. - 2. * t)
+    }
+
+    fn gobo_color(&self, dir: Vec3) -> Color {
+        let gobo = match &self.gobo {
+            Some(gobo) => gobo,
+            None => return vec3!(1, 1, 1),
+        };
+        let (right, up) = coordinate_system(self.dir);
+        let dir = dir.unit();
+        let tan_outer = (1. - self.cos_outer.powi(2)).sqrt() / self.cos_outer;
+        let z = dir.dot(self.dir);
+        let u = 0.5 + 0.5 * dir.dot(right) / (z * tan_outer);
+        let v = 0.5 - 0.5 * dir.dot(up) / (z * tan_outer);
+        gobo.value(u, v, self.pos + dir)
+    }
+}
+
+impl LightSource for SpotLight {
+    fn intensity(&self, hit: &HitInfo) -> f64 {
+        let d = hit.pos() - self.pos;
+        self.intensity * self.falloff(d) * self.attenuation.factor(d.len())
+    }
+
+    fn dir_at(&self, hit: &HitInfo) -> Vec3 {
+        (hit.pos() - self.pos).unit()
+    }
+
+    fn is_in_shadow(&self, hit: &HitInfo, world: &World) -> bool {
+        is_blocked(hit.pos(), self.pos, world)
+    }
+
+    fn color(&self, hit: &HitInfo) -> Color {
+        self.light_color * self.gobo_color(hit.pos() - self.pos)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let theta_e = self.cos_outer.acos() - self.cos_inner.acos();
+        Some(LightBounds {
+            aabb: Aabb::point(self.pos),
+            // same scale as point lights so both compare fairly
+            power: 4. * PI * self.intensity * luminance(self.light_color),
+            axis: self.dir,
+            cos_theta_o: self.cos_inner,
+            cos_theta_e: theta_e.cos(),
+            two_sided: false,
+        })
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn distance(&self, hit: &HitInfo) -> Option<f64> {
+        Some(self.pos.distance(hit.pos()))
+    }
+}
+
 // sky light from `Ray Tracing in One Weekend`
 #[derive(Clone, Copy, Debug)]
 pub struct SkyLight;
@@ -191,8 +519,9 @@ impl LightSource for SkyLight {
 }
 
 pub struct LightShape {
-    shape: Box<Shape>,
+    shape: Box<dyn Shape>,
     color: Color,
+    radiance: f64,
 }
 
 impl LightShape {
@@ -200,14 +529,26 @@ impl LightShape {
         LightShape {
             shape: Box::new(shape),
             color: (1., 1., 1.).into(),
+            radiance: 1.,
         }
     }
+
+    pub fn with_color(mut self, color: Color) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// set emitted radiance in W/(sr m^2), or nits for glTF emissive strength.
+    pub fn with_radiance(mut self, radiance: f64) -> Self {
+        self.radiance = radiance;
+        self
+    }
 }
 
 impl LightSource for LightShape {
     fn intensity(&self, hit: &HitInfo) -> f64 {
         if self.shape.hit_info(&hit.reflect()).is_some() {
-            1.
+            self.radiance
         } else {
             0.
         }
@@ -230,14 +571,93 @@ impl LightSource for LightShape {
         })
     }
 
+    fn bounds(&self) -> Option<LightBounds> {
+        let aabb = self.shape.bounds()?;
+        // half the box surface approximates the area of flat and round shapes alike
+        let area = aabb.surface_area() / 2.;
+        let power = PI * area * self.radiance * luminance(self.color);
+        Some(LightBounds::omni(aabb, power))
+    }
+
     fn looked(&self, ray: &Ray, world: &World) -> Option<Color> {
         let info = self.shape.hit_info(ray)?;
-        ray.hit(world).map_or(Some(self.color), |rec| {
+        let color = self.radiance * self.color;
+        ray.hit(world).map_or(Some(color), |rec| {
             if info.distance() < rec.info.distance() {
-                Some(self.color)
+                Some(color)
             } else {
                 None
             }
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spot_light_falloff() {
+        let light = SpotLight::new(vec3!(0, 0, 0), vec3!(0, 0, -1)).with_cone(10., 20.);
+        assert_abs_diff_eq!(light.falloff(vec3!(0, 0, -1)), 1.);
+        assert_abs_diff_eq!(light.falloff(vec3!(0, 0, 1)), 0.);
+        assert_abs_diff_eq!(light.falloff(vec3!((30f64 / 180. * PI).tan(), 0, -1)), 0.);
+        let mid = light.falloff(vec3!((15f64 / 180. * PI).tan(), 0, -1));
+        assert!(0. < mid && mid < 1.);
+    }
+
+    #[test]
+    fn test_spot_light_power() {
+        let before = SpotLight::new(vec3!(0, 0, 0), vec3!(0, 0, -1))
+            .with_power(10.)
+            .with_cone(10., 20.);
+        let after = SpotLight::new(vec3!(0, 0, 0), vec3!(0, 0, -1))
+            .with_cone(10., 20.)
+            .with_power(10.);
+        assert_abs_diff_eq!(before.intensity, after.intensity, epsilon = 1e-12);
+        // emitted power is the axis intensity over the effective cone
+        let (inner, outer) = ((10f64 / 180. * PI).cos(), (20f64 / 180. * PI).cos());
+        let cone = 2. * PI * (1. - 0.5 * (inner + outer));
+        assert_abs_diff_eq!(before.intensity * cone, 10., epsilon = 1e-9);
+        // an explicit intensity is not rescaled by a later cone
+        let fixed = before.with_intensity(3.).with_cone(5., 40.);
+        assert_abs_diff_eq!(fixed.intensity, 3.);
+    }
+
+    #[test]
+    fn test_attenuation() {
+        let a = Attenuation::new();
+        assert_abs_diff_eq!(a.factor(2.), 0.25);
+        assert_abs_diff_eq!(a.with_radius(1.).factor(0.5), 1.);
+        let a = a.with_range(10.);
+        assert_abs_diff_eq!(a.factor(10.), 0.);
+        assert_abs_diff_eq!(a.factor(20.), 0.);
+        assert!(a.factor(5.) < 1. / 25. && a.factor(5.) > 0.);
+    }
+
+    #[test]
+    fn test_light_transmittance() {
+        use crate::{
+            medium::{HomogeneousMedium, Volume},
+            object::Sphere,
+        };
+
+        let light = PointLight::new(vec3!(0, 0, 2));
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+        let mut world = World::empty();
+        assert_eq!(
+            LightInfo::new(&light, &hit, &world).transmittance(),
+            vec3!(1, 1, 1)
+        );
+        // the light crosses a ball of fog 1 across
+        let medium = HomogeneousMedium::new((0.5, 1., 1.5), (0.5, 0.5, 0.5));
+        world.add_volume(Volume::new(Sphere::new(vec3!(0, 0, 1), 0.5), medium));
+        let n = 4000;
+        let tr = (0..n)
+            .map(|_| LightInfo::new(&light, &hit, &world).transmittance())
+            .sum::<Color>()
+            / n as f64;
+        let expected = vec3!((-1f64).exp(), (-1.5f64).exp(), (-2f64).exp());
+        assert_abs_diff_eq!(tr, expected, epsilon = 2e-2);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{io, path::Path, sync::Arc};
+
+use rand::Rng;
+
+use crate::{
+    image::Image,
+    object::World,
+    ray::{HitInfo, Ray},
+    sampling::Distribution2D,
+    util::{luminance, Color, Vec3, PI},
+};
+
+use super::{LightSample, LightSource};
+
+/// light from an equirectangular environment map surrounding the whole scene, with +z as zenith.
+/// directions are importance sampled by luminance. an empty image gives a black environment.
+#[derive(Clone)]
+pub struct EnvironmentLight {
+    image: Arc<Image>,
+    distribution: Arc<Distribution2D>,
+    rotation: f64,
+    intensity: f64,
+}
+
+impl EnvironmentLight {
+    pub fn new(image: Image) -> Self {
+        let image = if image.pixels().is_empty() {
+            Image::new(1, 1)
+        } else {
+            image
+        };
+        let (w, h) = (image.width(), image.height());
+        // rows near the poles cover a smaller solid angle
+        let func: Vec<_> = (0..h)
+            .flat_map(|y| {
+                let sin = ((y as f64 + 0.5) / h as f64 * PI).sin();
+                (0..w).map(move |x| (x, y, sin))
+            })
+            .map(|(x, y, sin)| luminance(image.get(x, y)) * sin)
+            .collect();
+        EnvironmentLight {
+            distribution: Arc::new(Distribution2D::new(&func, w, h)),
+            image: Arc::new(image),
+            rotation: 0.,
+            intensity: 1.,
+        }
+    }
+
+    /// load an environment map from a `.hdr` or `.exr` file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(Image::open(path)?))
+    }
+
+    /// rotate the map around the zenith by `deg` degree.
+    pub fn with_rotation(mut self, deg: f64) -> Self {
+        self.rotation = deg / 180. * PI;
+        self
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    fn dir_to_uv(&self, dir: Vec3) -> (f64, f64) {
+        let dir = dir.unit();
+        let theta = max!(-1., min!(1., dir.z)).acos();
+        let phi = dir.y.atan2(dir.x) + self.rotation;
+        ((phi / (2. * PI)).rem_euclid(1.), theta / PI)
+    }
+
+    fn uv_to_dir(&self, u: f64, v: f64) -> Vec3 {
+        let theta = v * PI;
+        let phi = u * 2. * PI - self.rotation;
+        vec3!(
+            theta.sin() * phi.cos(),
+            theta.sin() * phi.sin(),
+            theta.cos()
+        )
+    }
+
+    /// map color looking towards `dir`, before intensity scaling.
+    fn lookup(&self, dir: Vec3) -> Color {
+        let (u, v) = self.dir_to_uv(dir);
+        self.image.sample(u, v)
+    }
+
+    /// solid angle density of sampling `dir` with `sample`.
+    pub fn pdf(&self, dir: Vec3) -> f64 {
+        let (u, v) = self.dir_to_uv(dir);
+        let sin = (v * PI).sin();
+        if sin <= 0. {
+            return 0.;
+        }
+        self.distribution.pdf(u, v) / (2. * PI * PI * sin)
+    }
+}
+
+impl LightSource for EnvironmentLight {
+    fn intensity(&self, _hit: &HitInfo) -> f64 {
+        self.intensity
+    }
+
+    fn dir_at(&self, hit: &HitInfo) -> Vec3 {
+        -hit.dir_out()
+    }
+
+    fn color(&self, hit: &HitInfo) -> Color {
+        self.lookup(hit.dir_out())
+    }
+
+    fn sample(&self, _hit: &HitInfo) -> LightSample {
+        let mut rng = rand::thread_rng();
+        let ((u, v), pdf) = self.distribution.sample(rng.gen(), rng.gen());
+        let dir = self.uv_to_dir(u, v);
+        let sin = (v * PI).sin();
+        let pdf = pdf / (2. * PI * PI * sin);
+        LightSample {
+            dir: -dir,
+            intensity: if pdf > 0. { self.intensity / pdf } else { 0. },
+            color: self.image.sample(u, v),
+        }
+    }
+
+    fn is_sample_in_shadow(&self, hit: &HitInfo, sample: &LightSample, world: &World) -> bool {
+        Ray::new(hit.pos(), -sample.dir).hit(world).is_some()
+    }
+
+    fn looked(&self, ray: &Ray, world: &World) -> Option<Color> {
+        if ray.hit(world).is_none() {
+            Some(self.intensity * self.lookup(ray.dir()))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_environment_mapping() {
+        let light = EnvironmentLight::new(Image::new(8, 4)).with_rotation(30.);
+        for &dir in [vec3!(1, 0, 0), vec3!(0.3, -0.5, 0.8), vec3!(-1, -1, -1)].iter() {
+            let (u, v) = light.dir_to_uv(dir);
+            assert_abs_diff_eq!(light.uv_to_dir(u, v), dir.unit());
+        }
+        // an all black map falls back to uniform sampling over the map
+        assert_abs_diff_eq!(
+            light.pdf(vec3!(0, 1, 0)),
+            1. / (2. * PI * PI),
+            epsilon = 1e-9
+        );
+        let empty = EnvironmentLight::new(Image::new(0, 0));
+        assert_eq!(empty.lookup(vec3!(0, 0, 1)), vec3!(0, 0, 0));
+    }
+
+    #[test]
+    fn test_environment_bad_pixels() {
+        // nan and inf pixels in a loaded map are never sampled
+        let mut image = Image::new(8, 4);
+        image.set(1, 1, vec3!(1, 1, 1));
+        image.set(2, 1, vec3!(f64::NAN, 0, 0));
+        image.set(5, 2, vec3!(f64::INFINITY, 1, 1));
+        image.set(6, 2, vec3!(-1, -1, -1));
+        let path = std::env::temp_dir().join("cft_environment_bad_pixels.pfm");
+        image.save(&path).unwrap();
+        let light = EnvironmentLight::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let light = light.unwrap();
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+        for _ in 0..256 {
+            let sample = light.sample(&hit);
+            assert!(sample.intensity.is_finite());
+            let (u, v) = light.dir_to_uv(-sample.dir);
+            assert!((u * 8.).floor() == 1. && (v * 4.).floor() == 1.);
+        }
+    }
+}
@@ -0,0 +1,479 @@
+// light selection strategies, the light BVH follows pbrt-v4
+// see https://pbr-book.org/4ed/Light_Sources/Light_Sampling for details
+
+use std::sync::Arc;
+
+use crate::{
+    object::Aabb,
+    ray::HitInfo,
+    sampling::Distribution1D,
+    util::{Vec3, PI},
+};
+
+use super::LightSource;
+
+/// spatial and directional extent of the light a `LightSource` emits.
+#[derive(Debug, Clone, Copy)]
+pub struct LightBounds {
+    pub aabb: Aabb,
+    /// emitted power, only used to compare lights
+    pub power: f64,
+    /// main emission direction
+    pub axis: Vec3,
+    /// cosine of the angle around `axis` containing all emitting normals
+    pub cos_theta_o: f64,
+    /// cosine of the angle beyond `theta_o` light is still emitted into
+    pub cos_theta_e: f64,
+    pub two_sided: bool,
+}
+
+fn safe_sqrt(v: f64) -> f64 {
+    max!(v, 0.).sqrt()
+}
+
+// cos(max(0, a - b)) from sines and cosines
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        1.
+    } else {
+        cos_a * cos_b + sin_a * sin_b
+    }
+}
+
+// sin(max(0, a - b)) from sines and cosines
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        0.
+    } else {
+        sin_a * cos_b - cos_a * sin_b
+    }
+}
+
+// rotate `v` by `theta` around unit `axis`
+fn rotate(v: Vec3, axis: Vec3, theta: f64) -> Vec3 {
+    let (sin, cos) = theta.sin_cos();
+    v * cos + axis.cross(v) * sin + axis * axis.dot(v) * (1. - cos)
+}
+
+impl LightBounds {
+    /// bounds of a light emitting from `aabb` in all directions.
+    pub fn omni(aabb: Aabb, power: f64) -> Self {
+        LightBounds {
+            aabb,
+            power,
+            axis: vec3!(0, 0, 1),
+            cos_theta_o: -1.,
+            cos_theta_e: 0.,
+            two_sided: false,
+        }
+    }
+
+    pub fn union(self, rhs: LightBounds) -> LightBounds {
+        if self.power == 0. {
+            return rhs;
+        }
+        if rhs.power == 0. {
+            return self;
+        }
+        let (axis, cos_theta_o) = self.union_cone(&rhs);
+        LightBounds {
+            aabb: self.aabb.union(rhs.aabb),
+            power: self.power + rhs.power,
+            axis,
+            cos_theta_o,
+            cos_theta_e: min!(self.cos_theta_e, rhs.cos_theta_e),
+            two_sided: self.two_sided || rhs.two_sided,
+        }
+    }
+
+    // smallest cone containing the direction cones of both bounds
+    fn union_cone(&self, rhs: &LightBounds) -> (Vec3, f64) {
+        let (a, b) = (self, rhs);
+        let theta_a = a.cos_theta_o.acos();
+        let theta_b = b.cos_theta_o.acos();
+        let theta_d = max!(-1., min!(1., a.axis.dot(b.axis))).acos();
+        if min!(theta_d + theta_b, PI) <= theta_a {
+            return (a.axis, a.cos_theta_o);
+        }
+        if min!(theta_d + theta_a, PI) <= theta_b {
+            return (b.axis, b.cos_theta_o);
+        }
+        let theta_o = (theta_a + theta_d + theta_b) / 2.;
+        let wr = a.axis.cross(b.axis);
+        if theta_o >= PI || wr.len2() == 0. {
+            return (vec3!(0, 0, 1), -1.);
+        }
+        let axis = rotate(a.axis, wr.unit(), theta_o - theta_a);
+        (axis, theta_o.cos())
+    }
+
+    /// estimate of how much light this bounds contributes at point `p` with normal `n`.
+    pub fn importance(&self, p: Vec3, n: Vec3) -> f64 {
+        let pc = self.aabb.center();
+        let d2 = max!(p.distance(pc).powi(2), self.aabb.diagonal().len() / 2.);
+
+        let wi = (p - pc).unit();
+        let mut cos_theta_w = self.axis.dot(wi);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = safe_sqrt(1. - cos_theta_w * cos_theta_w);
+
+        // angle subtended by the bounds as seen from `p`
+        let cos_theta_b = if self.aabb.contains(p) {
+            -1.
+        } else {
+            let r2 = (self.aabb.diagonal() / 2.).len2();
+            let sin2 = r2 / p.distance(pc).powi(2);
+            if sin2 >= 1. {
+                -1.
+            } else {
+                safe_sqrt(1. - sin2)
+            }
+        };
+        let sin_theta_b = safe_sqrt(1. - cos_theta_b * cos_theta_b);
+
+        // minimum angle between the emission cone and the direction to `p`
+        let sin_theta_o = safe_sqrt(1. - self.cos_theta_o * self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.;
+        }
+
+        let mut importance = self.power * cos_theta_p / d2;
+        if n.len2() > 0. {
+            let cos_theta_i = wi.dot(n).abs();
+            let sin_theta_i = safe_sqrt(1. - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+        max!(importance, 0.)
+    }
+}
+
+/// strategy choosing one of `World::lights` to shade a hit with.
+pub trait LightSampler: Sync + Send {
+    /// choose a light for `hit` from `u` in [0, 1), returning its index and probability.
+    fn sample(&self, hit: &HitInfo, u: f64) -> Option<(usize, f64)>;
+
+    /// probability of choosing light `index` for `hit`.
+    fn pmf(&self, hit: &HitInfo, index: usize) -> f64;
+}
+
+/// choose every light with the same probability.
+#[derive(Debug, Clone, Copy)]
+pub struct UniformLightSampler {
+    count: usize,
+}
+
+impl UniformLightSampler {
+    pub fn new(lights: &[Arc<dyn LightSource>]) -> Self {
+        UniformLightSampler {
+            count: lights.len(),
+        }
+    }
+}
+
+impl LightSampler for UniformLightSampler {
+    fn sample(&self, _hit: &HitInfo, u: f64) -> Option<(usize, f64)> {
+        if self.count == 0 {
+            return None;
+        }
+        let i = min!((u * self.count as f64) as usize, self.count - 1);
+        Some((i, 1. / self.count as f64))
+    }
+
+    fn pmf(&self, _hit: &HitInfo, index: usize) -> f64 {
+        if index < self.count {
+            1. / self.count as f64
+        } else {
+            0.
+        }
+    }
+}
+
+// split lights into bounded ones and infinite ones without `LightSource::bounds`
+fn partition(lights: &[Arc<dyn LightSource>]) -> (Vec<(usize, LightBounds)>, Vec<usize>) {
+    let mut bounded = Vec::new();
+    let mut infinite = Vec::new();
+    for (i, light) in lights.iter().enumerate() {
+        match light.bounds() {
+            Some(b) if b.power > 0. => bounded.push((i, b)),
+            Some(_) => {}
+            None => infinite.push(i),
+        }
+    }
+    (bounded, infinite)
+}
+
+// probability of picking among the infinite lights instead of the bounded ones
+fn infinite_prob(infinite: usize, has_bounded: bool) -> f64 {
+    let bounded = if has_bounded { 1. } else { 0. };
+    if infinite == 0 {
+        0.
+    } else {
+        infinite as f64 / (infinite as f64 + bounded)
+    }
+}
+
+/// choose bounded lights proportional to their power.
+/// each infinite light is chosen as likely as all bounded lights together.
+#[derive(Debug, Clone)]
+pub struct PowerLightSampler {
+    bounded: Vec<usize>,
+    distribution: Option<Distribution1D>,
+    infinite: Vec<usize>,
+}
+
+impl PowerLightSampler {
+    pub fn new(lights: &[Arc<dyn LightSource>]) -> Self {
+        let (bounded, infinite) = partition(lights);
+        let powers: Vec<_> = bounded.iter().map(|(_, b)| b.power).collect();
+        PowerLightSampler {
+            bounded: bounded.iter().map(|(i, _)| *i).collect(),
+            distribution: if powers.is_empty() {
+                None
+            } else {
+                Some(Distribution1D::new(&powers))
+            },
+            infinite,
+        }
+    }
+}
+
+impl LightSampler for PowerLightSampler {
+    fn sample(&self, _hit: &HitInfo, u: f64) -> Option<(usize, f64)> {
+        let p_inf = infinite_prob(self.infinite.len(), self.distribution.is_some());
+        if u < p_inf {
+            let n = self.infinite.len();
+            let i = min!((u / p_inf * n as f64) as usize, n - 1);
+            return Some((self.infinite[i], p_inf / n as f64));
+        }
+        let u = (u - p_inf) / (1. - p_inf);
+        let (i, pmf) = self.distribution.as_ref()?.sample_discrete(u);
+        Some((self.bounded[i], pmf * (1. - p_inf)))
+    }
+
+    fn pmf(&self, _hit: &HitInfo, index: usize) -> f64 {
+        let p_inf = infinite_prob(self.infinite.len(), self.distribution.is_some());
+        if self.infinite.contains(&index) {
+            return p_inf / self.infinite.len() as f64;
+        }
+        match (
+            &self.distribution,
+            self.bounded.iter().position(|i| *i == index),
+        ) {
+            (Some(d), Some(i)) => d.pmf(i) * (1. - p_inf),
+            _ => 0.,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum LightNode {
+    Leaf {
+        bounds: LightBounds,
+        light: usize,
+    },
+    Interior {
+        bounds: LightBounds,
+        // the first child directly follows its parent
+        second: usize,
+    },
+}
+
+impl LightNode {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            LightNode::Leaf { bounds, .. } | LightNode::Interior { bounds, .. } => bounds,
+        }
+    }
+}
+
+/// choose bounded lights by their estimated contribution at the hit through a BVH over light bounds,
+/// so choosing a light costs time logarithmic in the light count.
+/// each infinite light is chosen as likely as all bounded lights together.
+#[derive(Debug, Clone)]
+pub struct BvhLightSampler {
+    nodes: Vec<LightNode>,
+    infinite: Vec<usize>,
+    // path from the root to each light, one bit per level with 1 for the second child
+    trails: Vec<Option<(u64, u32)>>,
+}
+
+impl BvhLightSampler {
+    pub fn new(lights: &[Arc<dyn LightSource>]) -> Self {
+        let (mut bounded, infinite) = partition(lights);
+        let mut sampler = BvhLightSampler {
+            nodes: Vec::new(),
+            infinite,
+            trails: vec![None; lights.len()],
+        };
+        if !bounded.is_empty() {
+            sampler.build(&mut bounded, 0, 0);
+        }
+        sampler
+    }
+
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        let index = self.nodes.len();
+        if lights.len() == 1 {
+            let (light, bounds) = lights[0];
+            self.nodes.push(LightNode::Leaf { bounds, light });
+            self.trails[light] = Some((trail, depth));
+            return index;
+        }
+
+        let bounds = lights[1..]
+            .iter()
+            .fold(lights[0].1, |acc, (_, b)| acc.union(*b));
+        // split at the median along the axis the centers spread the most
+        let centers = lights[1..]
+            .iter()
+            .fold(Aabb::point(lights[0].1.aabb.center()), |acc, (_, b)| {
+                acc.union_point(b.aabb.center())
+            });
+        let d = centers.diagonal();
+        let key = |b: &LightBounds| {
+            let c = b.aabb.center();
+            if d.x >= d.y && d.x >= d.z {
+                c.x
+            } else if d.y >= d.z {
+                c.y
+            } else {
+                c.z
+            }
+        };
+        lights.sort_by(|a, b| key(&a.1).partial_cmp(&key(&b.1)).unwrap());
+        let mid = lights.len() / 2;
+
+        self.nodes.push(LightNode::Interior { bounds, second: 0 });
+        let (left, right) = lights.split_at_mut(mid);
+        self.build(left, trail, depth + 1);
+        let second = self.build(right, trail | (1 << depth), depth + 1);
+        self.nodes[index] = LightNode::Interior { bounds, second };
+        index
+    }
+
+    // probabilities of descending into the two children of an interior node
+    fn child_probs(&self, node: usize, second: usize, hit: &HitInfo) -> Option<(f64, f64)> {
+        let (p, n) = (hit.pos(), hit.normal());
+        let a = self.nodes[node + 1].bounds().importance(p, n);
+        let b = self.nodes[second].bounds().importance(p, n);
+        if a + b <= 0. {
+            None
+        } else {
+            Some((a / (a + b), b / (a + b)))
+        }
+    }
+}
+
+impl LightSampler for BvhLightSampler {
+    fn sample(&self, hit: &HitInfo, u: f64) -> Option<(usize, f64)> {
+        let p_inf = infinite_prob(self.infinite.len(), !self.nodes.is_empty());
+        if u < p_inf {
+            let n = self.infinite.len();
+            let i = min!((u / p_inf * n as f64) as usize, n - 1);
+            return Some((self.infinite[i], p_inf / n as f64));
+        }
+        let mut u = (u - p_inf) / (1. - p_inf);
+        let mut pmf = 1. - p_inf;
+        let mut node = 0;
+        loop {
+            match self.nodes.get(node)? {
+                LightNode::Leaf { bounds, light } => {
+                    if bounds.importance(hit.pos(), hit.normal()) <= 0. {
+                        return None;
+                    }
+                    return Some((*light, pmf));
+                }
+                LightNode::Interior { second, .. } => {
+                    let (pa, pb) = self.child_probs(node, *second, hit)?;
+                    if u < pa {
+                        u = min!(u / pa, 1. - f64::EPSILON);
+                        pmf *= pa;
+                        node += 1;
+                    } else {
+                        u = min!((u - pa) / pb, 1. - f64::EPSILON);
+                        pmf *= pb;
+                        node = *second;
+                    }
+                }
+            }
+        }
+    }
+
+    fn pmf(&self, hit: &HitInfo, index: usize) -> f64 {
+        let p_inf = infinite_prob(self.infinite.len(), !self.nodes.is_empty());
+        if self.infinite.contains(&index) {
+            return p_inf / self.infinite.len() as f64;
+        }
+        let (trail, depth) = match self.trails.get(index) {
+            Some(Some(t)) => *t,
+            _ => return 0.,
+        };
+        let mut pmf = 1. - p_inf;
+        let mut node = 0;
+        for level in 0..depth {
+            if let LightNode::Interior { second, .. } = self.nodes[node] {
+                let (pa, pb) = match self.child_probs(node, second, hit) {
+                    Some(p) => p,
+                    None => return 0.,
+                };
+                if trail & (1 << level) == 0 {
+                    pmf *= pa;
+                    node += 1;
+                } else {
+                    pmf *= pb;
+                    node = second;
+                }
+            }
+        }
+        if self.nodes[node]
+            .bounds()
+            .importance(hit.pos(), hit.normal())
+            <= 0.
+        {
+            return 0.;
+        }
+        pmf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::light::PointLight;
+
+    #[test]
+    fn test_light_samplers() {
+        let mut lights: Vec<Arc<dyn LightSource>> = Vec::new();
+        for i in 0..10 {
+            let light = PointLight::new(vec3!(i * 10, 0, 2)).with_intensity(f64::from(i + 1));
+            lights.push(Arc::new(light));
+        }
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+
+        let samplers: Vec<Box<dyn LightSampler>> = vec![
+            Box::new(UniformLightSampler::new(&lights)),
+            Box::new(PowerLightSampler::new(&lights)),
+            Box::new(BvhLightSampler::new(&lights)),
+        ];
+        for sampler in samplers.iter() {
+            let total: f64 = (0..lights.len()).map(|i| sampler.pmf(&hit, i)).sum();
+            assert_abs_diff_eq!(total, 1., epsilon = 1e-9);
+            for k in 0..100 {
+                let (i, pmf) = sampler.sample(&hit, (f64::from(k) + 0.5) / 100.).unwrap();
+                assert_abs_diff_eq!(pmf, sampler.pmf(&hit, i), epsilon = 1e-9);
+            }
+        }
+
+        // the closest light dominates in the bvh
+        let bvh = BvhLightSampler::new(&lights);
+        assert!(bvh.pmf(&hit, 0) > bvh.pmf(&hit, 9));
+        // while the brightest one dominates by power
+        let power = PowerLightSampler::new(&lights);
+        assert!(power.pmf(&hit, 0) < power.pmf(&hit, 9));
+    }
+}
@@ -0,0 +1,274 @@
+// analytic daylight from `A Practical Analytic Model for Daylight` (Preetham et al. 1999)
+// see https://www.cs.utah.edu/~shirley/papers/sunsky/sunsky.pdf for details
+
+use rand::Rng;
+
+use crate::{
+    object::World,
+    ray::{HitInfo, Ray},
+    util::{coordinate_system, xyz_to_rgb, Color, Vec3, PI},
+};
+
+use super::{LightSample, LightSource};
+
+/// angular radius of the sun disc seen from earth
+const SUN_RADIUS: f64 = 0.2667 / 180. * PI;
+
+/// illuminance of the sun outside the atmosphere in klx
+const SUN_ILLUMINANCE: f64 = 128.;
+
+/// clear sky lit by the sun at `elevation` and `azimuth` through an atmosphere of `turbidity`.
+/// radiance is in kcd/m^2 scaled by `intensity`, with +z as zenith.
+#[derive(Clone, Copy, Debug)]
+pub struct PhysicalSky {
+    sun_dir: Vec3,
+    turbidity: f64,
+    intensity: f64,
+    // perez coefficients of Y, x and y
+    perez: [[f64; 5]; 3],
+    // zenith Y, x and y divided by perez_fn(0, theta_s)
+    zenith: [f64; 3],
+}
+
+fn perez_fn(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    (1. + c[0] * (c[1] / cos_theta).exp())
+        * (1. + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+}
+
+impl PhysicalSky {
+    /// create a sky with sun `elevation` above the horizon and `azimuth` from +x towards +y, both in degree.
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64) -> Self {
+        let (el, az) = (elevation / 180. * PI, azimuth / 180. * PI);
+        let sun_dir = vec3!(el.cos() * az.cos(), el.cos() * az.sin(), el.sin());
+        let t = max!(turbidity, 1.);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        // keep the sun slightly above the horizon, the model is undefined below it
+        let theta_s = min!(PI / 2. - el, PI / 2. - 1e-3);
+        let chi = (4. / 9. - t / 120.) * (PI - 2. * theta_s);
+        let zenith_y = max!((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192, 0.);
+        let chromaticity = |m: [[f64; 4]; 3]| {
+            let th = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.];
+            let row = |r: [f64; 4]| r.iter().zip(th.iter()).map(|(a, b)| a * b).sum::<f64>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_yc = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+        let zenith = [
+            zenith_y / perez_fn(&perez[0], 1., theta_s),
+            zenith_x / perez_fn(&perez[1], 1., theta_s),
+            zenith_yc / perez_fn(&perez[2], 1., theta_s),
+        ];
+
+        PhysicalSky {
+            sun_dir,
+            turbidity: t,
+            intensity: 0.1,
+            perez,
+            zenith,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn sun_dir(&self) -> Vec3 {
+        self.sun_dir
+    }
+
+    /// the sun disc lighting this sky, with the same intensity scale.
+    pub fn sun(&self) -> SunLight {
+        SunLight::new(self.sun_dir, self.turbidity).with_intensity(self.intensity)
+    }
+
+    /// sky radiance looking towards `dir`.
+    pub fn radiance(&self, dir: Vec3) -> Color {
+        let dir = dir.unit();
+        // below the horizon mirror the sky right above it
+        let cos_theta = max!(dir.z.abs(), 1e-3);
+        let dir = vec3!(dir.x, dir.y, cos_theta).unit();
+        let gamma = max!(-1., min!(1., dir.dot(self.sun_dir))).acos();
+        let big_y = self.zenith[0] * perez_fn(&self.perez[0], cos_theta, gamma);
+        let x = self.zenith[1] * perez_fn(&self.perez[1], cos_theta, gamma);
+        let y = self.zenith[2] * perez_fn(&self.perez[2], cos_theta, gamma);
+        if y <= 0. {
+            return (0., 0., 0.).into();
+        }
+        let xyz = vec3!(x / y * big_y, big_y, (1. - x - y) / y * big_y);
+        self.intensity * xyz_to_rgb(xyz)
+    }
+}
+
+impl LightSource for PhysicalSky {
+    fn intensity(&self, _hit: &HitInfo) -> f64 {
+        1.
+    }
+
+    fn dir_at(&self, hit: &HitInfo) -> Vec3 {
+        -hit.dir_out()
+    }
+
+    fn color(&self, hit: &HitInfo) -> Color {
+        self.radiance(hit.dir_out())
+    }
+
+    fn looked(&self, ray: &Ray, world: &World) -> Option<Color> {
+        if ray.hit(world).is_none() {
+            Some(self.radiance(ray.dir()))
+        } else {
+            None
+        }
+    }
+}
+
+/// the sun disc, attenuated by rayleigh and aerosol scattering along its path through the atmosphere.
+/// radiance is in kcd/m^2 scaled by `intensity`.
+#[derive(Clone, Copy, Debug)]
+pub struct SunLight {
+    dir: Vec3,
+    cos_radius: f64,
+    radiance: Color,
+    intensity: f64,
+}
+
+impl SunLight {
+    /// create a sun seen towards `dir` through an atmosphere of `turbidity`.
+    pub fn new<T: Into<Vec3>>(dir: T, turbidity: f64) -> Self {
+        let dir = dir.into().unit();
+        let solid_angle = 2. * PI * (1. - SUN_RADIUS.cos());
+
+        // relative optical mass of the atmosphere
+        let theta = max!(-1., min!(1., dir.z)).acos();
+        let radiance = if theta < PI / 2. {
+            let m = 1. / (theta.cos() + 0.15 * (93.885 - theta / PI * 180.).powf(-1.253));
+            let beta = 0.04608 * turbidity - 0.04586;
+            // transmittance at representative wavelengths in micrometer
+            let tau = |lambda: f64| {
+                let rayleigh = (-0.008735 * lambda.powf(-4.08) * m).exp();
+                let aerosol = (-beta * lambda.powf(-1.3) * m).exp();
+                rayleigh * aerosol
+            };
+            SUN_ILLUMINANCE / solid_angle * vec3!(tau(0.68), tau(0.55), tau(0.44))
+        } else {
+            (0., 0., 0.).into()
+        };
+
+        SunLight {
+            dir,
+            cos_radius: SUN_RADIUS.cos(),
+            radiance,
+            intensity: 0.1,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    fn solid_angle(&self) -> f64 {
+        2. * PI * (1. - self.cos_radius)
+    }
+}
+
+impl LightSource for SunLight {
+    fn intensity(&self, _hit: &HitInfo) -> f64 {
+        self.intensity * self.solid_angle()
+    }
+
+    fn dir_at(&self, _hit: &HitInfo) -> Vec3 {
+        -self.dir
+    }
+
+    fn color(&self, _hit: &HitInfo) -> Color {
+        self.radiance
+    }
+
+    fn is_in_shadow(&self, hit: &HitInfo, world: &World) -> bool {
+        Ray::new(hit.pos(), self.dir).hit(world).is_some()
+    }
+
+    fn sample(&self, _hit: &HitInfo) -> LightSample {
+        // uniform direction in the cone of the sun disc
+        let mut rng = rand::thread_rng();
+        let cos = 1. - rng.gen::<f64>() * (1. - self.cos_radius);
+        let sin = (1. - cos * cos).sqrt();
+        let phi = rng.gen_range(0., 2. * PI);
+        let (s, t) = coordinate_system(self.dir);
+        let dir = cos * self.dir + sin * (phi.cos() * s + phi.sin() * t);
+        LightSample {
+            dir: -dir,
+            intensity: self.intensity * self.solid_angle(),
+            color: self.radiance,
+        }
+    }
+
+    fn is_sample_in_shadow(&self, hit: &HitInfo, sample: &LightSample, world: &World) -> bool {
+        Ray::new(hit.pos(), -sample.dir).hit(world).is_some()
+    }
+
+    fn looked(&self, ray: &Ray, world: &World) -> Option<Color> {
+        if ray.dir().unit().dot(self.dir) >= self.cos_radius && ray.hit(world).is_none() {
+            Some(self.intensity * self.radiance)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_physical_sky() {
+        let sky = PhysicalSky::new(30., 0., 3.);
+        let zenith = sky.radiance(vec3!(0, 0, 1));
+        // a clear sky is blue
+        assert!(zenith.z > zenith.x);
+        // and brighter around the sun
+        assert!(sky.radiance(vec3!(1, 0, 0.6)).y > sky.radiance(vec3!(-1, 0, 0.6)).y);
+
+        // a low sun is redder than a high one
+        let low = PhysicalSky::new(5., 0., 3.).sun().radiance;
+        let high = PhysicalSky::new(80., 0., 3.).sun().radiance;
+        assert!(low.z / low.x < high.z / high.x);
+        assert_abs_diff_eq!(
+            PhysicalSky::new(-10., 0., 3.).sun().radiance,
+            vec3!(0, 0, 0)
+        );
+    }
+}
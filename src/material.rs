@@ -4,14 +4,50 @@ use crate::{
     util::Color,
 };
 
-pub use self::{basic::*, compose::*};
+pub use self::{basic::*, compose::*, diffuse::*, fresnel::*, microfacet::*, principled::*};
 
 mod basic;
 mod compose;
+mod diffuse;
+mod fresnel;
+mod microfacet;
+mod principled;
 
 pub trait Material: Sync + Send {
     fn render(&self, hit: &HitInfo, world: &World, traced: &[Color]) -> Color;
     fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
         vec![hit.reflect()]
     }
+
+    /// color of the surface at `hit` for the albedo pass, white by default like a mirror or clear glass.
+    fn albedo(&self, _hit: &HitInfo) -> Color {
+        (1., 1., 1.).into()
+    }
+
+    /// radiance the surface gives off by itself at `hit`, which `render` includes.
+    fn emission(&self, _hit: &HitInfo) -> Color {
+        (0., 0., 0.).into()
+    }
+
+    /// `render` split into the light gathered from `world` without the scattered rays,
+    /// like the emission and sampled lights, and the weight of the radiance traced along
+    /// each of the `rays` scattered rays, which `render` adds to it.
+    ///
+    /// the default finds the weights by rendering white along one ray at a time, which fits
+    /// materials which look at `world` or scatter rays, but not both. others should override it
+    /// so that lights are sampled once.
+    fn render_weights(&self, hit: &HitInfo, world: &World, rays: usize) -> (Color, Vec<Color>) {
+        let black = Color::new(0., 0., 0.);
+        let mut traced = vec![black; rays];
+        let light = self.render(hit, world, &traced);
+        let weights = (0..rays)
+            .map(|i| {
+                traced[i] = Color::new(1., 1., 1.);
+                let weight = self.render(hit, world, &traced) - light;
+                traced[i] = black;
+                weight
+            })
+            .collect();
+        (light, weights)
+    }
 }
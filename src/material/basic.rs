@@ -1,11 +1,14 @@
+use rand::Rng;
+
 use crate::{
-    light::LightInfo,
+    light::{LightInfo, LightSource},
     object::World,
     ray::{HitInfo, Ray},
+    spectrum::{blackbody_normalized, integrate_rgb, RGB_WAVELENGTHS},
     util::{Color, Vec3},
 };
 
-use super::Material;
+use super::{fresnel_thin_film, Material};
 
 #[derive(Clone, Copy)]
 pub struct PhongModel {
@@ -45,6 +48,38 @@ impl PhongModel {
     pub fn diffuse(&self) -> f64 {
         self.diffuse
     }
+
+    // illumination of `hit` by a single light
+    fn shade(&self, light: &dyn LightSource, hit: &HitInfo, world: &World) -> Color {
+        if let Some(c) = light.looked(&hit.reflect(), world) {
+            return hit.tint(c);
+        }
+        let info = LightInfo::new(light, hit, world);
+        let ratio1 = 1.;
+        let ratio2 = hit.dir_out().dot(-info.dir());
+        let mut ratio = ratio1 * ratio2.powf(self.shininess);
+        ratio = min!(ratio, 1.);
+        ratio = max!(ratio, 0.);
+
+        // specular illumination
+        let si = ratio;
+
+        // diffuse illumination
+        let di = max!(hit.normal().dot(-info.dir()), 0.);
+
+        // ambient illumination
+        let ai = 0.1;
+
+        // light intensity, dimmed by media on the way
+        let li = info.intensity() * hit.tint(info.color()) * info.transmittance();
+
+        // total intensity = specular + diffuse + ambient
+        if info.is_in_shadow() {
+            ai * li
+        } else {
+            (si * 0.5 + di * 0.5 + ai) * li
+        }
+    }
 }
 
 impl Default for PhongModel {
@@ -55,46 +90,31 @@ impl Default for PhongModel {
 
 impl Material for PhongModel {
     fn render(&self, hit: &HitInfo, world: &World, _traced: &[Color]) -> Color {
-        let c = world
-            .lights
-            .iter()
-            .map(|light| {
-                if let Some(c) = light.looked(&hit.reflect(), world) {
-                    return c;
-                }
-                let info = LightInfo::new(light.as_ref(), hit, world);
-                let ratio1 = 1.;
-                let ratio2 = hit.dir_out().dot(-info.dir());
-                let mut ratio = ratio1 * ratio2.powf(self.shininess);
-                ratio = min!(ratio, 1.);
-                ratio = max!(ratio, 0.);
-
-                // specular illumination
-                let si = ratio;
-
-                // diffuse illumination
-                let di = max!(hit.normal().dot(-info.dir()), 0.);
-
-                // ambient illumination
-                let ai = 0.1;
-
-                // light intensity
-                let li = info.intensity() * info.color();
-
-                // total intensity = specular + diffuse + ambient
-                if info.is_in_shadow() {
-                    ai * li
-                } else {
-                    (si * 0.5 + di * 0.5 + ai) * li
-                }
-            })
-            .sum::<Vec3>();
+        let c = if world.light_sampler.is_some() {
+            world
+                .sample_light(hit)
+                .map_or((0., 0., 0.).into(), |(light, pmf)| {
+                    self.shade(light, hit, world) / pmf
+                })
+        } else {
+            world
+                .lights
+                .iter()
+                .map(|light| self.shade(light.as_ref(), hit, world))
+                .sum::<Vec3>()
+        };
         let kd = self.diffuse();
-        kd * c * self.color
+        kd * c * hit.tint(self.color)
+    }
+    fn render_weights(&self, hit: &HitInfo, world: &World, _rays: usize) -> (Color, Vec<Color>) {
+        (self.render(hit, world, &[]), Vec::new())
     }
     fn scatter(&self, _hit: &HitInfo) -> Vec<Ray> {
         Vec::new()
     }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.diffuse() * hit.tint(self.color)
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -121,6 +141,10 @@ impl Material for Specular {
     fn render(&self, _hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
         self.albedo * traced[0]
     }
+
+    fn albedo(&self, _hit: &HitInfo) -> Color {
+        (self.albedo, self.albedo, self.albedo).into()
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -128,6 +152,7 @@ pub struct Transparent {
     opacity: f64,
     ior: f64,
     color: Color,
+    absorption: Color,
 }
 
 impl Transparent {
@@ -138,6 +163,20 @@ impl Transparent {
     pub fn opacity(&self) -> f64 {
         self.opacity
     }
+
+    pub fn absorption(&self) -> Color {
+        self.absorption
+    }
+
+    // beer-lambert transmittance of the path inside the material which ends at `hit`
+    fn transmittance(&self, hit: &HitInfo) -> Color {
+        if !hit.is_to_outward() {
+            return (1., 1., 1.).into();
+        }
+        let d = hit.distance();
+        let a = hit.tint(self.absorption);
+        vec3!((-a.x * d).exp(), (-a.y * d).exp(), (-a.z * d).exp())
+    }
 }
 
 impl Transparent {
@@ -146,6 +185,7 @@ impl Transparent {
             opacity,
             ior,
             color: (1., 1., 1.).into(),
+            absorption: (0., 0., 0.).into(),
         }
     }
 
@@ -163,11 +203,24 @@ impl Transparent {
         self.color = color.into();
         self
     }
+
+    /// absorption coefficient per unit length travelled inside the material.
+    pub fn with_absorption<T: Into<Color>>(mut self, absorption: T) -> Self {
+        self.absorption = absorption.into();
+        self
+    }
+
+    /// absorb so that light keeps `color` after travelling `distance` inside the material.
+    pub fn with_transmittance<T: Into<Color>>(self, color: T, distance: f64) -> Self {
+        let c = color.into();
+        let a = |c: f64| -max!(c, 1e-6).ln() / distance;
+        self.with_absorption((a(c.x), a(c.y), a(c.z)))
+    }
 }
 
 impl Material for Transparent {
-    fn render(&self, _hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
-        self.color * (1. - self.opacity) * traced[0]
+    fn render(&self, hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
+        hit.tint(self.color) * (1. - self.opacity) * self.transmittance(hit) * traced[0]
     }
 
     fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
@@ -181,4 +234,100 @@ impl Material for Transparent {
             .map(|ray| vec![ray])
             .unwrap_or(vec![hit.reflect()])
     }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        hit.tint(self.color)
+    }
+}
+
+/// emitter glowing like a black body at `temperature` kelvin, with a spectral peak of `intensity`.
+#[derive(Clone, Copy)]
+pub struct Blackbody {
+    temperature: f64,
+    intensity: f64,
+    // color of the normalized spectrum for rgb rendering
+    rgb: Color,
+}
+
+impl Blackbody {
+    pub fn new(temperature: f64) -> Self {
+        Blackbody {
+            temperature,
+            intensity: 1.,
+            rgb: integrate_rgb(|l| blackbody_normalized(l, temperature)),
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+}
+
+impl Material for Blackbody {
+    fn render(&self, hit: &HitInfo, _world: &World, _traced: &[Color]) -> Color {
+        self.emission(hit)
+    }
+
+    fn scatter(&self, _hit: &HitInfo) -> Vec<Ray> {
+        Vec::new()
+    }
+
+    fn albedo(&self, _hit: &HitInfo) -> Color {
+        (0., 0., 0.).into()
+    }
+
+    fn emission(&self, hit: &HitInfo) -> Color {
+        let t = self.temperature;
+        let radiance = hit.wavelengths().map_or(self.rgb, |l| {
+            vec3!(
+                blackbody_normalized(l.x, t),
+                blackbody_normalized(l.y, t),
+                blackbody_normalized(l.z, t)
+            )
+        });
+        self.intensity * radiance
+    }
+}
+
+/// free standing interference film in air, like a soap bubble, of `ior` and `thickness` in nm.
+#[derive(Clone, Copy)]
+pub struct ThinFilm {
+    ior: f64,
+    thickness: f64,
+}
+
+impl ThinFilm {
+    pub fn new(ior: f64, thickness: f64) -> Self {
+        ThinFilm { ior, thickness }
+    }
+
+    pub fn with_thickness(mut self, thickness: f64) -> Self {
+        self.thickness = thickness;
+        self
+    }
+}
+
+impl Material for ThinFilm {
+    fn render(&self, _hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
+        traced.iter().cloned().sum()
+    }
+
+    // reflect or pass straight through by the mean reflectance, the film is too thin to offset the ray
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let lambda = hit.wavelengths().unwrap_or(RGB_WAVELENGTHS);
+        let cos = -hit.dir_in().dot(hit.normal());
+        let r = fresnel_thin_film(cos, self.ior, self.thickness, 1., lambda);
+        let p = (r.x + r.y + r.z) / 3.;
+        if rand::thread_rng().gen::<f64>() < p {
+            vec![hit.reflect().with_weight(r / p)]
+        } else {
+            let t = Color::new(1., 1., 1.) - r;
+            vec![hit.spawn(hit.dir_in()).with_weight(t / (1. - p))]
+        }
+    }
 }
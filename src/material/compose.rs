@@ -1,10 +1,21 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use rand::Rng;
+
 use crate::{
     object::World,
     ray::{HitInfo, Ray},
+    sampling::sample_cosine_hemisphere,
+    spectrum::{Ior, RGB_WAVELENGTHS},
+    texture::Texture,
     util::*,
 };
 
-use super::*;
+use super::{microfacet::reflect, *};
 
 #[derive(Clone, Copy)]
 pub struct Metal {
@@ -42,7 +53,10 @@ impl Metal {
 impl Material for Metal {
     fn render(&self, hit: &HitInfo, world: &World, traced: &[Color]) -> Color {
         let si = self.s.render(hit, world, traced);
-        si * self.color
+        si * hit.tint(self.color)
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.s.albedo() * hit.tint(self.color)
     }
     fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
         let mut r = hit.reflect();
@@ -55,6 +69,7 @@ impl Material for Metal {
 pub struct Dielectric {
     s: Specular,
     r: Transparent,
+    dispersion: Option<Ior>,
 }
 
 impl Dielectric {
@@ -62,11 +77,32 @@ impl Dielectric {
         Dielectric {
             s: Specular::new(1.),
             r: Transparent::new(0., ior),
+            dispersion: None,
         }
     }
 
     pub fn with_ior(mut self, ior: f64) -> Self {
         self.r = self.r.with_ior(ior);
+        self.dispersion = None;
+        self
+    }
+
+    /// vary the ior with wavelength in spectral mode, rgb rendering uses its nominal value.
+    pub fn with_dispersion(mut self, ior: Ior) -> Self {
+        self.r = self.r.with_ior(ior.nominal());
+        self.dispersion = Some(ior);
+        self
+    }
+
+    /// absorption coefficient per unit length travelled inside.
+    pub fn with_absorption<T: Into<Color>>(mut self, absorption: T) -> Self {
+        self.r = self.r.with_absorption(absorption);
+        self
+    }
+
+    /// absorb so that light keeps `color` after travelling `distance` inside.
+    pub fn with_transmittance<T: Into<Color>>(mut self, color: T, distance: f64) -> Self {
+        self.r = self.r.with_transmittance(color, distance);
         self
     }
 }
@@ -76,13 +112,35 @@ impl Material for Dielectric {
         self.r.render(hit, world, traced)
     }
 
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.r.albedo(hit)
+    }
+
     fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
-        use rand::Rng;
         let mut rng = rand::thread_rng();
-        if rng.gen_range(0., 1.) < hit.reflect_prob(self.r.ior()) {
+        let (r, hero) = match (self.dispersion, hit.wavelengths()) {
+            (Some(ior), Some(lambda)) => {
+                // the path only stays valid for the hero wavelength, unless it was split before
+                let split = lambda.y != lambda.x || lambda.z != lambda.x;
+                let hero = if split { Some(lambda.x) } else { None };
+                (self.r.with_ior(ior.at(lambda.x)), hero)
+            }
+            _ => (self.r, None),
+        };
+        let rays = if rng.gen_range(0., 1.) < hit.reflect_prob(r.ior()) {
             self.s.scatter(hit)
         } else {
-            self.r.scatter(hit)
+            r.scatter(hit)
+        };
+        match hero {
+            Some(l) => rays
+                .into_iter()
+                .map(|ray| {
+                    let weight = ray.weight() * vec3!(3, 0, 0);
+                    ray.with_wavelengths(vec3!(l, l, l)).with_weight(weight)
+                })
+                .collect(),
+            None => rays,
         }
     }
 }
@@ -109,11 +167,554 @@ impl LambertianModel {
 
 impl Material for LambertianModel {
     fn render(&self, hit: &HitInfo, world: &World, traced: &[Color]) -> Color {
-        self.c * self.s.render(hit, world, traced)
+        hit.tint(self.c) * self.s.render(hit, world, traced)
     }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.s.albedo() * hit.tint(self.c)
+    }
+    // cosine weighted around the normal, so the sample weight is just the albedo
     fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
-        let mut r = hit.reflect();
-        r.dir = (r.dir() + gen_point_in_sphere(1.)).unit();
-        vec![r]
+        let wi = sample_cosine_hemisphere(rand::thread_rng().gen());
+        vec![hit.spawn(Frame::new(hit.normal()).to_world(wi))]
+    }
+}
+
+// a number in [0, 1) which is the same for every call on the same hit,
+// so that `scatter` and `render` pick the same layer
+fn hit_random(hit: &HitInfo, salt: u64) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    salt.hash(&mut hasher);
+    let (p, d) = (hit.pos(), hit.dir_in());
+    for x in [p.x, p.y, p.z, d.x, d.y, d.z].iter() {
+        x.to_bits().hash(&mut hasher);
+    }
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// stochastic blend of two materials, `weight` is the fraction of `b`.
+pub struct Mix<A, B> {
+    a: A,
+    b: B,
+    weight: Arc<dyn Texture>,
+    salt: u64,
+}
+
+impl<A: Material, B: Material> Mix<A, B> {
+    pub fn new<T: Texture + 'static>(a: A, b: B, weight: T) -> Self {
+        Mix {
+            a,
+            b,
+            weight: Arc::new(weight),
+            salt: rand::random(),
+        }
+    }
+
+    fn pick_b(&self, hit: &HitInfo) -> bool {
+        let w = hit.texture(&self.weight).x;
+        hit_random(hit, self.salt) < w
+    }
+}
+
+impl<A: Material, B: Material> Material for Mix<A, B> {
+    fn render(&self, hit: &HitInfo, world: &World, traced: &[Color]) -> Color {
+        if self.pick_b(hit) {
+            self.b.render(hit, world, traced)
+        } else {
+            self.a.render(hit, world, traced)
+        }
+    }
+
+    fn render_weights(&self, hit: &HitInfo, world: &World, rays: usize) -> (Color, Vec<Color>) {
+        if self.pick_b(hit) {
+            self.b.render_weights(hit, world, rays)
+        } else {
+            self.a.render_weights(hit, world, rays)
+        }
+    }
+
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        if self.pick_b(hit) {
+            self.b.scatter(hit)
+        } else {
+            self.a.scatter(hit)
+        }
+    }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        if self.pick_b(hit) {
+            self.b.albedo(hit)
+        } else {
+            self.a.albedo(hit)
+        }
+    }
+
+    fn emission(&self, hit: &HitInfo) -> Color {
+        if self.pick_b(hit) {
+            self.b.emission(hit)
+        } else {
+            self.a.emission(hit)
+        }
+    }
+}
+
+/// smooth or rough dielectric coat, like varnish or lacquer, over any `base`.
+/// the base is seen through the coat without refraction and filtered by its `color`.
+pub struct Coated<M> {
+    base: M,
+    ior: f64,
+    color: Color,
+    distribution: TrowbridgeReitz,
+    // ior and thickness in nm of an interference film on the coat
+    thin_film: Option<(f64, f64)>,
+    salt: u64,
+}
+
+impl<M: Material> Coated<M> {
+    pub fn new(base: M) -> Self {
+        Coated {
+            base,
+            ior: 1.5,
+            color: (1., 1., 1.).into(),
+            distribution: TrowbridgeReitz::new(0., 0.),
+            thin_film: None,
+            salt: rand::random(),
+        }
+    }
+
+    pub fn with_ior(mut self, ior: f64) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    pub fn with_color<T: Into<Color>>(mut self, color: T) -> Self {
+        self.color = color.into();
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Self {
+        self.distribution = TrowbridgeReitz::from_roughness(roughness, roughness);
+        self
+    }
+
+    /// iridescent film of `ior` and `thickness` in nm on top of the coat, like oil on water.
+    pub fn with_thin_film(mut self, ior: f64, thickness: f64) -> Self {
+        self.thin_film = Some((ior, thickness));
+        self
+    }
+
+    fn reflectance(&self, hit: &HitInfo, cos: f64) -> Color {
+        match self.thin_film {
+            Some((film_ior, thickness)) => {
+                let lambda = hit.wavelengths().unwrap_or(RGB_WAVELENGTHS);
+                fresnel_thin_film(cos, film_ior, thickness, self.ior, lambda)
+            }
+            None => {
+                let f = fresnel_dielectric(cos, self.ior);
+                (f, f, f).into()
+            }
+        }
+    }
+
+    // the coat is picked by its mean reflectance seen from outside, returning the reflectance and the probability
+    fn pick_coat(&self, hit: &HitInfo) -> (bool, Color, f64) {
+        if hit.is_to_outward() {
+            return (false, (0., 0., 0.).into(), 0.);
+        }
+        let f = self.reflectance(hit, -hit.dir_in().dot(hit.normal()));
+        let p = (f.x + f.y + f.z) / 3.;
+        (hit_random(hit, self.salt) < p, f, p)
+    }
+}
+
+impl<M: Material> Material for Coated<M> {
+    fn render(&self, hit: &HitInfo, world: &World, traced: &[Color]) -> Color {
+        let (coat, f, p) = self.pick_coat(hit);
+        if coat {
+            traced.iter().cloned().sum()
+        } else {
+            let rest = (Color::new(1., 1., 1.) - f) / (1. - p);
+            rest * hit.tint(self.color) * self.base.render(hit, world, traced)
+        }
+    }
+
+    fn render_weights(&self, hit: &HitInfo, world: &World, rays: usize) -> (Color, Vec<Color>) {
+        let (coat, f, p) = self.pick_coat(hit);
+        if coat {
+            return ((0., 0., 0.).into(), vec![(1., 1., 1.).into(); rays]);
+        }
+        let rest = (Color::new(1., 1., 1.) - f) / (1. - p) * hit.tint(self.color);
+        let (light, weights) = self.base.render_weights(hit, world, rays);
+        (
+            rest * light,
+            weights.into_iter().map(|w| rest * w).collect(),
+        )
+    }
+
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let (coat, f, p) = self.pick_coat(hit);
+        if !coat {
+            return self.base.scatter(hit);
+        }
+        let d = &self.distribution;
+        if d.is_smooth() {
+            return vec![hit.reflect().with_weight(f / p)];
+        }
+        let frame = hit.frame();
+        let wo = frame.to_local(-hit.dir_in());
+        let wm = d.sample_wm(wo, rand::thread_rng().gen());
+        let wi = reflect(wo, wm);
+        if wi.z <= 0. {
+            return vec![];
+        }
+        let weight = self.reflectance(hit, wo.dot(wm)) * d.g(wo, wi) / d.g1(wo) / p;
+        vec![hit.spawn(frame.to_world(wi)).with_weight(weight)]
+    }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        hit.tint(self.color) * self.base.albedo(hit)
+    }
+}
+
+/// velvet or cloth sheen over any `base`, a retro-reflective glow at grazing angles.
+pub struct Sheen<M> {
+    base: M,
+    color: Color,
+    distribution: Charlie,
+    // directional albedo of the sheen lobe by the cosine of the view angle
+    albedo: [f64; SHEEN_ALBEDO_SIZE],
+    salt: u64,
+}
+
+const SHEEN_ALBEDO_SIZE: usize = 16;
+
+impl<M: Material> Sheen<M> {
+    pub fn new<T: Into<Color>>(base: M, color: T) -> Self {
+        Sheen {
+            base,
+            color: color.into(),
+            distribution: Charlie::new(0., 0.),
+            albedo: [0.; SHEEN_ALBEDO_SIZE],
+            salt: rand::random(),
+        }
+        .with_roughness(0.5)
+    }
+
+    pub fn with_roughness(self, roughness: f64) -> Self {
+        self.with_anisotropic_roughness(roughness, roughness)
+    }
+
+    /// stretch the sheen along the tangent (`roughness_x`) or bitangent (`roughness_y`)
+    /// of the shading frame, like brushed fabric.
+    pub fn with_anisotropic_roughness(mut self, roughness_x: f64, roughness_y: f64) -> Self {
+        self.distribution = Charlie::from_roughness(roughness_x, roughness_y);
+        // integrate brdf * cos over the hemisphere for each view angle, averaged over the
+        // view azimuths of a quadrant, which the distribution mirrors to the others
+        let (nt, np, no) = (32, 64, 4);
+        for (i, a) in self.albedo.iter_mut().enumerate() {
+            let cos_o = (i as f64 + 0.5) / SHEEN_ALBEDO_SIZE as f64;
+            let sin_o = (1. - cos_o * cos_o).sqrt();
+            let mut sum = 0.;
+            for o in 0..no {
+                let phi_o = (o as f64 + 0.5) / no as f64 * PI / 2.;
+                let wo = vec3!(sin_o * phi_o.cos(), sin_o * phi_o.sin(), cos_o);
+                for t in 0..nt {
+                    let cos_i = (t as f64 + 0.5) / nt as f64;
+                    let sin_i = (1. - cos_i * cos_i).sqrt();
+                    for k in 0..np {
+                        let phi = (k as f64 + 0.5) / np as f64 * 2. * PI;
+                        let wi = vec3!(sin_i * phi.cos(), sin_i * phi.sin(), cos_i);
+                        sum += self.distribution.brdf(wo, wi) * cos_i;
+                    }
+                }
+            }
+            *a = min!(sum * 2. * PI / (nt * np * no) as f64, 1.);
+        }
+        self
+    }
+
+    fn albedo(&self, cos: f64) -> f64 {
+        let i = (cos * SHEEN_ALBEDO_SIZE as f64) as usize;
+        self.albedo[min!(i, SHEEN_ALBEDO_SIZE - 1)]
+    }
+
+    // the sheen is picked by its reflectance, returning the base attenuation and the probability
+    fn pick_sheen(&self, hit: &HitInfo) -> (bool, Color, f64) {
+        if hit.is_to_outward() {
+            return (false, (1., 1., 1.).into(), 0.);
+        }
+        let e = self.albedo(-hit.dir_in().dot(hit.normal()));
+        // the base is seen through the fibers which did not reflect
+        let color = hit.tint(self.color);
+        let reflected = max!(color.x, max!(color.y, color.z)) * e;
+        let p = max!(min!(reflected, 0.9), 0.);
+        let rest = (1. - reflected) / (1. - p) * Color::new(1., 1., 1.);
+        (hit_random(hit, self.salt) < p, rest, p)
+    }
+}
+
+impl<M: Material> Material for Sheen<M> {
+    fn render(&self, hit: &HitInfo, world: &World, traced: &[Color]) -> Color {
+        let (sheen, rest, _) = self.pick_sheen(hit);
+        if sheen {
+            traced.iter().cloned().sum()
+        } else {
+            rest * self.base.render(hit, world, traced)
+        }
+    }
+
+    fn render_weights(&self, hit: &HitInfo, world: &World, rays: usize) -> (Color, Vec<Color>) {
+        let (sheen, rest, _) = self.pick_sheen(hit);
+        if sheen {
+            return ((0., 0., 0.).into(), vec![(1., 1., 1.).into(); rays]);
+        }
+        let (light, weights) = self.base.render_weights(hit, world, rays);
+        (
+            rest * light,
+            weights.into_iter().map(|w| rest * w).collect(),
+        )
+    }
+
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let (sheen, _, p) = self.pick_sheen(hit);
+        if !sheen {
+            return self.base.scatter(hit);
+        }
+        let frame = hit.frame();
+        let wo = frame.to_local(-hit.dir_in());
+        let wi = sample_cosine_hemisphere(rand::thread_rng().gen());
+        // brdf * cos / pdf of cosine sampling is brdf * pi
+        let weight = PI * self.distribution.brdf(wo, wi) / p * hit.tint(self.color);
+        vec![hit.spawn(frame.to_world(wi)).with_weight(weight)]
+    }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.base.albedo(hit)
+    }
+}
+
+/// different materials on the `front` and `back` faces of a surface,
+/// the front is the side the shape normal points to.
+pub struct TwoSided<F, B> {
+    front: F,
+    back: B,
+}
+
+impl<F: Material, B: Material> TwoSided<F, B> {
+    pub fn new(front: F, back: B) -> Self {
+        TwoSided { front, back }
+    }
+}
+
+impl<F: Material, B: Material> Material for TwoSided<F, B> {
+    fn render(&self, hit: &HitInfo, world: &World, traced: &[Color]) -> Color {
+        if hit.is_to_outward() {
+            self.back.render(hit, world, traced)
+        } else {
+            self.front.render(hit, world, traced)
+        }
+    }
+
+    fn render_weights(&self, hit: &HitInfo, world: &World, rays: usize) -> (Color, Vec<Color>) {
+        if hit.is_to_outward() {
+            self.back.render_weights(hit, world, rays)
+        } else {
+            self.front.render_weights(hit, world, rays)
+        }
+    }
+
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        if hit.is_to_outward() {
+            self.back.scatter(hit)
+        } else {
+            self.front.scatter(hit)
+        }
+    }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        if hit.is_to_outward() {
+            self.back.albedo(hit)
+        } else {
+            self.front.albedo(hit)
+        }
+    }
+
+    fn emission(&self, hit: &HitInfo) -> Color {
+        if hit.is_to_outward() {
+            self.back.emission(hit)
+        } else {
+            self.front.emission(hit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hit_random() {
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0.3, 0.2, 0), vec3!(0, 1, -1));
+        let u = hit_random(&hit, 7);
+        assert!((0. ..1.).contains(&u));
+        assert_eq!(u, hit_random(&hit, 7));
+        assert_ne!(u, hit_random(&hit, 8));
+
+        // the blend converges to the weight
+        let n = 10000;
+        let picked = (0..n)
+            .filter(|&i| {
+                let p = vec3!(i as f64 * 0.01, 0, 0);
+                hit_random(&HitInfo::new(1., vec3!(0, 0, 1), p, vec3!(0, 0, -1)), 1) < 0.3
+            })
+            .count();
+        assert_abs_diff_eq!(picked as f64 / n as f64, 0.3, epsilon = 0.02);
+    }
+
+    #[test]
+    fn test_mix() {
+        let red = LambertianModel::new(1.).with_color((1., 0., 0.));
+        let blue = LambertianModel::new(1.).with_color((0., 0., 1.));
+        let mix = Mix::new(red, blue, 0.3);
+        let world = World::empty();
+        let traced = [vec3!(1, 1, 1)];
+        let n = 10000;
+        let mut blues = 0;
+        for i in 0..n {
+            let p = vec3!(i as f64 * 0.01, 0, 0);
+            let hit = HitInfo::new(1., vec3!(0, 0, 1), p, vec3!(0, 0, -1));
+            // every method picks the same material on a hit
+            let albedo = mix.albedo(&hit);
+            assert_eq!(mix.render(&hit, &world, &traced), albedo);
+            assert_eq!(
+                mix.render_weights(&hit, &world, 1),
+                (vec3!(0, 0, 0), vec![albedo])
+            );
+            if albedo == vec3!(0, 0, 1) {
+                blues += 1;
+            }
+        }
+        assert_abs_diff_eq!(blues as f64 / n as f64, 0.3, epsilon = 0.02);
+
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+        assert_eq!(Mix::new(red, blue, 0.).albedo(&hit), vec3!(1, 0, 0));
+        assert_eq!(Mix::new(red, blue, 1.).albedo(&hit), vec3!(0, 0, 1));
+    }
+
+    #[test]
+    fn test_two_sided() {
+        let red = LambertianModel::new(1.).with_color((1., 0., 0.));
+        let blue = LambertianModel::new(0.5).with_color((0., 0., 1.));
+        let sided = TwoSided::new(red, blue);
+        let world = World::empty();
+        let traced = [vec3!(1, 1, 1)];
+        let front = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+        let back = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, 1));
+        assert!(!front.is_to_outward() && back.is_to_outward());
+        assert_eq!(sided.albedo(&front), vec3!(1, 0, 0));
+        assert_eq!(sided.albedo(&back), vec3!(0, 0, 0.5));
+        assert_eq!(sided.render(&front, &world, &traced), vec3!(1, 0, 0));
+        assert_eq!(sided.render(&back, &world, &traced), vec3!(0, 0, 0.5));
+        // scattered rays leave on the side of the hit
+        for hit in [front, back].iter() {
+            let ray = sided.scatter(hit)[0];
+            assert!(ray.dir().dot(hit.normal()) > 0.);
+        }
+    }
+
+    #[test]
+    fn test_coated() {
+        let coated = Coated::new(LambertianModel::new(0.5)).with_ior(1.5);
+        let front = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+        let back = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, 1));
+        // the coat reflects 4% at normal incidence and is only seen from outside
+        let f = fresnel_dielectric(1., 1.5);
+        assert_abs_diff_eq!(f, 0.04, epsilon = 1e-9);
+        let (_, reflectance, p) = coated.pick_coat(&front);
+        assert_abs_diff_eq!(reflectance, vec3!(f, f, f), epsilon = 1e-9);
+        assert_abs_diff_eq!(p, f, epsilon = 1e-9);
+        assert!(!coated.pick_coat(&back).0);
+
+        // on average the coat reflects `f` and passes the rest on to the base
+        let world = World::empty();
+        let traced = [vec3!(1, 1, 1)];
+        let n = 10000;
+        let mut sum = vec3!(0, 0, 0);
+        for i in 0..n {
+            let p = vec3!(i as f64 * 0.01, 0, 0);
+            let hit = HitInfo::new(1., vec3!(0, 0, 1), p, vec3!(0, 0, -1));
+            let color = coated.render(&hit, &world, &traced);
+            let (light, weights) = coated.render_weights(&hit, &world, 1);
+            assert_abs_diff_eq!(light + weights[0] * traced[0], color, epsilon = 1e-9);
+            sum += color;
+            // the smooth coat is a mirror with the weight of its reflectance over the probability
+            if coated.pick_coat(&hit).0 {
+                let ray = coated.scatter(&hit)[0];
+                assert_abs_diff_eq!(ray.dir(), vec3!(0, 0, 1), epsilon = 1e-9);
+                assert_abs_diff_eq!(ray.weight(), vec3!(1, 1, 1), epsilon = 1e-9);
+            }
+        }
+        let expected = f + (1. - f) * 0.5;
+        assert_abs_diff_eq!(
+            sum / n as f64,
+            vec3!(expected, expected, expected),
+            epsilon = 0.01
+        );
+    }
+
+    #[test]
+    fn test_sheen_albedo() {
+        let velvet = Sheen::new(LambertianModel::new(0.5), (1., 1., 1.)).with_roughness(0.5);
+        for &a in velvet.albedo.iter() {
+            assert!(a > 0. && a <= 1.);
+        }
+        // fibers catch more light at grazing angles
+        assert!(velvet.albedo(0.1) > velvet.albedo(0.9));
+
+        // the lobe is picked by the same tinted reflectance the base loses, also in spectral mode
+        let red = Sheen::new(LambertianModel::new(0.5), (0.8, 0.2, 0.1));
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0.3, 0, -1))
+            .with_wavelengths(Some(vec3!(450, 550, 650)));
+        let color = hit.tint(red.color);
+        let reflected = max!(color.x, max!(color.y, color.z)) * red.albedo(hit.dir_out().z);
+        let (_, rest, p) = red.pick_sheen(&hit);
+        assert_abs_diff_eq!(p, reflected, epsilon = 1e-9);
+        assert_abs_diff_eq!((1. - p) * rest.x, 1. - reflected, epsilon = 1e-9);
+        let anisotropic = red.with_anisotropic_roughness(0.3, 0.9);
+        assert!(anisotropic.albedo(0.5) > 0. && anisotropic.albedo(0.5) <= 1.);
+    }
+
+    #[test]
+    fn test_dielectric() {
+        let entering = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+        let leaving = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, 1));
+        assert_abs_diff_eq!(entering.reflect_prob(1.5), 0.04);
+        assert_abs_diff_eq!(leaving.reflect_prob(1.5), 0.04);
+        // total internal reflection only happens when leaving
+        let dir = vec3!(1, 0, 0.5);
+        let entering = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), -dir);
+        let leaving = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), dir);
+        assert!(entering.reflect_prob(1.5) < 1.);
+        assert_abs_diff_eq!(leaving.reflect_prob(1.5), 1.);
+
+        // thick glass is darker than thin glass
+        let world = World::empty();
+        let glass = Dielectric::new(1.5).with_transmittance((0.5, 0.8, 1.), 1.);
+        let traced = [vec3!(1, 1, 1)];
+        let thin = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, 1));
+        let thick = HitInfo::new(2., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, 1));
+        assert_abs_diff_eq!(glass.render(&thin, &world, &traced), vec3!(0.5, 0.8, 1));
+        assert_abs_diff_eq!(glass.render(&thick, &world, &traced), vec3!(0.25, 0.64, 1));
+        assert_abs_diff_eq!(glass.render(&entering, &world, &traced), vec3!(1, 1, 1));
+
+        // dispersion keeps only the hero wavelength
+        let prism = Dielectric::new(1.5).with_dispersion(Ior::bk7());
+        let lambda = vec3!(450, 600, 750);
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(1, 0, -1))
+            .with_wavelengths(Some(lambda));
+        let ray = prism.scatter(&hit)[0];
+        assert_eq!(ray.wavelengths(), Some(vec3!(450, 450, 450)));
+        assert_abs_diff_eq!(ray.weight(), vec3!(3, 0, 0));
+        let hit = hit.with_wavelengths(ray.wavelengths());
+        assert_abs_diff_eq!(prism.scatter(&hit)[0].weight(), vec3!(1, 1, 1));
     }
 }
@@ -0,0 +1,142 @@
+use rand::Rng;
+
+use crate::{
+    object::World,
+    ray::{HitInfo, Ray},
+    sampling::sample_cosine_hemisphere,
+    util::{luminance, Color, Frame, Vec3, PI},
+};
+
+use super::Material;
+
+/// rough diffuse surface like clay or concrete, which gets brighter towards the light at grazing angles.
+/// see `Generalization of Lambert's Reflectance Model` (Oren and Nayar 1994) for details.
+#[derive(Clone, Copy)]
+pub struct OrenNayar {
+    albedo: f64,
+    color: Color,
+    a: f64,
+    b: f64,
+}
+
+impl OrenNayar {
+    pub fn new(albedo: f64) -> Self {
+        OrenNayar {
+            albedo,
+            color: (1., 1., 1.).into(),
+            a: 1.,
+            b: 0.,
+        }
+    }
+
+    pub fn with_color<T: Into<Color>>(mut self, color: T) -> Self {
+        self.color = color.into();
+        self
+    }
+
+    /// standard deviation of the microfacet slope angle in degree, 0 is lambertian.
+    pub fn with_roughness(mut self, sigma: f64) -> Self {
+        let sigma2 = (sigma / 180. * PI).powi(2);
+        self.a = 1. - sigma2 / (2. * (sigma2 + 0.33));
+        self.b = 0.45 * sigma2 / (sigma2 + 0.09);
+        self
+    }
+
+    // brdf over the lambertian one for local directions `wo` and `wi`
+    fn factor(&self, wo: Vec3, wi: Vec3) -> f64 {
+        let sin_o = max!(1. - wo.z * wo.z, 0.).sqrt();
+        let sin_i = max!(1. - wi.z * wi.z, 0.).sqrt();
+        if sin_o < 1e-4 || sin_i < 1e-4 {
+            return self.a;
+        }
+        let cos_phi = max!((wo.x * wi.x + wo.y * wi.y) / (sin_o * sin_i), 0.);
+        // sin(alpha) * tan(beta), with alpha the larger and beta the smaller polar angle
+        let (sin_alpha, tan_beta) = if wi.z > wo.z {
+            (sin_o, sin_i / wi.z)
+        } else {
+            (sin_i, sin_o / wo.z)
+        };
+        self.a + self.b * cos_phi * sin_alpha * tan_beta
+    }
+}
+
+impl Material for OrenNayar {
+    fn render(&self, hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
+        self.albedo * hit.tint(self.color) * traced.iter().cloned().sum::<Color>()
+    }
+
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let frame = Frame::new(hit.normal());
+        let wo = frame.to_local(-hit.dir_in());
+        let wi = sample_cosine_hemisphere(rand::thread_rng().gen());
+        let f = self.factor(wo, wi);
+        vec![hit.spawn(frame.to_world(wi)).with_weight((f, f, f))]
+    }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.albedo * hit.tint(self.color)
+    }
+}
+
+/// thin diffuse sheet like paper or leaves, which scatters light to both of its sides.
+#[derive(Clone, Copy)]
+pub struct DiffuseTransmission {
+    reflectance: Color,
+    transmittance: Color,
+}
+
+impl DiffuseTransmission {
+    pub fn new<T: Into<Color>>(reflectance: T, transmittance: T) -> Self {
+        DiffuseTransmission {
+            reflectance: reflectance.into(),
+            transmittance: transmittance.into(),
+        }
+    }
+}
+
+impl Material for DiffuseTransmission {
+    fn render(&self, _hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
+        traced.iter().cloned().sum()
+    }
+
+    // pick a side by the brightness of each lobe, both are cosine weighted
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let (r, t) = (luminance(self.reflectance), luminance(self.transmittance));
+        if r + t <= 0. {
+            return vec![];
+        }
+        let mut rng = rand::thread_rng();
+        let mut wi = sample_cosine_hemisphere(rng.gen());
+        let pr = r / (r + t);
+        let weight = if rng.gen::<f64>() < pr {
+            hit.tint(self.reflectance) / pr
+        } else {
+            wi.z = -wi.z;
+            hit.tint(self.transmittance) / (1. - pr)
+        };
+        let frame = Frame::new(hit.normal());
+        vec![hit.spawn(frame.to_world(wi)).with_weight(weight)]
+    }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        hit.tint(self.reflectance)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_oren_nayar() {
+        let wo = vec3!(0.6, 0, 0.8);
+        let wi = vec3!(-0.6, 0.48, 0.64).unit();
+        assert_abs_diff_eq!(OrenNayar::new(1.).factor(wo, wi), 1.);
+
+        let rough = OrenNayar::new(1.).with_roughness(30.);
+        // darker than lambertian at normal view, brighter looking back towards the light
+        assert!(rough.factor(vec3!(0, 0, 1), vec3!(0, 0, 1)) < 1.);
+        let back = vec3!(0.8, 0, 0.6);
+        assert!(rough.factor(wo, back) > rough.factor(wo, wi));
+    }
+}
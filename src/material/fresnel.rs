@@ -0,0 +1,129 @@
+use crate::util::{Color, Vec3, PI};
+
+/// unpolarized fresnel reflectance of a dielectric interface.
+/// `cos_i` is measured on the incident side and `eta` is the transmitted ior over the incident one,
+/// a negative `cos_i` means the light arrives from the transmitted side.
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let mut cos_i = max!(-1., min!(1., cos_i));
+    let mut eta = eta;
+    if cos_i < 0. {
+        eta = 1. / eta;
+        cos_i = -cos_i;
+    }
+    let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1. {
+        // total internal reflection
+        return 1.;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.
+}
+
+// see https://seblagarde.wordpress.com/2013/04/29/memo-on-fresnel-equations/ for details
+fn fresnel_complex(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1. - cos2;
+    let (eta2, k2) = (eta * eta, k * k);
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4. * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = max!(0.5 * (a2_plus_b2 + t0), 0.).sqrt();
+    let t2 = 2. * cos_i * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    (rs + rp) / 2.
+}
+
+/// unpolarized fresnel reflectance per channel of a conductor with complex ior `eta + i*k`, seen from air.
+pub fn fresnel_conductor(cos_i: f64, eta: Color, k: Color) -> Color {
+    let cos_i = max!(0., min!(1., cos_i));
+    vec3!(
+        fresnel_complex(cos_i, eta.x, k.x),
+        fresnel_complex(cos_i, eta.y, k.y),
+        fresnel_complex(cos_i, eta.z, k.z)
+    )
+}
+
+/// reflectance of a thin film of `film_ior` and `thickness` nm over a dielectric of `base_ior`, seen from air,
+/// at the wavelength in nm of each channel of `lambda`.
+/// see `A Practical Extension to Microfacet Theory for the Modeling of Varying Iridescence`
+/// (Belcour and Barla 2017) for details.
+pub fn fresnel_thin_film(
+    cos_i: f64,
+    film_ior: f64,
+    thickness: f64,
+    base_ior: f64,
+    lambda: Vec3,
+) -> Color {
+    let cos1 = max!(0., min!(1., cos_i));
+    let sin2 = 1. - cos1 * cos1;
+    let (n2, n3) = (max!(film_ior, 1.), max!(base_ior, 1.));
+    let cos2 = (1. - sin2 / (n2 * n2)).sqrt();
+    let cos3 = (1. - sin2 / (n3 * n3)).sqrt();
+
+    // amplitude reflection coefficients at the top and bottom of the film
+    let rs12 = (cos1 - n2 * cos2) / (cos1 + n2 * cos2);
+    let rp12 = (n2 * cos1 - cos2) / (n2 * cos1 + cos2);
+    let rs23 = (n2 * cos2 - n3 * cos3) / (n2 * cos2 + n3 * cos3);
+    let rp23 = (n3 * cos2 - n2 * cos3) / (n3 * cos2 + n2 * cos3);
+
+    // airy summation of the waves bouncing inside the film
+    let airy = |r12: f64, r23: f64, cos_delta: f64| {
+        let cross = 2. * r12 * r23 * cos_delta;
+        (r12 * r12 + r23 * r23 + cross) / (1. + r12 * r12 * r23 * r23 + cross)
+    };
+    let at = |l: f64| {
+        let cos_delta = (4. * PI * n2 * thickness * cos2 / l).cos();
+        (airy(rs12, rs23, cos_delta) + airy(rp12, rp23, cos_delta)) / 2.
+    };
+    vec3!(at(lambda.x), at(lambda.y), at(lambda.z))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fresnel() {
+        assert_abs_diff_eq!(fresnel_dielectric(1., 1.5), 0.04);
+        assert_abs_diff_eq!(fresnel_dielectric(-1., 1.5), 0.04);
+        assert_abs_diff_eq!(fresnel_dielectric(0., 1.5), 1.);
+        // total internal reflection beyond the critical angle
+        assert_abs_diff_eq!(fresnel_dielectric(-0.5, 1.5), 1.);
+
+        let (eta, k) = (vec3!(0.2, 1., 1.5), vec3!(3.9, 2.5, 0.));
+        let normal = |e: f64, k: f64| ((e - 1.).powi(2) + k * k) / ((e + 1.).powi(2) + k * k);
+        assert_abs_diff_eq!(
+            fresnel_conductor(1., eta, k),
+            vec3!(normal(0.2, 3.9), normal(1., 2.5), 0.04)
+        );
+        assert_abs_diff_eq!(fresnel_conductor(0., eta, k), vec3!(1, 1, 1));
+        // without absorption a conductor is a dielectric
+        assert_abs_diff_eq!(
+            fresnel_conductor(0.6, eta, k).z,
+            fresnel_dielectric(0.6, 1.5),
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_thin_film() {
+        let lambda = vec3!(650, 550, 450);
+        // a film without thickness is just the base
+        let bare = fresnel_thin_film(0.7, 1.33, 0., 1.5, lambda);
+        let f = fresnel_dielectric(0.7, 1.5);
+        assert_abs_diff_eq!(bare, vec3!(f, f, f), epsilon = 1e-9);
+
+        // a quarter wave anti-reflection coating cancels green only
+        let n = 1.5f64.sqrt();
+        let coated = fresnel_thin_film(1., n, 550. / (4. * n), 1.5, lambda);
+        assert_abs_diff_eq!(coated.y, 0., epsilon = 1e-9);
+        assert!(coated.x > 1e-3 && coated.z > 1e-3);
+    }
+}
@@ -0,0 +1,434 @@
+// GGX / Trowbridge-Reitz microfacet models with smith masking-shadowing
+// see `Sampling the GGX Distribution of Visible Normals` (Heitz 2018) for details
+
+use rand::Rng;
+
+use crate::{
+    object::World,
+    ray::{HitInfo, Ray},
+    spectrum::interpolate_rgb_at,
+    util::{Color, Vec3, PI},
+};
+
+use super::{fresnel_conductor, fresnel_dielectric, Material};
+
+/// anisotropic trowbridge-reitz distribution of microfacet normals around the local +z axis.
+#[derive(Clone, Copy, Debug)]
+pub struct TrowbridgeReitz {
+    alpha_x: f64,
+    alpha_y: f64,
+}
+
+impl TrowbridgeReitz {
+    pub fn new(alpha_x: f64, alpha_y: f64) -> Self {
+        TrowbridgeReitz {
+            alpha_x: max!(alpha_x, 0.),
+            alpha_y: max!(alpha_y, 0.),
+        }
+    }
+
+    /// distribution of a perceptual `roughness` in [0, 1], alpha = roughness^2.
+    pub fn from_roughness(roughness_x: f64, roughness_y: f64) -> Self {
+        Self::new(roughness_x * roughness_x, roughness_y * roughness_y)
+    }
+
+    /// too smooth to be sampled, treat it as a perfect mirror.
+    pub fn is_smooth(&self) -> bool {
+        max!(self.alpha_x, self.alpha_y) < 1e-3
+    }
+
+    /// density of microfacet normal `wm`.
+    pub fn d(&self, wm: Vec3) -> f64 {
+        let (ax, ay) = (self.alpha_x, self.alpha_y);
+        let e = (wm.x / ax).powi(2) + (wm.y / ay).powi(2) + wm.z * wm.z;
+        1. / (PI * ax * ay * e * e)
+    }
+
+    /// smith auxiliary function, the masked microfacet area per visible area towards `w`.
+    pub fn lambda(&self, w: Vec3) -> f64 {
+        if w.z == 0. {
+            return f64::INFINITY;
+        }
+        let alpha2_tan2 =
+            ((w.x * self.alpha_x).powi(2) + (w.y * self.alpha_y).powi(2)) / (w.z * w.z);
+        ((1. + alpha2_tan2).sqrt() - 1.) / 2.
+    }
+
+    /// fraction of microfacets visible from `w`.
+    pub fn g1(&self, w: Vec3) -> f64 {
+        1. / (1. + self.lambda(w))
+    }
+
+    /// fraction of microfacets visible from both `wo` and `wi`.
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1. / (1. + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// density of microfacet normal `wm` among the normals visible from `w`.
+    pub fn pdf(&self, w: Vec3, wm: Vec3) -> f64 {
+        if w.z == 0. {
+            return 0.;
+        }
+        self.g1(w) / w.z.abs() * self.d(wm) * max!(w.dot(wm), 0.)
+    }
+
+    /// sample a microfacet normal visible from `w` with `u` in [0, 1)^2.
+    pub fn sample_wm(&self, w: Vec3, u: (f64, f64)) -> Vec3 {
+        // stretch to the hemisphere configuration
+        let mut wh = vec3!(self.alpha_x * w.x, self.alpha_y * w.y, w.z).unit();
+        if wh.z < 0. {
+            wh = -wh;
+        }
+        let t1 = if wh.z < 0.99999 {
+            vec3!(0, 0, 1).cross(wh).unit()
+        } else {
+            vec3!(1, 0, 0)
+        };
+        let t2 = wh.cross(t1);
+
+        // uniform point on the disk, warped to the projection of the visible hemisphere
+        let (r, phi) = (u.0.sqrt(), 2. * PI * u.1);
+        let (px, py) = (r * phi.cos(), r * phi.sin());
+        let h = (1. - px * px).sqrt();
+        let s = (1. + wh.z) / 2.;
+        let py = (1. - s) * h + s * py;
+        let pz = max!(1. - px * px - py * py, 0.).sqrt();
+        let nh = px * t1 + py * t2 + pz * wh;
+
+        // unstretch
+        vec3!(self.alpha_x * nh.x, self.alpha_y * nh.y, max!(nh.z, 1e-6)).unit()
+    }
+}
+
+/// charlie distribution of fiber normals for velvet and cloth sheen, stretched by `alpha_x`
+/// along the tangent and `alpha_y` along the bitangent.
+/// see `Production Friendly Microfacet Sheen BRDF` (Estevez and Kulla 2017) for details.
+#[derive(Clone, Copy, Debug)]
+pub struct Charlie {
+    alpha_x: f64,
+    alpha_y: f64,
+}
+
+impl Charlie {
+    pub fn new(alpha_x: f64, alpha_y: f64) -> Self {
+        Charlie {
+            alpha_x: max!(alpha_x, 1e-3),
+            alpha_y: max!(alpha_y, 1e-3),
+        }
+    }
+
+    /// distribution of a perceptual `roughness` in [0, 1], alpha = roughness^2.
+    pub fn from_roughness(roughness_x: f64, roughness_y: f64) -> Self {
+        Self::new(roughness_x * roughness_x, roughness_y * roughness_y)
+    }
+
+    /// density of fiber normal `wm`.
+    pub fn d(&self, wm: Vec3) -> f64 {
+        let sin2 = max!(1. - wm.z * wm.z, 0.);
+        // alpha varies with the azimuth of `wm` like an ellipse. the normalization integrates
+        // to one along every azimuth, so the whole distribution stays normalized
+        let alpha = if sin2 > 0. {
+            ((wm.x * self.alpha_x).powi(2) + (wm.y * self.alpha_y).powi(2)) / sin2
+        } else {
+            self.alpha_x * self.alpha_y
+        }
+        .sqrt();
+        (2. + 1. / alpha) * sin2.powf(0.5 / alpha) / (2. * PI)
+    }
+
+    /// sheen brdf between `wo` and `wi` in the upper hemisphere, with ashikhmin's visibility term.
+    pub fn brdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        let wm = (wo + wi).unit();
+        let v = 1. / (4. * (wi.z + wo.z - wi.z * wo.z));
+        self.d(wm) * v
+    }
+}
+
+pub(crate) fn reflect(wo: Vec3, n: Vec3) -> Vec3 {
+    -wo + 2. * wo.dot(n) * n
+}
+
+// `eta` is the ior on the side `n` points away from over the one of `wi`
+pub(crate) fn refract(wi: Vec3, n: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = wi.dot(n);
+    let sin2_t = max!(1. - cos_i * cos_i, 0.) / (eta * eta);
+    if sin2_t >= 1. {
+        return None;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    Some(-wi / eta + (cos_i / eta - cos_t) * n)
+}
+
+/// rough metal with complex ior `eta + i*k` per channel.
+/// in spectral mode both are interpolated between the wavelengths of the channels,
+/// see `spectrum::interpolate_rgb`.
+#[derive(Clone, Copy, Debug)]
+pub struct RoughConductor {
+    eta: Color,
+    k: Color,
+    distribution: TrowbridgeReitz,
+}
+
+impl RoughConductor {
+    pub fn new<T: Into<Color>>(eta: T, k: T) -> Self {
+        RoughConductor {
+            eta: eta.into(),
+            k: k.into(),
+            distribution: TrowbridgeReitz::new(0., 0.),
+        }
+    }
+
+    pub fn gold() -> Self {
+        Self::new((0.143, 0.374, 1.442), (3.983, 2.386, 1.603))
+    }
+
+    pub fn silver() -> Self {
+        Self::new((0.155, 0.117, 0.138), (4.828, 3.122, 2.147))
+    }
+
+    pub fn copper() -> Self {
+        Self::new((0.200, 0.924, 1.102), (3.912, 2.452, 2.142))
+    }
+
+    pub fn aluminium() -> Self {
+        Self::new((1.657, 0.880, 0.521), (9.224, 6.270, 4.837))
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Self {
+        self.distribution = TrowbridgeReitz::from_roughness(roughness, roughness);
+        self
+    }
+
+    /// stretch highlights along the tangent (`roughness_x`) or bitangent (`roughness_y`),
+    /// with the tangent following the `u` surface coordinate of the shape.
+    pub fn with_anisotropic_roughness(mut self, roughness_x: f64, roughness_y: f64) -> Self {
+        self.distribution = TrowbridgeReitz::from_roughness(roughness_x, roughness_y);
+        self
+    }
+
+    // fresnel reflectance at the wavelengths of `hit`
+    fn fresnel(&self, hit: &HitInfo, cos: f64) -> Color {
+        match hit.wavelengths() {
+            Some(lambda) => fresnel_conductor(
+                cos,
+                interpolate_rgb_at(self.eta, lambda),
+                interpolate_rgb_at(self.k, lambda),
+            ),
+            None => fresnel_conductor(cos, self.eta, self.k),
+        }
+    }
+}
+
+impl Material for RoughConductor {
+    fn render(&self, _hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
+        traced
+            .first()
+            .cloned()
+            .unwrap_or_else(|| (0., 0., 0.).into())
+    }
+
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let frame = hit.frame();
+        let wo = frame.to_local(-hit.dir_in());
+        if wo.z <= 0. {
+            return vec![];
+        }
+        if self.distribution.is_smooth() {
+            let weight = self.fresnel(hit, wo.z);
+            return vec![hit.reflect().with_weight(weight)];
+        }
+
+        let mut rng = rand::thread_rng();
+        let wm = self.distribution.sample_wm(wo, rng.gen());
+        let wi = reflect(wo, wm);
+        if wi.z <= 0. {
+            return vec![];
+        }
+        // brdf * cos / pdf with the visible normal density
+        let d = &self.distribution;
+        let weight = self.fresnel(hit, wo.dot(wm)) * d.g(wo, wi) / d.g1(wo);
+        vec![hit.spawn(frame.to_world(wi)).with_weight(weight)]
+    }
+}
+
+/// rough glass-like interface which both reflects and transmits.
+#[derive(Clone, Copy, Debug)]
+pub struct RoughDielectric {
+    ior: f64,
+    distribution: TrowbridgeReitz,
+}
+
+impl RoughDielectric {
+    pub fn new(ior: f64) -> Self {
+        RoughDielectric {
+            ior,
+            distribution: TrowbridgeReitz::new(0., 0.),
+        }
+    }
+
+    pub fn with_ior(mut self, ior: f64) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Self {
+        self.distribution = TrowbridgeReitz::from_roughness(roughness, roughness);
+        self
+    }
+
+    /// stretch highlights along the tangent (`roughness_x`) or bitangent (`roughness_y`),
+    /// with the tangent following the `u` surface coordinate of the shape.
+    pub fn with_anisotropic_roughness(mut self, roughness_x: f64, roughness_y: f64) -> Self {
+        self.distribution = TrowbridgeReitz::from_roughness(roughness_x, roughness_y);
+        self
+    }
+
+    pub fn ior(&self) -> f64 {
+        self.ior
+    }
+}
+
+impl Material for RoughDielectric {
+    fn render(&self, _hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
+        traced
+            .first()
+            .cloned()
+            .unwrap_or_else(|| (0., 0., 0.).into())
+    }
+
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let frame = hit.frame();
+        let wo = frame.to_local(-hit.dir_in());
+        if wo.z <= 0. {
+            return vec![];
+        }
+        // ior beyond the surface over the one on the incoming side
+        let eta = if hit.is_to_outward() {
+            1. / self.ior
+        } else {
+            self.ior
+        };
+
+        let mut rng = rand::thread_rng();
+        let d = &self.distribution;
+        let (wm, masking) = if d.is_smooth() {
+            (vec3!(0, 0, 1), 1.)
+        } else {
+            let wm = d.sample_wm(wo, rng.gen());
+            (wm, 1. / d.g1(wo))
+        };
+
+        // choose reflection or transmission by fresnel, which cancels out of the weight
+        let f = fresnel_dielectric(wo.dot(wm), eta);
+        let (wi, scale) = if rng.gen::<f64>() < f {
+            let wi = reflect(wo, wm);
+            if wi.z <= 0. {
+                return vec![];
+            }
+            (wi, 1.)
+        } else {
+            match refract(wo, wm, eta) {
+                // radiance is compressed into the smaller solid angle of the denser side
+                Some(wi) if wi.z < 0. => (wi, 1. / (eta * eta)),
+                _ => return vec![],
+            }
+        };
+        let g = if d.is_smooth() { 1. } else { d.g(wo, wi) };
+        let weight = g * masking * scale;
+        vec![hit
+            .spawn(frame.to_world(wi))
+            .with_weight((weight, weight, weight))]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // integrate `f` over the sphere with the midpoint rule
+    fn integrate<F: Fn(Vec3) -> f64>(f: F) -> f64 {
+        let (nt, np) = (400, 200);
+        let (dt, dp) = (PI / nt as f64, 2. * PI / np as f64);
+        let mut sum = 0.;
+        for i in 0..nt {
+            let theta = (i as f64 + 0.5) * dt;
+            for j in 0..np {
+                let phi = (j as f64 + 0.5) * dp;
+                let w = vec3!(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos()
+                );
+                sum += f(w) * theta.sin() * dt * dp;
+            }
+        }
+        sum
+    }
+
+    #[test]
+    fn test_trowbridge_reitz() {
+        let d = TrowbridgeReitz::new(0.3, 0.6);
+        let positive = |w: Vec3, v: f64| if w.z > 0. { v } else { 0. };
+        // projected microfacet area is the macro surface
+        let area = integrate(|wm| positive(wm, d.d(wm) * wm.z));
+        assert_abs_diff_eq!(area, 1., epsilon = 1e-2);
+        // visible normals are a distribution for every direction
+        let wo = vec3!(0.5, -0.3, 0.4).unit();
+        let visible = integrate(|wm| positive(wm, d.pdf(wo, wm)));
+        assert_abs_diff_eq!(visible, 1., epsilon = 1e-2);
+
+        for i in 0..10 {
+            for j in 0..10 {
+                let u = (i as f64 / 10., j as f64 / 10.);
+                let wm = d.sample_wm(wo, u);
+                assert_abs_diff_eq!(wm.len(), 1., epsilon = 1e-9);
+                assert!(wm.z > 0. && wm.dot(wo) >= -1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_charlie() {
+        let positive = |w: Vec3, v: f64| if w.z > 0. { v } else { 0. };
+        for &(ax, ay) in [(0.3, 0.3), (0.3, 0.6), (0.8, 0.1)].iter() {
+            let d = Charlie::new(ax, ay);
+            let area = integrate(|wm| positive(wm, d.d(wm) * wm.z));
+            assert_abs_diff_eq!(area, 1., epsilon = 1e-2);
+        }
+        // the smoother direction gathers more fiber normals near the surface
+        let d = Charlie::from_roughness(0.4, 0.8);
+        let (along_x, along_y) = (vec3!(0.9, 0, 0.436), vec3!(0, 0.9, 0.436));
+        assert!(d.d(along_x) > d.d(along_y));
+        let d = Charlie::new(0.3, 0.3);
+        assert_abs_diff_eq!(d.d(along_x), d.d(along_y), epsilon = 1e-12);
+    }
+
+    #[test]
+    fn test_conductor_spectral() {
+        // spectral gold at the channel wavelengths reflects like rgb gold, which is yellow
+        let gold = RoughConductor::gold();
+        let hit = HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), vec3!(0, 0, -1));
+        let rgb = gold.scatter(&hit)[0].weight();
+        assert!(rgb.x > rgb.y && rgb.y > rgb.z);
+        let spectral = hit.with_wavelengths(Some(crate::spectrum::RGB_WAVELENGTHS));
+        assert_abs_diff_eq!(gold.scatter(&spectral)[0].weight(), rgb, epsilon = 1e-9);
+        // beyond the red channel the ior keeps its red values, which exceed 1
+        let red = hit.with_wavelengths(Some(vec3!(700, 700, 700)));
+        assert_abs_diff_eq!(
+            gold.fresnel(&red, 1.),
+            gold.fresnel(&hit, 1.).x * vec3!(1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_refract() {
+        let n = vec3!(0, 0, 1);
+        let wi = vec3!(0.6, 0, 0.8);
+        let wt = refract(wi, n, 1.5).unwrap();
+        assert_abs_diff_eq!(wt.len(), 1., epsilon = 1e-9);
+        // snell's law
+        assert_abs_diff_eq!(-wt.x * 1.5, 0.6, epsilon = 1e-9);
+        assert!(refract(vec3!(0.8, 0, 0.6), n, 1. / 1.5).is_none());
+        assert_abs_diff_eq!(reflect(wi, n), vec3!(-0.6, 0, 0.8));
+    }
+}
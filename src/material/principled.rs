@@ -0,0 +1,474 @@
+// layered physically based material in the spirit of
+// `Physically Based Shading at Disney` (Burley 2012) and the glTF 2.0 metallic-roughness model
+// see https://github.com/KhronosGroup/glTF/tree/main/specification/2.0#appendix-b-brdf-implementation
+
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::{
+    object::World,
+    ray::{HitInfo, Ray},
+    sampling::sample_cosine_hemisphere,
+    texture::{Channel, Scaled, Texture},
+    util::{luminance, Color, Vec3, PI},
+};
+
+use super::{
+    fresnel_dielectric,
+    microfacet::{reflect, refract},
+    Charlie, Material, TrowbridgeReitz,
+};
+
+/// ior of the clearcoat layer
+const CLEARCOAT_IOR: f64 = 1.5;
+
+/// principled bsdf layering a clearcoat over metallic specular, diffuse with sheen, and transmission.
+/// every parameter is a texture, scalar ones read the red channel.
+///
+/// glTF materials map one-to-one:
+/// `baseColor` to `base_color`, `metallic` and `roughness` (see `with_metallic_roughness_texture`),
+/// `emissive` to `emission`, and the `KHR_materials_*` extensions `specular`, `ior`, `transmission`,
+/// `clearcoat` and `sheen` to their namesakes.
+#[derive(Clone)]
+pub struct Principled {
+    base_color: Arc<dyn Texture>,
+    metallic: Arc<dyn Texture>,
+    roughness: Arc<dyn Texture>,
+    specular: Arc<dyn Texture>,
+    ior: f64,
+    transmission: Arc<dyn Texture>,
+    subsurface: Arc<dyn Texture>,
+    clearcoat: Arc<dyn Texture>,
+    clearcoat_roughness: Arc<dyn Texture>,
+    sheen: Arc<dyn Texture>,
+    sheen_roughness: Arc<dyn Texture>,
+    emission: Arc<dyn Texture>,
+}
+
+// parameters evaluated at a hit
+struct Params {
+    base_color: Color,
+    metallic: f64,
+    roughness: f64,
+    specular: f64,
+    transmission: f64,
+    subsurface: f64,
+    clearcoat: f64,
+    clearcoat_roughness: f64,
+    sheen: Color,
+    sheen_roughness: f64,
+}
+
+fn clamp01(x: f64) -> f64 {
+    max!(0., min!(1., x))
+}
+
+fn schlick(f0: Color, cos: f64) -> Color {
+    let t = (1. - clamp01(cos)).powi(5);
+    f0 + (Color::new(1., 1., 1.) - f0) * t
+}
+
+// lambertian blended with the hanrahan-krueger like subsurface approximation of burley
+fn diffuse_term(subsurface: f64, roughness: f64, wo: Vec3, wi: Vec3) -> f64 {
+    if subsurface <= 0. {
+        return 1.;
+    }
+    let cos_d = wi.dot((wo + wi).unit());
+    let fss90 = cos_d * cos_d * roughness;
+    let (fl, fv) = ((1. - wi.z).powi(5), (1. - wo.z).powi(5));
+    let fss = (1. + (fss90 - 1.) * fl) * (1. + (fss90 - 1.) * fv);
+    let ss = 1.25 * (fss * (1. / (wi.z + wo.z) - 0.5) + 0.5);
+    1. + (ss - 1.) * subsurface
+}
+
+impl Principled {
+    pub fn new() -> Self {
+        Principled {
+            base_color: Arc::new(Color::new(0.8, 0.8, 0.8)),
+            metallic: Arc::new(0.),
+            roughness: Arc::new(0.5),
+            specular: Arc::new(1.),
+            ior: 1.5,
+            transmission: Arc::new(0.),
+            subsurface: Arc::new(0.),
+            clearcoat: Arc::new(0.),
+            clearcoat_roughness: Arc::new(0.),
+            sheen: Arc::new(Color::new(0., 0., 0.)),
+            sheen_roughness: Arc::new(0.),
+            emission: Arc::new(Color::new(0., 0., 0.)),
+        }
+    }
+
+    pub fn with_base_color<T: Texture + 'static>(mut self, base_color: T) -> Self {
+        self.base_color = Arc::new(base_color);
+        self
+    }
+
+    pub fn with_metallic<T: Texture + 'static>(mut self, metallic: T) -> Self {
+        self.metallic = Arc::new(metallic);
+        self
+    }
+
+    /// perceptual roughness, the microfacet alpha is its square.
+    pub fn with_roughness<T: Texture + 'static>(mut self, roughness: T) -> Self {
+        self.roughness = Arc::new(roughness);
+        self
+    }
+
+    /// glTF `metallicRoughnessTexture`, with metallic in blue and roughness in green, scaled by the factors.
+    pub fn with_metallic_roughness_texture<T: Texture + 'static>(
+        self,
+        texture: T,
+        metallic: f64,
+        roughness: f64,
+    ) -> Self {
+        let texture = Arc::new(texture);
+        self.with_metallic(Scaled::new(
+            Channel::new(texture.clone(), 2),
+            (metallic, metallic, metallic),
+        ))
+        .with_roughness(Scaled::new(
+            Channel::new(texture, 1),
+            (roughness, roughness, roughness),
+        ))
+    }
+
+    /// strength of the dielectric specular reflection, 1 is physically correct for `ior`.
+    pub fn with_specular<T: Texture + 'static>(mut self, specular: T) -> Self {
+        self.specular = Arc::new(specular);
+        self
+    }
+
+    pub fn with_ior(mut self, ior: f64) -> Self {
+        self.ior = ior;
+        self
+    }
+
+    /// fraction of the dielectric base which refracts instead of being diffuse.
+    pub fn with_transmission<T: Texture + 'static>(mut self, transmission: T) -> Self {
+        self.transmission = Arc::new(transmission);
+        self
+    }
+
+    /// flatten the diffuse lobe towards the look of subsurface scattering.
+    pub fn with_subsurface<T: Texture + 'static>(mut self, subsurface: T) -> Self {
+        self.subsurface = Arc::new(subsurface);
+        self
+    }
+
+    pub fn with_clearcoat<T: Texture + 'static>(mut self, clearcoat: T) -> Self {
+        self.clearcoat = Arc::new(clearcoat);
+        self
+    }
+
+    pub fn with_clearcoat_roughness<T: Texture + 'static>(mut self, roughness: T) -> Self {
+        self.clearcoat_roughness = Arc::new(roughness);
+        self
+    }
+
+    /// color of the retro-reflective sheen, black for none.
+    pub fn with_sheen<T: Texture + 'static>(mut self, sheen: T) -> Self {
+        self.sheen = Arc::new(sheen);
+        self
+    }
+
+    pub fn with_sheen_roughness<T: Texture + 'static>(mut self, roughness: T) -> Self {
+        self.sheen_roughness = Arc::new(roughness);
+        self
+    }
+
+    pub fn with_emission<T: Texture + 'static>(mut self, emission: T) -> Self {
+        self.emission = Arc::new(emission);
+        self
+    }
+
+    fn params(&self, hit: &HitInfo) -> Params {
+        let scalar = |t: &Arc<dyn Texture>| clamp01(hit.texture(t).x);
+        Params {
+            base_color: hit.tint(hit.texture(&self.base_color)),
+            metallic: scalar(&self.metallic),
+            roughness: scalar(&self.roughness),
+            specular: scalar(&self.specular),
+            transmission: scalar(&self.transmission),
+            subsurface: scalar(&self.subsurface),
+            clearcoat: scalar(&self.clearcoat),
+            clearcoat_roughness: scalar(&self.clearcoat_roughness),
+            sheen: hit.tint(hit.texture(&self.sheen)),
+            sheen_roughness: scalar(&self.sheen_roughness),
+        }
+    }
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Material for Principled {
+    fn render(&self, hit: &HitInfo, _world: &World, traced: &[Color]) -> Color {
+        self.emission(hit) + traced.iter().cloned().sum::<Color>()
+    }
+
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.params(hit).base_color
+    }
+
+    fn emission(&self, hit: &HitInfo) -> Color {
+        if hit.is_to_outward() {
+            (0., 0., 0.).into()
+        } else {
+            hit.tint(hit.texture(&self.emission))
+        }
+    }
+
+    // pick one lobe by its estimated albedo and return its sample weighted by the pick probability
+    fn scatter(&self, hit: &HitInfo) -> Vec<Ray> {
+        let frame = hit.frame();
+        let wo = frame.to_local(-hit.dir_in());
+        if wo.z <= 0. {
+            return vec![];
+        }
+        let p = self.params(hit);
+        // only specular lobes are seen from inside a transmissive body
+        let inside = hit.is_to_outward();
+        let eta = if inside { 1. / self.ior } else { self.ior };
+        let white = Color::new(1., 1., 1.);
+
+        let specular_f = |cos: f64| {
+            p.metallic * schlick(p.base_color, cos)
+                + (1. - p.metallic) * p.specular * fresnel_dielectric(cos, eta) * white
+        };
+        let coat = if inside {
+            0.
+        } else {
+            p.clearcoat * fresnel_dielectric(wo.z, CLEARCOAT_IOR)
+        };
+        let dielectric = (1. - p.metallic) * (1. - p.specular * fresnel_dielectric(wo.z, eta));
+
+        let w_specular = luminance(specular_f(wo.z));
+        let w_diffuse = if inside {
+            0.
+        } else {
+            dielectric * (1. - p.transmission) * luminance(p.base_color) + luminance(p.sheen)
+        };
+        let w_transmission = dielectric * p.transmission * luminance(p.base_color);
+        let total = w_specular + w_diffuse + w_transmission;
+        if total <= 0. && coat <= 0. {
+            return vec![];
+        }
+        let base = if total > 0. { 1. - coat } else { 0. };
+        let probs = [
+            1. - base,
+            base * w_specular / total,
+            base * w_diffuse / total,
+            base * w_transmission / total,
+        ];
+
+        let mut rng = rand::thread_rng();
+        let mut pick = rng.gen::<f64>();
+        let mut lobe = probs.len() - 1;
+        for (i, &prob) in probs.iter().enumerate() {
+            if pick < prob {
+                lobe = i;
+                break;
+            }
+            pick -= prob;
+        }
+        if probs[lobe] <= 0. {
+            return vec![];
+        }
+
+        let sample_wm = |roughness: f64| {
+            let d = TrowbridgeReitz::from_roughness(roughness, roughness);
+            if d.is_smooth() {
+                (vec3!(0, 0, 1), None)
+            } else {
+                (d.sample_wm(wo, rand::thread_rng().gen()), Some(d))
+            }
+        };
+        // smith masking of the sampled direction over the one of the visible normal density
+        let masking =
+            |d: Option<TrowbridgeReitz>, wi: Vec3| d.map_or(1., |d| d.g(wo, wi) / d.g1(wo));
+
+        let (wi, weight) = match lobe {
+            0 => {
+                let (wm, d) = sample_wm(p.clearcoat_roughness);
+                let wi = reflect(wo, wm);
+                if wi.z <= 0. {
+                    return vec![];
+                }
+                let f = p.clearcoat * fresnel_dielectric(wo.dot(wm), CLEARCOAT_IOR);
+                (wi, f * masking(d, wi) * white)
+            }
+            1 => {
+                let (wm, d) = sample_wm(p.roughness);
+                let wi = reflect(wo, wm);
+                if wi.z <= 0. {
+                    return vec![];
+                }
+                (wi, base * masking(d, wi) * specular_f(wo.dot(wm)))
+            }
+            2 => {
+                let wi = sample_cosine_hemisphere(rng.gen());
+                if wi.z <= 0. {
+                    return vec![];
+                }
+                // brdf * cos / pdf of cosine sampling is brdf * pi
+                let diffuse = dielectric
+                    * (1. - p.transmission)
+                    * diffuse_term(p.subsurface, p.roughness, wo, wi)
+                    * p.base_color;
+                let r = p.sheen_roughness;
+                let sheen = PI * Charlie::from_roughness(r, r).brdf(wo, wi) * p.sheen;
+                (wi, base * (diffuse + sheen))
+            }
+            _ => {
+                let (wm, d) = sample_wm(p.roughness);
+                let wi = match refract(wo, wm, eta) {
+                    Some(wi) if wi.z < 0. => wi,
+                    _ => return vec![],
+                };
+                let t = (1. - p.metallic)
+                    * p.transmission
+                    * (1. - p.specular * fresnel_dielectric(wo.dot(wm), eta))
+                    / (eta * eta);
+                (wi, base * t * masking(d, wi) * p.base_color)
+            }
+        };
+        vec![hit
+            .spawn(frame.to_world(wi))
+            .with_weight(weight / probs[lobe])]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_principled_lobes() {
+        let wo = vec3!(0.3, 0.2, 0.9).unit();
+        let wi = vec3!(-0.5, 0.1, 0.7).unit();
+        assert_abs_diff_eq!(diffuse_term(0., 0.5, wo, wi), 1.);
+        // sheen is brightest at grazing angles
+        let grazing = vec3!(0.99, 0., 0.141).unit();
+        let sheen = Charlie::from_roughness(0.5, 0.5);
+        assert!(sheen.brdf(grazing, wi) > sheen.brdf(wo, wi));
+        assert_abs_diff_eq!(schlick(Color::new(0.04, 1., 0.), 1.), vec3!(0.04, 1, 0));
+        assert_abs_diff_eq!(schlick(Color::new(0.04, 1., 0.), 0.), vec3!(1, 1, 1));
+    }
+
+    // reflected part of the bsdf times the cosine, following the layering of `scatter`
+    fn reflected(m: &Principled, hit: &HitInfo, wo: Vec3, wi: Vec3) -> Color {
+        let p = m.params(hit);
+        let wm = (wo + wi).unit();
+        let microfacet = |r: f64| {
+            let d = TrowbridgeReitz::from_roughness(r, r);
+            d.d(wm) * d.g(wo, wi) / (4. * wo.z)
+        };
+        let white = Color::new(1., 1., 1.);
+        let coat = p.clearcoat * fresnel_dielectric(wo.z, CLEARCOAT_IOR);
+        let dielectric = (1. - p.metallic) * (1. - p.specular * fresnel_dielectric(wo.z, m.ior));
+        // a smooth coat is a delta, and only the tests with a rough one have a clearcoat
+        let clearcoat = if p.clearcoat > 0. {
+            p.clearcoat
+                * fresnel_dielectric(wo.dot(wm), CLEARCOAT_IOR)
+                * microfacet(p.clearcoat_roughness)
+                * white
+        } else {
+            Color::new(0., 0., 0.)
+        };
+        let specular = (p.metallic * schlick(p.base_color, wo.dot(wm))
+            + (1. - p.metallic) * p.specular * fresnel_dielectric(wo.dot(wm), m.ior) * white)
+            * microfacet(p.roughness);
+        let diffuse = dielectric
+            * (1. - p.transmission)
+            * diffuse_term(p.subsurface, p.roughness, wo, wi)
+            * wi.z
+            / PI
+            * p.base_color;
+        let r = p.sheen_roughness;
+        let sheen = Charlie::from_roughness(r, r).brdf(wo, wi) * wi.z * p.sheen;
+        clearcoat + (1. - coat) * (specular + diffuse + sheen)
+    }
+
+    #[test]
+    fn test_principled_estimator() {
+        let wo = vec3!(0.3, 0.2, 0.9).unit();
+        // a tangent rotates the shading frame around the normal
+        let hit =
+            HitInfo::new(1., vec3!(0, 0, 1), vec3!(0, 0, 0), -wo).with_tangent(vec3!(1, 1, 0));
+        let frame = hit.frame();
+        let wo = frame.to_local(wo);
+        let materials = [
+            Principled::new().with_base_color(Color::new(0.8, 0.5, 0.2)),
+            Principled::new()
+                .with_base_color(Color::new(0.9, 0.6, 0.3))
+                .with_metallic(1.)
+                .with_roughness(0.6),
+            Principled::new()
+                .with_metallic(0.5)
+                .with_roughness(0.7)
+                .with_transmission(0.5)
+                .with_clearcoat(0.5)
+                .with_clearcoat_roughness(0.5),
+            Principled::new()
+                .with_base_color(Color::new(0.3, 0.7, 0.5))
+                .with_transmission(1.)
+                .with_subsurface(0.5)
+                .with_sheen(Color::new(0.4, 0.4, 0.4))
+                .with_sheen_roughness(0.5),
+        ];
+        let n = 200_000;
+        for m in materials.iter() {
+            // sampled weights over the reflected hemisphere against the integrated bsdf
+            let mut estimate = Color::new(0., 0., 0.);
+            for _ in 0..n {
+                for ray in m.scatter(&hit) {
+                    if frame.to_local(ray.dir()).z > 0. {
+                        estimate += ray.weight() / n as f64;
+                    }
+                }
+            }
+            let (nt, np) = (200, 200);
+            let (dt, dp) = (PI / 2. / nt as f64, 2. * PI / np as f64);
+            let mut expected = Color::new(0., 0., 0.);
+            for i in 0..nt {
+                let theta = (i as f64 + 0.5) * dt;
+                for j in 0..np {
+                    let phi = (j as f64 + 0.5) * dp;
+                    let wi = vec3!(
+                        theta.sin() * phi.cos(),
+                        theta.sin() * phi.sin(),
+                        theta.cos()
+                    );
+                    expected += reflected(m, &hit, wo, wi) * theta.sin() * dt * dp;
+                }
+            }
+            assert_abs_diff_eq!(estimate, expected, epsilon = 1e-2);
+        }
+
+        // a smooth glass splits into the fresnel reflection and the refraction
+        let glass = Principled::new()
+            .with_base_color(Color::new(1., 1., 1.))
+            .with_roughness(0.)
+            .with_transmission(1.);
+        let f = fresnel_dielectric(wo.z, 1.5);
+        let (mut reflection, mut transmission) = (Color::new(0., 0., 0.), Color::new(0., 0., 0.));
+        let n = 20_000;
+        for _ in 0..n {
+            for ray in glass.scatter(&hit) {
+                if frame.to_local(ray.dir()).z > 0. {
+                    reflection += ray.weight() / n as f64;
+                } else {
+                    transmission += ray.weight() / n as f64;
+                }
+            }
+        }
+        assert_abs_diff_eq!(reflection, vec3!(f, f, f), epsilon = 1e-2);
+        let t = (1. - f) / (1.5 * 1.5);
+        assert_abs_diff_eq!(transmission, vec3!(t, t, t), epsilon = 1e-2);
+    }
+}
@@ -0,0 +1,377 @@
+// participating media like fog, smoke and the inside of wax, see
+// `Monte Carlo Methods for Volumetric Light Transport Simulation` (Novák et al. 2018) for details.
+// media are traced with null collisions against a majorant, so homogeneous and varying
+// densities share the same distance sampling and transmittance estimation.
+
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::{
+    object::Shape,
+    ray::Ray,
+    util::{Color, Frame, Vec3, EPS, PI},
+};
+
+pub use self::grid::*;
+
+mod grid;
+
+pub trait Medium: Sync + Send {
+    /// absorption and scattering coefficients per unit length at `p`.
+    fn coefficients(&self, p: Vec3) -> (Color, Color);
+    /// upper bound of every channel of the extinction coefficient over the medium.
+    fn majorant(&self) -> f64;
+    fn phase(&self) -> HenyeyGreenstein;
+
+    /// radiance emitted per unit length at `p`, e.g. by fire.
+    fn emission(&self, _p: Vec3) -> Color {
+        (0., 0., 0.).into()
+    }
+}
+
+/// phase function with a single parameter `g`, the mean cosine of the scattering angle.
+/// positive `g` scatters forward, negative backward and 0 evenly in all directions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HenyeyGreenstein {
+    g: f64,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f64) -> Self {
+        HenyeyGreenstein {
+            g: max!(-0.99, min!(0.99, g)),
+        }
+    }
+
+    pub fn g(&self) -> f64 {
+        self.g
+    }
+
+    /// density over the sphere for the angle between the incoming and outgoing travel directions.
+    pub fn p(&self, cos_theta: f64) -> f64 {
+        let g = self.g;
+        let denom = 1. + g * g - 2. * g * cos_theta;
+        (1. - g * g) / (4. * PI * denom * denom.sqrt())
+    }
+
+    /// draw an outgoing travel direction for light travelling along `dir`, with pdf `p`.
+    pub fn sample(&self, dir: Vec3, u: (f64, f64)) -> Vec3 {
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1. - 2. * u.0
+        } else {
+            let s = (1. - g * g) / (1. - g + 2. * g * u.0);
+            (1. + g * g - s * s) / (2. * g)
+        };
+        let cos_theta = max!(-1., min!(1., cos_theta));
+        let sin_theta = max!(0., 1. - cos_theta * cos_theta).sqrt();
+        let phi = 2. * PI * u.1;
+        let local = vec3!(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        Frame::new(dir.unit()).to_world(local)
+    }
+}
+
+/// medium with the same coefficients everywhere.
+#[derive(Debug, Clone, Copy)]
+pub struct HomogeneousMedium {
+    sigma_a: Color,
+    sigma_s: Color,
+    phase: HenyeyGreenstein,
+}
+
+impl HomogeneousMedium {
+    pub fn new<T: Into<Color>>(sigma_a: T, sigma_s: T) -> Self {
+        HomogeneousMedium {
+            sigma_a: sigma_a.into(),
+            sigma_s: sigma_s.into(),
+            phase: HenyeyGreenstein::new(0.),
+        }
+    }
+
+    /// mean cosine of the scattering angle, see `HenyeyGreenstein`.
+    pub fn with_asymmetry(mut self, g: f64) -> Self {
+        self.phase = HenyeyGreenstein::new(g);
+        self
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn coefficients(&self, _p: Vec3) -> (Color, Color) {
+        (self.sigma_a, self.sigma_s)
+    }
+
+    fn majorant(&self) -> f64 {
+        let t = self.sigma_a + self.sigma_s;
+        max!(t.x, t.y, t.z)
+    }
+
+    fn phase(&self) -> HenyeyGreenstein {
+        self.phase
+    }
+}
+
+/// a medium filling the inside of a closed shape, or the whole scene without a shape.
+pub struct Volume {
+    shape: Option<Box<dyn Shape>>,
+    medium: Arc<dyn Medium>,
+}
+
+// at most this many boundary crossings of one volume are found along a ray
+const MAX_CROSSINGS: usize = 16;
+
+impl Volume {
+    pub fn new<S, M>(shape: S, medium: M) -> Self
+    where
+        S: Shape + 'static,
+        M: Medium + 'static,
+    {
+        Volume {
+            shape: Some(Box::new(shape)),
+            medium: Arc::new(medium),
+        }
+    }
+
+    /// medium everywhere in the scene, like fog.
+    pub fn unbounded<M: Medium + 'static>(medium: M) -> Self {
+        Volume {
+            shape: None,
+            medium: Arc::new(medium),
+        }
+    }
+
+    pub fn medium(&self) -> &dyn Medium {
+        self.medium.as_ref()
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.shape.is_some()
+    }
+
+    // parts of `ray` within `t_max` inside this volume,
+    // telling inside from outside by the parity of boundary crossings
+    fn segments(&self, ray: &Ray, t_max: f64) -> Vec<(f64, f64)> {
+        let shape = match &self.shape {
+            Some(shape) => shape,
+            None => return vec![(0., t_max)],
+        };
+        let mut crossings = Vec::new();
+        let mut t = 0.;
+        while crossings.len() < MAX_CROSSINGS {
+            let next = Ray::new(ray.at(t), ray.dir());
+            match shape.hit_info(&next) {
+                Some(info) => {
+                    t += info.distance();
+                    crossings.push(t);
+                    t += EPS;
+                }
+                None => break,
+            }
+        }
+        if crossings.len() % 2 == 1 {
+            crossings.insert(0, 0.);
+        }
+        crossings
+            .chunks(2)
+            .filter(|c| c.len() == 2 && c[0] < t_max)
+            .map(|c| (c[0], min!(c[1], t_max)))
+            .collect()
+    }
+}
+
+/// outcome of sampling the media along a ray, see `sample_collision`.
+pub enum Collision {
+    /// scattered at `pos` by a medium with `phase`, the path throughput is scaled by `weight`
+    Scattered {
+        pos: Vec3,
+        phase: HenyeyGreenstein,
+        weight: Color,
+    },
+    Absorbed,
+    /// reached the end of the ray, the path throughput is scaled by `weight`
+    Passed(Color),
+}
+
+// null collision tracking along `ray` through `volumes` up to `t_max`.
+// `visit` gets each tentative collision with its volume, and returns whether to stop
+fn track<F>(volumes: &[Volume], ray: &Ray, t_max: f64, mut visit: F)
+where
+    F: FnMut(f64, &Volume) -> bool,
+{
+    let segments: Vec<_> = volumes
+        .iter()
+        .filter(|v| v.medium.majorant() > 0.)
+        .flat_map(|v| v.segments(ray, t_max).into_iter().map(move |s| (s, v)))
+        .collect();
+    if segments.is_empty() {
+        return;
+    }
+    let mut rng = rand::thread_rng();
+    let mut t = 0.;
+    loop {
+        // media add up, so the nearest collision among all of them comes first.
+        // free flights are memoryless and can be drawn again after every collision
+        let next = segments
+            .iter()
+            .filter_map(|&((t0, t1), v)| {
+                let start = max!(t0, t);
+                let u: f64 = rng.gen();
+                let c = start - (1. - u).ln() / v.medium.majorant();
+                if start < t1 && c < t1 {
+                    Some((c, v))
+                } else {
+                    None
+                }
+            })
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        match next {
+            Some((c, v)) => {
+                t = c;
+                if visit(t, v) {
+                    return;
+                }
+            }
+            None => return,
+        }
+    }
+}
+
+fn mean(c: Color) -> f64 {
+    (c.x + c.y + c.z) / 3.
+}
+
+/// sample where `ray` first interacts with the media in `volumes` before `t_max`,
+/// along with the radiance emitted by them on the way there.
+/// coefficients vary by channel, so each event is picked by its mean and weighted per channel.
+pub fn sample_collision(volumes: &[Volume], ray: &Ray, t_max: f64) -> (Collision, Color) {
+    let mut rng = rand::thread_rng();
+    let mut weight: Color = (1., 1., 1.).into();
+    let mut emitted: Color = (0., 0., 0.).into();
+    let mut result = None;
+    track(volumes, ray, t_max, |t, v| {
+        let pos = ray.at(t);
+        let (sigma_a, sigma_s) = v.medium.coefficients(pos);
+        let (sigma_a, sigma_s) = (ray.tint(sigma_a), ray.tint(sigma_s));
+        let majorant = v.medium.majorant();
+        // every tentative collision estimates the emission, see pbrt-v4 `VolPathIntegrator`
+        emitted += weight * ray.tint(v.medium.emission(pos)) / majorant;
+        let sigma_n = Color::new(majorant, majorant, majorant) - sigma_a - sigma_s;
+        let p_a = max!(mean(sigma_a), 0.) / majorant;
+        let p_s = max!(mean(sigma_s), 0.) / majorant;
+        let u: f64 = rng.gen();
+        if u < p_a {
+            result = Some(Collision::Absorbed);
+            true
+        } else if u < p_a + p_s {
+            weight = weight * sigma_s / (majorant * p_s);
+            result = Some(Collision::Scattered {
+                pos,
+                phase: v.medium.phase(),
+                weight,
+            });
+            true
+        } else {
+            weight = weight * sigma_n / (majorant * (1. - p_a - p_s));
+            false
+        }
+    });
+    (result.unwrap_or(Collision::Passed(weight)), emitted)
+}
+
+/// fraction of light passing through the media in `volumes` along `ray` up to `t_max`,
+/// estimated with ratio tracking.
+/// nothing passes an infinite distance through a medium filling the whole scene.
+pub fn transmittance(volumes: &[Volume], ray: &Ray, t_max: f64) -> Color {
+    // channels without extinction would keep ratio tracking going forever
+    let endless = volumes
+        .iter()
+        .any(|v| !v.is_bounded() && v.medium.majorant() > 0.);
+    if endless && t_max.is_infinite() {
+        return (0., 0., 0.).into();
+    }
+    let mut tr: Color = (1., 1., 1.).into();
+    track(volumes, ray, t_max, |t, v| {
+        let (sigma_a, sigma_s) = v.medium.coefficients(ray.at(t));
+        let sigma_t = ray.tint(sigma_a + sigma_s);
+        let majorant = v.medium.majorant();
+        tr = tr * (Color::new(1., 1., 1.) - sigma_t / majorant);
+        max!(tr.x.abs(), tr.y.abs(), tr.z.abs()) < EPS
+    });
+    tr
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::object::Sphere;
+
+    #[test]
+    fn test_henyey_greenstein() {
+        let hg = HenyeyGreenstein::new(0.6);
+        // normalized over the sphere
+        let n = 2000;
+        let integral: f64 = (0..n)
+            .map(|i| {
+                let cos = -1. + 2. * (i as f64 + 0.5) / n as f64;
+                2. * PI * hg.p(cos) * 2. / n as f64
+            })
+            .sum();
+        assert_abs_diff_eq!(integral, 1., epsilon = 1e-3);
+        // samples have a mean cosine of g
+        let dir = vec3!(0, 0.6, 0.8);
+        let mean_cos = (0..n)
+            .map(|i| {
+                let u = ((i as f64 + 0.5) / n as f64, (i * 7 % n) as f64 / n as f64);
+                hg.sample(dir, u).dot(dir)
+            })
+            .sum::<f64>()
+            / n as f64;
+        assert_abs_diff_eq!(mean_cos, 0.6, epsilon = 1e-2);
+    }
+
+    #[test]
+    fn test_transmittance() {
+        let medium = HomogeneousMedium::new((0.5, 0.5, 0.5), (0.5, 1., 1.5));
+        let volumes = vec![Volume::new(Sphere::new(vec3!(0, 0, 0), 1.), medium)];
+        let ray = Ray::new(vec3!(0, 0, -3), vec3!(0, 0, 1));
+        let n = 4000;
+        let tr = (0..n)
+            .map(|_| transmittance(&volumes, &ray, 10.))
+            .sum::<Color>()
+            / n as f64;
+        // the ray travels 2 inside the sphere
+        let expected = vec3!((-2f64).exp(), (-3f64).exp(), (-4f64).exp());
+        assert_abs_diff_eq!(tr, expected, epsilon = 1e-2);
+        // rays starting inside only cross the rest
+        let inside = Ray::new(vec3!(0, 0, 0.5), vec3!(0, 0, 1));
+        assert_eq!(volumes[0].segments(&inside, 10.).len(), 1);
+        assert_abs_diff_eq!(volumes[0].segments(&inside, 10.)[0].1, 0.5, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_unbounded_transmittance() {
+        use crate::{
+            light::ParallelLight,
+            material::LambertianModel,
+            object::{Object, World},
+        };
+
+        // fog which lets blue through, lit from infinitely far away
+        let fog = HomogeneousMedium::new((0.05, 0.05, 0.), (0.05, 0.05, 0.));
+        let volumes = vec![Volume::unbounded(fog)];
+        let ray = Ray::new(vec3!(0, 0, 0), vec3!(0, 0, 1));
+        assert_eq!(transmittance(&volumes, &ray, f64::INFINITY), vec3!(0, 0, 0));
+        assert_eq!(transmittance(&volumes, &ray, 1.).z, 1.);
+
+        let mut world = World::empty();
+        world.set_fog(fog);
+        world.add_light(ParallelLight::new(vec3!(0, 0, -1)));
+        world.add_obj(Object::new(
+            Sphere::new(vec3!(0, 0, 0), 1.),
+            LambertianModel::new(0.8),
+        ));
+        for _ in 0..20 {
+            world.trace(&Ray::new(vec3!(0, 0, 3), vec3!(0, 0, -1)), 5);
+        }
+    }
+}
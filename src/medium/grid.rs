@@ -0,0 +1,360 @@
+// media varying over a voxel grid, like clouds and explosions from fluid simulations
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, ErrorKind, Read},
+    path::Path,
+};
+
+use crate::{
+    object::{Aabb, Transformed},
+    spectrum::{blackbody_normalized, integrate_rgb},
+    transform::Transform,
+    util::{Color, Vec3},
+};
+
+use super::{HenyeyGreenstein, Medium, Volume};
+
+const MAGIC: &[u8; 4] = b"VGRD";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("grid: {}", msg))
+}
+
+/// scalar values on the voxels of a box, stored with x varying fastest and z slowest.
+///
+/// grid files come in two flavors. the binary one starts with the bytes `VGRD`, followed by
+/// the three dimensions as little endian `u32` and the values as little endian `f32`.
+/// the text one has the three dimensions and then the values separated by whitespace,
+/// with `#` starting a comment until the end of the line.
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    dims: [usize; 3],
+    values: Vec<f64>,
+    max: f64,
+}
+
+impl VoxelGrid {
+    pub fn new(nx: usize, ny: usize, nz: usize, values: Vec<f64>) -> VoxelGrid {
+        assert!(nx > 0 && ny > 0 && nz > 0, "empty voxel grid");
+        assert_eq!(nx * ny * nz, values.len(), "voxel count mismatch");
+        let max = values.iter().cloned().fold(0., f64::max);
+        VoxelGrid {
+            dims: [nx, ny, nz],
+            values,
+            max,
+        }
+    }
+
+    /// fill the grid with `f` of the voxel centers in [0, 1]^3.
+    pub fn from_fn<F: Fn(Vec3) -> f64>(nx: usize, ny: usize, nz: usize, f: F) -> VoxelGrid {
+        let mut values = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    let p = vec3!(
+                        (x as f64 + 0.5) / nx as f64,
+                        (y as f64 + 0.5) / ny as f64,
+                        (z as f64 + 0.5) / nz as f64
+                    );
+                    values.push(f(p));
+                }
+            }
+        }
+        VoxelGrid::new(nx, ny, nz, values)
+    }
+
+    /// load a binary or text grid file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<VoxelGrid> {
+        VoxelGrid::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: BufRead>(r: &mut R) -> io::Result<VoxelGrid> {
+        if r.fill_buf()?.starts_with(MAGIC) {
+            read_binary(r)
+        } else {
+            read_text(r)
+        }
+    }
+
+    pub fn dims(&self) -> [usize; 3] {
+        self.dims
+    }
+
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    fn get(&self, x: usize, y: usize, z: usize) -> f64 {
+        let [nx, ny, _] = self.dims;
+        self.values[(z * ny + y) * nx + x]
+    }
+
+    /// trilinearly interpolate the grid at `p` in [0, 1]^3, 0 outside of it.
+    pub fn lookup(&self, p: Vec3) -> f64 {
+        let inside = |x: f64| (0. ..=1.).contains(&x);
+        if !(inside(p.x) && inside(p.y) && inside(p.z)) {
+            return 0.;
+        }
+        // voxel values sit at the cell centers
+        let axis = |x: f64, n: usize| {
+            let x = max!(0., x * n as f64 - 0.5);
+            let i = min!(x as usize, n - 1);
+            (i, min!(i + 1, n - 1), x - i as f64)
+        };
+        let [nx, ny, nz] = self.dims;
+        let (x0, x1, fx) = axis(p.x, nx);
+        let (y0, y1, fy) = axis(p.y, ny);
+        let (z0, z1, fz) = axis(p.z, nz);
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let plane = |z| {
+            lerp(
+                lerp(self.get(x0, y0, z), self.get(x1, y0, z), fx),
+                lerp(self.get(x0, y1, z), self.get(x1, y1, z), fx),
+                fy,
+            )
+        };
+        lerp(plane(z0), plane(z1), fz)
+    }
+}
+
+// number of voxels of a grid read from a file
+fn voxel_count(dims: &[usize]) -> io::Result<usize> {
+    if dims.contains(&0) {
+        return Err(invalid("empty grid"));
+    }
+    dims.iter()
+        .try_fold(1usize, |n, &d| n.checked_mul(d))
+        .ok_or_else(|| invalid("grid too large"))
+}
+
+fn read_binary<R: Read>(r: &mut R) -> io::Result<VoxelGrid> {
+    let mut word = [0u8; 4];
+    r.read_exact(&mut word)?;
+    let mut dims = [0usize; 3];
+    for d in dims.iter_mut() {
+        r.read_exact(&mut word)?;
+        *d = u32::from_le_bytes(word) as usize;
+    }
+    let count = voxel_count(&dims)?;
+    // the header is not trusted with the allocation, the data has to match it
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    if count.checked_mul(4) != Some(data.len()) {
+        return Err(invalid("voxel count mismatch"));
+    }
+    let values = data
+        .chunks(4)
+        .map(|b| f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+        .collect();
+    Ok(VoxelGrid::new(dims[0], dims[1], dims[2], values))
+}
+
+fn read_text<R: BufRead>(r: &mut R) -> io::Result<VoxelGrid> {
+    let mut numbers = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        let line = line.split('#').next().unwrap_or("");
+        for word in line.split_whitespace() {
+            let x = word
+                .parse::<f64>()
+                .map_err(|_| invalid(&format!("bad number `{}`", word)))?;
+            numbers.push(x);
+        }
+    }
+    if numbers.len() < 3 {
+        return Err(invalid("missing dimensions"));
+    }
+    let dims = numbers
+        .drain(..3)
+        .map(|x| {
+            if x.fract() == 0. && x >= 1. && x <= u32::MAX as f64 {
+                Ok(x as usize)
+            } else {
+                Err(invalid(&format!("bad dimension `{}`", x)))
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    if numbers.len() != voxel_count(&dims)? {
+        return Err(invalid("voxel count mismatch"));
+    }
+    Ok(VoxelGrid::new(dims[0], dims[1], dims[2], numbers))
+}
+
+// blackbody colors from 0 to 12000 kelvin in steps of 100
+const BLACKBODY_STEP: f64 = 100.;
+const BLACKBODY_STEPS: usize = 120;
+
+/// medium with density, and optionally emission, read from voxel grids in the unit box,
+/// which is placed in the scene by a transform.
+pub struct GridMedium {
+    density: VoxelGrid,
+    sigma_a: Color,
+    sigma_s: Color,
+    phase: HenyeyGreenstein,
+    transform: Transform,
+    emission: Option<(VoxelGrid, Color)>,
+    temperature: Option<(VoxelGrid, f64)>,
+    blackbody: Vec<Color>,
+}
+
+impl GridMedium {
+    /// coefficients are `sigma_a` and `sigma_s` scaled by the interpolated `density`.
+    pub fn new<T: Into<Color>>(density: VoxelGrid, sigma_a: T, sigma_s: T) -> Self {
+        GridMedium {
+            density,
+            sigma_a: sigma_a.into(),
+            sigma_s: sigma_s.into(),
+            phase: HenyeyGreenstein::new(0.),
+            transform: Transform::identity(),
+            emission: None,
+            temperature: None,
+            blackbody: Vec::new(),
+        }
+    }
+
+    /// mean cosine of the scattering angle, see `HenyeyGreenstein`.
+    pub fn with_asymmetry(mut self, g: f64) -> Self {
+        self.phase = HenyeyGreenstein::new(g);
+        self
+    }
+
+    /// place the unit box of the grids in the scene.
+    pub fn with_transform(mut self, transform: Transform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// emit `color` per unit length scaled by the interpolated `emission`.
+    pub fn with_emission<T: Into<Color>>(mut self, emission: VoxelGrid, color: T) -> Self {
+        self.emission = Some((emission, color.into()));
+        self
+    }
+
+    /// glow like a black body at the interpolated `temperature` in kelvin,
+    /// with `scale` the emission per unit length at the peak wavelength.
+    pub fn with_temperature(mut self, temperature: VoxelGrid, scale: f64) -> Self {
+        self.temperature = Some((temperature, scale));
+        self.blackbody = (0..=BLACKBODY_STEPS)
+            .map(|i| {
+                let t = i as f64 * BLACKBODY_STEP;
+                if t > 0. {
+                    integrate_rgb(|l| blackbody_normalized(l, t))
+                } else {
+                    (0., 0., 0.).into()
+                }
+            })
+            .collect();
+        self
+    }
+
+    /// the volume of the grid's box filled with this medium.
+    pub fn into_volume(self) -> Volume {
+        let shape = Transformed::new(Aabb::new((0., 0., 0.), (1., 1., 1.)), self.transform);
+        Volume::new(shape, self)
+    }
+
+    fn local(&self, p: Vec3) -> Vec3 {
+        self.transform.inverse().point(p)
+    }
+
+    fn blackbody_color(&self, kelvin: f64) -> Color {
+        let x = max!(0., kelvin / BLACKBODY_STEP);
+        let i = min!(x as usize, BLACKBODY_STEPS - 1);
+        let t = min!(x - i as f64, 1.);
+        self.blackbody[i] * (1. - t) + self.blackbody[i + 1] * t
+    }
+}
+
+impl Medium for GridMedium {
+    fn coefficients(&self, p: Vec3) -> (Color, Color) {
+        let d = self.density.lookup(self.local(p));
+        (d * self.sigma_a, d * self.sigma_s)
+    }
+
+    fn majorant(&self) -> f64 {
+        let t = self.sigma_a + self.sigma_s;
+        self.density.max() * max!(t.x, t.y, t.z)
+    }
+
+    fn phase(&self) -> HenyeyGreenstein {
+        self.phase
+    }
+
+    fn emission(&self, p: Vec3) -> Color {
+        let local = self.local(p);
+        let mut color = (0., 0., 0.).into();
+        if let Some((grid, c)) = &self.emission {
+            color += grid.lookup(local) * *c;
+        }
+        if let Some((grid, scale)) = &self.temperature {
+            color += *scale * self.blackbody_color(grid.lookup(local));
+        }
+        color
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{medium::transmittance, ray::Ray};
+
+    #[test]
+    fn test_voxel_grid() {
+        let text = "# a 2x1x2 grid\n2 1 2\n0 1\n2 3 # top layer\n";
+        let grid = VoxelGrid::read(&mut text.as_bytes()).unwrap();
+        assert_eq!(grid.dims(), [2, 1, 2]);
+        assert_abs_diff_eq!(grid.max(), 3.);
+        // voxel centers and the midpoint between all of them
+        assert_abs_diff_eq!(grid.lookup(vec3!(0.25, 0.5, 0.25)), 0.);
+        assert_abs_diff_eq!(grid.lookup(vec3!(0.75, 0.5, 0.75)), 3.);
+        assert_abs_diff_eq!(grid.lookup(vec3!(0.5, 0.5, 0.5)), 1.5);
+        assert_abs_diff_eq!(grid.lookup(vec3!(0.5, 0.5, 1.5)), 0.);
+
+        let mut binary = MAGIC.to_vec();
+        for &d in [2u32, 1, 2].iter() {
+            binary.extend_from_slice(&d.to_le_bytes());
+        }
+        for &x in [0f32, 1., 2., 3.].iter() {
+            binary.extend_from_slice(&x.to_le_bytes());
+        }
+        let same = VoxelGrid::read(&mut binary.as_slice()).unwrap();
+        assert_abs_diff_eq!(
+            same.lookup(vec3!(0.6, 0.5, 0.3)),
+            grid.lookup(vec3!(0.6, 0.5, 0.3))
+        );
+
+        assert!(VoxelGrid::read(&mut "2 2 2\n1 2 3".as_bytes()).is_err());
+        for text in ["0 0 0", "-1 1 1\n0", "1.5 1 1\n0", "NaN 1 1\n0"].iter() {
+            assert!(VoxelGrid::read(&mut text.as_bytes()).is_err());
+        }
+        // a huge header fails instead of allocating
+        let mut huge = MAGIC.to_vec();
+        for _ in 0..3 {
+            huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        }
+        huge.extend_from_slice(&[0; 16]);
+        assert!(VoxelGrid::read(&mut huge.as_slice()).is_err());
+        binary.extend_from_slice(&[0; 4]);
+        assert!(VoxelGrid::read(&mut binary.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_grid_medium() {
+        // a ramp of density from 0 to 2 along the ray, in a box 2 long
+        let grid = VoxelGrid::from_fn(1, 1, 64, |p| 2. * p.z);
+        let medium = GridMedium::new(grid, (0.5, 0.5, 0.5), (0.5, 0.5, 0.5)).with_transform(
+            Transform::scale((1., 1., 2.)).then(Transform::translate((0., 0., 1.))),
+        );
+        let volumes = vec![medium.into_volume()];
+        let ray = Ray::new(vec3!(0.5, 0.5, 0), vec3!(0, 0, 1));
+        let n = 4000;
+        let tr = (0..n)
+            .map(|_| transmittance(&volumes, &ray, 10.))
+            .sum::<Color>()
+            / n as f64;
+        // optical depth is the mean density over the length
+        let expected = (-2f64).exp();
+        assert_abs_diff_eq!(tr, vec3!(expected, expected, expected), epsilon = 1e-2);
+    }
+}
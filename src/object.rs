@@ -1,11 +1,17 @@
 use std::cmp;
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use crate::{
-    light::LightSource,
+    aov::AovSample,
+    light::{LightSampler, LightSource},
     material::Material,
+    medium::{self, Collision, HenyeyGreenstein, Medium, Volume},
     ray::{HitInfo, HitRecord, Ray},
-    util::{Color, Vec3, EPS},
+    spectrum,
+    texture::Texture,
+    transform::Transform,
+    util::{Color, Vec3, EPS, PI},
 };
 
 use rand::Rng;
@@ -13,12 +19,186 @@ use rand::Rng;
 pub trait Shape: Sync + Send {
     fn hit_info(&self, ray: &Ray) -> Option<HitInfo>;
     fn hit_moving(&self, ray: &Ray, delta: Vec3) -> Option<HitInfo>;
+
+    /// axis aligned bounding box of this shape, if it is bounded.
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+/// axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new<T: Into<Vec3>>(p0: T, p1: T) -> Aabb {
+        Aabb::point(p0.into()).union_point(p1.into())
+    }
+
+    pub fn point(p: Vec3) -> Aabb {
+        Aabb { min: p, max: p }
+    }
+
+    pub fn union_point(self, p: Vec3) -> Aabb {
+        self.union(Aabb::point(p))
+    }
+
+    pub fn union(self, rhs: Aabb) -> Aabb {
+        let (a, b) = (self, rhs);
+        Aabb {
+            min: vec3!(
+                min!(a.min.x, b.min.x),
+                min!(a.min.y, b.min.y),
+                min!(a.min.z, b.min.z)
+            ),
+            max: vec3!(
+                max!(a.max.x, b.max.x),
+                max!(a.max.y, b.max.y),
+                max!(a.max.z, b.max.z)
+            ),
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.
+    }
+
+    pub fn diagonal(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.diagonal();
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn contains(&self, p: Vec3) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x)
+            && (self.min.y..=self.max.y).contains(&p.y)
+            && (self.min.z..=self.max.z).contains(&p.z)
+    }
+}
+
+// a solid box, hit from outside on its near side and from inside on its far side
+impl Shape for Aabb {
+    fn hit_info(&self, ray: &Ray) -> Option<HitInfo> {
+        let (o, d) = (ray.pos(), ray.dir());
+        let slabs = [
+            (o.x, d.x, self.min.x, self.max.x),
+            (o.y, d.y, self.min.y, self.max.y),
+            (o.z, d.z, self.min.z, self.max.z),
+        ];
+        let (mut near, mut far) = (f64::NEG_INFINITY, f64::INFINITY);
+        for &(o, d, lo, hi) in slabs.iter() {
+            let (t0, t1) = ((lo - o) / d, (hi - o) / d);
+            near = max!(near, min!(t0, t1));
+            far = min!(far, max!(t0, t1));
+        }
+        if near > far {
+            return None;
+        }
+        let t = if near > EPS {
+            near
+        } else if far > EPS {
+            far
+        } else {
+            return None;
+        };
+        let point = ray.at(t);
+        // the face is on the axis where the point is relatively farthest from the center
+        let half = self.diagonal() / 2.;
+        let p = point - self.center();
+        let r = vec3!(p.x / half.x, p.y / half.y, p.z / half.z);
+        let norm = if r.x.abs() >= r.y.abs() && r.x.abs() >= r.z.abs() {
+            vec3!(r.x.signum(), 0, 0)
+        } else if r.y.abs() >= r.z.abs() {
+            vec3!(0, r.y.signum(), 0)
+        } else {
+            vec3!(0, 0, r.z.signum())
+        };
+        Some(HitInfo::new(t, norm, point, d))
+    }
+
+    fn hit_moving(&self, ray: &Ray, delta: Vec3) -> Option<HitInfo> {
+        Aabb::new(self.min + delta, self.max + delta).hit_info(ray)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(*self)
+    }
+}
+
+/// `shape` placed in the scene by `transform`.
+pub struct Transformed<S> {
+    shape: S,
+    transform: Transform,
+}
+
+impl<S: Shape> Transformed<S> {
+    pub fn new(shape: S, transform: Transform) -> Self {
+        Transformed { shape, transform }
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+}
+
+impl<S: Shape> Transformed<S> {
+    fn hit_with(&self, ray: &Ray, transform: Transform) -> Option<HitInfo> {
+        let inv = transform.inverse();
+        let local = Ray::new(inv.point(ray.pos()), inv.vector(ray.dir()));
+        let info = self.shape.hit_info(&local)?;
+        let point = transform.point(local.at(info.distance()));
+        // `HitInfo::new` faces the normal towards the ray again, keeping which side was hit
+        let norm = transform.normal(info.normal());
+        let norm = if info.is_to_outward() { -norm } else { norm };
+        let (u, v) = info.uv();
+        let distance = point.distance(ray.pos());
+        let hit = HitInfo::new(distance, norm, point, ray.dir()).with_uv(u, v);
+        Some(match info.tangent() {
+            Some(tangent) => hit.with_tangent(transform.vector(tangent)),
+            None => hit,
+        })
+    }
+}
+
+impl<S: Shape> Shape for Transformed<S> {
+    fn hit_info(&self, ray: &Ray) -> Option<HitInfo> {
+        self.hit_with(ray, self.transform)
+    }
+
+    fn hit_moving(&self, ray: &Ray, delta: Vec3) -> Option<HitInfo> {
+        self.hit_with(ray, self.transform.then(Transform::translate(delta)))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let b = self.shape.bounds()?;
+        let corners = (0..8).map(|i| {
+            let pick = |bit: usize, lo: f64, hi: f64| if i & bit == 0 { lo } else { hi };
+            vec3!(
+                pick(1, b.min.x, b.max.x),
+                pick(2, b.min.y, b.max.y),
+                pick(4, b.min.z, b.max.z)
+            )
+        });
+        corners
+            .map(|p| Aabb::point(self.transform.point(p)))
+            .fold(None, |acc: Option<Aabb>, p| {
+                Some(acc.map_or(p, |acc| acc.union(p)))
+            })
+    }
 }
 
 pub struct Object {
     pub shape: Box<dyn Shape>,
     pub material: Arc<dyn Material>,
     pub moving_to: Vec3,
+    /// opacity mask read from the first channel, hits are skipped where it is below a random number
+    pub alpha: Option<Arc<dyn Texture>>,
 }
 
 impl Object {
@@ -31,6 +211,7 @@ impl Object {
             shape: Box::new(shape),
             material: Arc::new(material),
             moving_to: (0.,0.,0.).into(),
+            alpha: None,
         }
     }
 
@@ -39,6 +220,12 @@ impl Object {
         self
     }
 
+    /// cut out the surface where `alpha` is 0, and let rays through with probability `1 - alpha` elsewhere.
+    pub fn with_alpha<T: Texture + 'static>(mut self, alpha: T) -> Object {
+        self.alpha = Some(Arc::new(alpha));
+        self
+    }
+
     fn moving_delta(&self) -> Vec3 {
         let mut rng = rand::thread_rng();
         let t: f64 = rng.gen();
@@ -46,11 +233,34 @@ impl Object {
     }
 }
 
+// at most this many masked out surfaces of one object are skipped by a ray
+const MAX_ALPHA_SKIPS: usize = 64;
+
 impl Object {
     pub fn hit_by(&self, ray: &Ray) -> Option<HitRecord> {
-        self.shape.hit_moving(ray, self.moving_delta()).map(|info| HitRecord {
+        let delta = self.moving_delta();
+        let mut info = self.shape.hit_moving(ray, delta)?;
+        if let Some(alpha) = &self.alpha {
+            // continue past masked out hits until an opaque one
+            let mut rng = rand::thread_rng();
+            let mut skipped = 0.;
+            for _ in 0..MAX_ALPHA_SKIPS {
+                let (u, v) = info.uv();
+                if alpha.value(u, v, info.pos()).x > rng.gen::<f64>() {
+                    break;
+                }
+                skipped += info.distance() + EPS;
+                let next = Ray::new(ray.pos() + skipped * ray.dir(), ray.dir());
+                info = self.shape.hit_moving(&next, delta)?;
+            }
+            info = info.with_distance(info.distance() + skipped);
+        }
+        if let Some(differential) = ray.differential() {
+            info = info.with_differential(&differential, |r| self.shape.hit_moving(r, delta));
+        }
+        Some(HitRecord {
             material: self.material.clone(),
-            info,
+            info: info.with_wavelengths(ray.wavelengths()),
         })
     }
 }
@@ -60,6 +270,8 @@ pub struct Triangle {
     pub p0: Vec3,
     pub p1: Vec3,
     pub p2: Vec3,
+    /// surface coordinates of the three vertices
+    pub uvs: [(f64, f64); 3],
 }
 
 impl Triangle {
@@ -68,14 +280,32 @@ impl Triangle {
             p0: p0.into(),
             p1: p1.into(),
             p2: p2.into(),
+            uvs: [(0., 0.), (1., 0.), (0., 1.)],
         }
     }
 
+    pub fn with_uvs(mut self, uv0: (f64, f64), uv1: (f64, f64), uv2: (f64, f64)) -> Triangle {
+        self.uvs = [uv0, uv1, uv2];
+        self
+    }
+
     pub fn normal(&self) -> Vec3 {
-        let Self { p0, p1, p2 } = self;
+        let Self { p0, p1, p2, .. } = self;
         (*p1 - *p0).cross(*p2 - *p0).unit()
     }
 
+    // derivative of the position by the surface coordinate u, none for degenerate uvs
+    fn dpdu(&self) -> Option<Vec3> {
+        let [uv0, uv1, uv2] = self.uvs;
+        let (du02, dv02) = (uv0.0 - uv2.0, uv0.1 - uv2.1);
+        let (du12, dv12) = (uv1.0 - uv2.0, uv1.1 - uv2.1);
+        let det = du02 * dv12 - dv02 * du12;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        Some((dv12 * (self.p0 - self.p2) - dv02 * (self.p1 - self.p2)) / det)
+    }
+
     pub fn is_in_plane<T: Into<Vec3>>(&self, point: T) -> bool {
         let v = self.p0 - point.into();
         v.dot(self.normal()).abs() < EPS
@@ -118,12 +348,17 @@ impl Shape for Triangle {
         }
         let t = f * e2.dot(q);
         if t > EPS {
-            Some(HitInfo::new(
-                t,
-                e1.cross(e2).unit(),
-                t * ray.dir() + ray.pos(),
-                ray.dir(),
-            ))
+            let [uv0, uv1, uv2] = self.uvs;
+            let w = 1. - u - v;
+            let info = HitInfo::new(t, e1.cross(e2).unit(), t * ray.dir() + ray.pos(), ray.dir())
+                .with_uv(
+                    w * uv0.0 + u * uv1.0 + v * uv2.0,
+                    w * uv0.1 + u * uv1.1 + v * uv2.1,
+                );
+            Some(match self.dpdu() {
+                Some(dpdu) => info.with_tangent(dpdu),
+                None => info,
+            })
         } else {
             None
         }
@@ -136,6 +371,10 @@ impl Shape for Triangle {
         tri.p2 += delta;
         tri.hit_info(ray)
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.p0, self.p1).union_point(self.p2))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -156,8 +395,8 @@ impl Square {
         let p2 = center + x2 - y2;
         let p3 = center + x2 + y2;
         Square {
-            tri0: Triangle::new(p0, p1, p2),
-            tri1: Triangle::new(p2, p3, p0),
+            tri0: Triangle::new(p0, p1, p2).with_uvs((0., 0.), (0., 1.), (1., 1.)),
+            tri1: Triangle::new(p2, p3, p0).with_uvs((1., 1.), (1., 0.), (0., 0.)),
         }
     }
 
@@ -168,8 +407,8 @@ impl Square {
     // anti-clockwise
     pub fn from_points(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3) -> Self {
         Square {
-            tri0: Triangle::new(p0, p1, p2),
-            tri1: Triangle::new(p1, p2, p3),
+            tri0: Triangle::new(p0, p1, p2).with_uvs((0., 0.), (0., 1.), (1., 1.)),
+            tri1: Triangle::new(p1, p2, p3).with_uvs((0., 1.), (1., 1.), (1., 0.)),
         }
     }
 
@@ -182,7 +421,7 @@ impl Square {
     }
 
     pub fn get_corners(&self) -> Vec<Vec3> {
-        let Triangle { p0, p1, p2 } = self.tri0;
+        let Triangle { p0, p1, p2, .. } = self.tri0;
         vec![p0, p1, p2, self.tri1.p2]
     }
 }
@@ -194,6 +433,10 @@ impl Shape for Square {
     fn hit_moving(&self, ray: &Ray, delta: Vec3) -> Option<HitInfo> {
         self.tri0.hit_moving(ray, delta).or(self.tri1.hit_moving(ray, delta))
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.tri0.bounds()?.union(self.tri1.bounds()?))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -252,6 +495,13 @@ impl Shape for Cube {
                 d1.partial_cmp(&d2).unwrap_or(cmp::Ordering::Equal)
             })
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.squares()
+            .iter()
+            .filter_map(|square| square.bounds())
+            .fold(None, |acc, b| Some(acc.map_or(b, |a: Aabb| a.union(b))))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -292,7 +542,18 @@ impl Shape for Sphere {
         };
         let norm_proj = ray.dir().proj_to(norm);
         let _dir = ray.dir() - 2. * norm_proj;
-        Some(HitInfo::new(t, norm, point, ray.dir()))
+        // longitude and colatitude around +z
+        let local = (point - self.center).unit();
+        let phi = local.y.atan2(local.x);
+        let u = if phi < 0. { phi + 2. * PI } else { phi } / (2. * PI);
+        let v = max!(-1., min!(1., local.z)).acos() / PI;
+        // along the longitude, vanishing at the poles
+        let dpdu = 2. * PI * self.radius.abs() * vec3!(-local.y, local.x, 0);
+        Some(
+            HitInfo::new(t, norm, point, ray.dir())
+                .with_uv(u, v)
+                .with_tangent(dpdu),
+        )
     }
 
     fn hit_moving(&self, ray: &Ray, delta: Vec3) -> Option<HitInfo> {
@@ -300,11 +561,26 @@ impl Shape for Sphere {
         sph.center += delta;
         sph.hit_info(ray)
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = self.radius.abs();
+        Some(Aabb::new(self.center - r, self.center + r))
+    }
 }
 
 pub struct World {
+    /// objects of the scene, which should be added by `add_obj` to get their material ids.
     pub objects: Vec<Object>,
     pub lights: Vec<Arc<dyn LightSource>>,
+    /// when set, materials shade with one light chosen by it instead of every light.
+    pub light_sampler: Option<Box<dyn LightSampler>>,
+    /// trace sampled wavelengths instead of rgb, see `spectrum`.
+    pub spectral: bool,
+    /// participating media, which should not overlap apart from fog.
+    pub volumes: Vec<Volume>,
+    // id of the material of every object for `Aov::MaterialId`, by the address of its data
+    material_ids: Vec<usize>,
+    materials: HashMap<usize, usize>,
 }
 
 impl World {
@@ -312,10 +588,42 @@ impl World {
         World {
             objects: Vec::new(),
             lights: Vec::new(),
+            light_sampler: None,
+            spectral: false,
+            volumes: Vec::new(),
+            material_ids: Vec::new(),
+            materials: HashMap::new(),
+        }
+    }
+
+    /// trace rays from `trace` at sampled wavelengths and convert them to rgb through cie xyz.
+    pub fn set_spectral(&mut self, spectral: bool) {
+        self.spectral = spectral;
+    }
+
+    /// shade with one light chosen by `sampler`, which should be built after all lights are added.
+    pub fn set_light_sampler<T: LightSampler + 'static>(&mut self, sampler: T) {
+        self.light_sampler = Some(Box::new(sampler));
+    }
+
+    /// choose a light for `hit` with the light sampler, returning the light and its probability.
+    pub fn sample_light(&self, hit: &HitInfo) -> Option<(&dyn LightSource, f64)> {
+        let sampler = self.light_sampler.as_ref()?;
+        let (i, pmf) = sampler.sample(hit, rand::thread_rng().gen())?;
+        let light = self.lights.get(i)?;
+        if pmf > 0. {
+            Some((light.as_ref(), pmf))
+        } else {
+            None
         }
     }
 
     pub fn add_obj(&mut self, obj: Object) {
+        // compare the data pointers only, vtables of the same type may differ between crates
+        let material = Arc::as_ptr(&obj.material) as *const () as usize;
+        let next = self.objects.len() + 1;
+        let id = *self.materials.entry(material).or_insert(next);
+        self.material_ids.push(id);
         self.objects.push(obj);
     }
 
@@ -323,35 +631,266 @@ impl World {
         self.lights.push(Arc::new(light));
     }
 
+    pub fn add_volume(&mut self, volume: Volume) {
+        self.volumes.push(volume);
+    }
+
+    /// fill the whole scene with `medium`.
+    pub fn set_fog<M: Medium + 'static>(&mut self, medium: M) {
+        self.volumes.retain(|v| v.is_bounded());
+        self.volumes.push(Volume::unbounded(medium));
+    }
+
+    /// radiance arriving along `ray` scaled by its weight, following at most `depth` bounces.
     pub fn trace(&self, ray: &Ray, depth: u64) -> Color {
         if depth == 0 {
             return Color::new(0., 0., 0.);
         }
+        if self.spectral && ray.wavelengths().is_none() {
+            let lambda = spectrum::sample_wavelengths(rand::thread_rng().gen());
+            let radiance = self.trace(&ray.with_wavelengths(lambda), depth);
+            return spectrum::to_rgb(radiance, lambda);
+        }
+        let weight = ray.weight();
+        if weight == Color::new(0., 0., 0.) {
+            return weight;
+        }
+        weight * self.radiance(ray, depth)
+    }
 
-        let mut see_light = false;
-        let mut color = (0., 0., 0.).into();
-        for light in &self.lights {
-            if let Some(c) = light.looked(ray, self) {
-                see_light = true;
-                color += c;
-            }
+    fn radiance(&self, ray: &Ray, depth: u64) -> Color {
+        let hit = ray.hit(self);
+        if self.volumes.is_empty() {
+            return self.shade(ray, hit, depth);
         }
-        if see_light {
+        let t_max = hit.as_ref().map_or(f64::INFINITY, |rec| rec.distance());
+        let (collision, emitted) = medium::sample_collision(&self.volumes, ray, t_max);
+        emitted
+            + match collision {
+                Collision::Scattered { pos, phase, weight } => {
+                    weight * self.scatter_in_medium(ray, pos, phase, depth)
+                }
+                Collision::Absorbed => (0., 0., 0.).into(),
+                Collision::Passed(weight) => weight * self.shade(ray, hit, depth),
+            }
+    }
+
+    // radiance along `ray` from lights it looks at or from its surface `hit`
+    fn shade(&self, ray: &Ray, hit: Option<HitRecord>, depth: u64) -> Color {
+        if let Some(color) = self.looked(ray) {
             return color;
         }
 
-        ray.hit(self)
-            .map(|hit| {
-                let m = &hit.material;
-                let info = &hit.info;
-                let traced: Vec<_> = m
-                    .scatter(info)
-                    .into_iter()
-                    .map(|ray| self.trace(&ray, depth - 1))
-                    .collect();
-                m.render(info, self, &traced)
+        hit.map(|hit| {
+            let m = &hit.material;
+            let info = &hit.info;
+            let traced: Vec<_> = m
+                .scatter(info)
+                .into_iter()
+                .map(|ray| self.trace(&ray, depth - 1))
+                .collect();
+            m.render(info, self, &traced)
+        })
+        .unwrap_or((0., 0., 0.).into())
+    }
+
+    /// radiance along a camera `ray` like `trace`, together with the passes of `Aov`.
+    pub fn trace_aovs(&self, ray: &Ray, depth: u64) -> AovSample {
+        let mut sample = AovSample::empty();
+        if depth == 0 {
+            return sample;
+        }
+        if self.spectral && ray.wavelengths().is_none() {
+            let lambda = spectrum::sample_wavelengths(rand::thread_rng().gen());
+            let mut sample = self.trace_aovs(&ray.with_wavelengths(lambda), depth);
+            for c in [
+                &mut sample.beauty,
+                &mut sample.albedo,
+                &mut sample.direct,
+                &mut sample.indirect,
+                &mut sample.emission,
+            ]
+            .iter_mut()
+            {
+                **c = spectrum::to_rgb(**c, lambda);
+            }
+            return sample;
+        }
+        let hit = self
+            .objects
+            .iter()
+            .enumerate()
+            .filter_map(|(i, obj)| obj.hit_by(ray).map(|rec| (i, rec)))
+            .min_by(|(_, a), (_, b)| {
+                a.distance()
+                    .partial_cmp(&b.distance())
+                    .unwrap_or(cmp::Ordering::Equal)
+            });
+        if let Some((i, rec)) = &hit {
+            let info = &rec.info;
+            sample.depth = info.distance();
+            sample.position = ray.at(info.distance());
+            sample.normal = info.normal();
+            sample.albedo = rec.material.albedo(info);
+            sample.object_id = i + 1;
+            sample.material_id = self.material_ids.get(*i).cloned().unwrap_or(0);
+            sample.shadow = self.shadow(info);
+        }
+        let hit = hit.map(|(_, rec)| rec);
+        let black = Color::new(0., 0., 0.);
+        let (emission, direct, indirect) = if self.volumes.is_empty() {
+            self.shade_passes(ray, hit, depth)
+        } else {
+            let t_max = hit.as_ref().map_or(f64::INFINITY, |rec| rec.distance());
+            let (collision, emitted) = medium::sample_collision(&self.volumes, ray, t_max);
+            match collision {
+                // light scattered by the medium counts as indirect
+                Collision::Scattered { pos, phase, weight } => (
+                    emitted,
+                    black,
+                    weight * self.scatter_in_medium(ray, pos, phase, depth),
+                ),
+                Collision::Absorbed => (emitted, black, black),
+                Collision::Passed(weight) => {
+                    let (e, d, i) = self.shade_passes(ray, hit, depth);
+                    (emitted + weight * e, weight * d, weight * i)
+                }
+            }
+        };
+        let weight = ray.weight();
+        sample.emission = weight * emission;
+        sample.direct = weight * direct;
+        sample.indirect = weight * indirect;
+        sample.beauty = sample.emission + sample.direct + sample.indirect;
+        sample
+    }
+
+    // radiance of lights `ray` looks at directly, if any
+    fn looked(&self, ray: &Ray) -> Option<Color> {
+        self.lights
+            .iter()
+            .filter_map(|light| light.looked(ray, self))
+            .fold(None, |acc, c| {
+                Some(acc.unwrap_or_else(|| Color::new(0., 0., 0.)) + ray.tint(c))
             })
-            .unwrap_or((0., 0., 0.).into())
+    }
+
+    // radiance of `shade` split into emission, direct and indirect light.
+    // the material is shaded once, with the radiance along its scattered rays split into what
+    // they find at the next light or emitter and the rest, which the material weights alike
+    fn shade_passes(&self, ray: &Ray, hit: Option<HitRecord>, depth: u64) -> (Color, Color, Color) {
+        let black = Color::new(0., 0., 0.);
+        if let Some(color) = self.looked(ray) {
+            return (color, black, black);
+        }
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return (black, black, black),
+        };
+        let m = &hit.material;
+        let info = &hit.info;
+        let rays = m.scatter(info);
+        let (near, far): (Vec<_>, Vec<_>) = rays
+            .iter()
+            .map(|ray| self.trace_split(ray, depth - 1))
+            .unzip();
+        let emission = m.emission(info);
+        let (light, weights) = m.render_weights(info, self, rays.len());
+        let weighted = |traced: &[Color]| {
+            let sum = weights.iter().zip(traced).map(|(&w, &c)| w * c);
+            sum.fold(Color::new(0., 0., 0.), |acc, c| acc + c)
+        };
+        (emission, light - emission + weighted(&near), weighted(&far))
+    }
+
+    // radiance along `ray` like `trace`, split into what it finds at its first light,
+    // emitter or medium emission and what arrives there after further bounces
+    fn trace_split(&self, ray: &Ray, depth: u64) -> (Color, Color) {
+        let black = Color::new(0., 0., 0.);
+        let weight = ray.weight();
+        if depth == 0 || weight == black {
+            return (black, black);
+        }
+        let hit = ray.hit(self);
+        let (near, far) = if self.volumes.is_empty() {
+            self.shade_split(ray, hit, depth)
+        } else {
+            let t_max = hit.as_ref().map_or(f64::INFINITY, |rec| rec.distance());
+            let (collision, emitted) = medium::sample_collision(&self.volumes, ray, t_max);
+            match collision {
+                Collision::Scattered { pos, phase, weight } => (
+                    emitted,
+                    weight * self.scatter_in_medium(ray, pos, phase, depth),
+                ),
+                Collision::Absorbed => (emitted, black),
+                Collision::Passed(weight) => {
+                    let (near, far) = self.shade_split(ray, hit, depth);
+                    (emitted + weight * near, weight * far)
+                }
+            }
+        };
+        (weight * near, weight * far)
+    }
+
+    fn shade_split(&self, ray: &Ray, hit: Option<HitRecord>, depth: u64) -> (Color, Color) {
+        let black = Color::new(0., 0., 0.);
+        if let Some(color) = self.looked(ray) {
+            return (color, black);
+        }
+        match hit {
+            Some(hit) => {
+                let emission = hit.material.emission(&hit.info);
+                (emission, self.shade(ray, Some(hit), depth) - emission)
+            }
+            None => (black, black),
+        }
+    }
+
+    // fraction of the light arriving at `hit` which other objects block, weighted by intensity
+    fn shadow(&self, hit: &HitInfo) -> f64 {
+        let (mut blocked, mut total) = (0., 0.);
+        for light in &self.lights {
+            let sample = light.sample(hit);
+            if sample.intensity <= 0. {
+                continue;
+            }
+            total += sample.intensity;
+            if light.is_sample_in_shadow(hit, &sample, self) {
+                blocked += sample.intensity;
+            }
+        }
+        if total > 0. {
+            blocked / total
+        } else {
+            0.
+        }
+    }
+
+    // radiance scattered towards `ray` at `pos` inside a medium.
+    // delta lights are sampled directly, everything else is found by a ray drawn from `phase`
+    fn scatter_in_medium(
+        &self,
+        ray: &Ray,
+        pos: Vec3,
+        phase: HenyeyGreenstein,
+        depth: u64,
+    ) -> Color {
+        let probe =
+            HitInfo::new(0., -ray.dir(), pos, ray.dir()).with_wavelengths(ray.wavelengths());
+        let mut color: Color = (0., 0., 0.).into();
+        for light in self.lights.iter().filter(|light| light.is_delta()) {
+            let sample = light.sample(&probe);
+            if sample.intensity <= 0. || light.is_sample_in_shadow(&probe, &sample, self) {
+                continue;
+            }
+            let to_light = ray.scattered(pos, -sample.dir);
+            let distance = light.distance(&probe).unwrap_or(f64::INFINITY);
+            let tr = medium::transmittance(&self.volumes, &to_light, distance);
+            let p = phase.p(sample.dir.dot(-ray.dir()));
+            color += sample.intensity * p * tr * ray.tint(sample.color);
+        }
+        let dir = phase.sample(ray.dir(), rand::thread_rng().gen());
+        color + self.trace(&ray.scattered(pos, dir), depth - 1)
     }
 }
 
@@ -391,4 +930,140 @@ mod test {
         assert!(!tri.contain(vec3!(0, 0, 0.1)));
         assert!(tri.contain(vec3!(1, 1, 0)));
     }
+
+    #[test]
+    fn test_uv() {
+        let square = Square::new(vec3!(0, 0, 0), vec3!(1, 0, 0), vec3!(0, 1, 0), 2.);
+        let ray = Ray::new(vec3!(0.5, 0.5, 1), vec3!(0, 0, -1));
+        let (u, v) = square.hit_info(&ray).unwrap().uv();
+        assert_abs_diff_eq!(u, 0.75);
+        assert_abs_diff_eq!(v, 0.25);
+
+        let sphere = Sphere::new(vec3!(0, 0, 0), 1.);
+        let ray = Ray::new(vec3!(0, 2, 0), vec3!(0, -1, 0));
+        let (u, v) = sphere.hit_info(&ray).unwrap().uv();
+        assert_abs_diff_eq!(u, 0.25);
+        assert_abs_diff_eq!(v, 0.5);
+    }
+
+    #[test]
+    fn test_tangent() {
+        let square = Square::new(vec3!(0, 0, 0), vec3!(1, 0, 0), vec3!(0, 1, 0), 2.);
+        let ray = Ray::new(vec3!(0.5, 0.5, 1), vec3!(0, 0, -1));
+        let info = square.hit_info(&ray).unwrap();
+        assert_abs_diff_eq!(info.tangent().unwrap(), vec3!(2, 0, 0), epsilon = 1e-9);
+        // the other triangle of the square agrees
+        let ray = Ray::new(vec3!(-0.5, -0.5, 1), vec3!(0, 0, -1));
+        let info = square.hit_info(&ray).unwrap();
+        assert_abs_diff_eq!(info.tangent().unwrap(), vec3!(2, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(
+            info.frame().to_world(vec3!(1, 0, 0)),
+            vec3!(1, 0, 0),
+            epsilon = 1e-9
+        );
+
+        let stretched = Transformed::new(square, Transform::scale((2., 1., 1.)));
+        let info = stretched.hit_info(&ray).unwrap();
+        assert_abs_diff_eq!(info.tangent().unwrap(), vec3!(4, 0, 0), epsilon = 1e-9);
+
+        // along the longitude of a sphere, with any frame at the poles
+        let sphere = Sphere::new(vec3!(0, 0, 0), 1.);
+        let ray = Ray::new(vec3!(0, 2, 0), vec3!(0, -1, 0));
+        let info = sphere.hit_info(&ray).unwrap();
+        assert_abs_diff_eq!(info.frame().to_world(vec3!(1, 0, 0)), vec3!(-1, 0, 0));
+        let ray = Ray::new(vec3!(0, 0, 2), vec3!(0, 0, -1));
+        let frame = sphere.hit_info(&ray).unwrap().frame();
+        assert_abs_diff_eq!(frame.to_world(vec3!(0, 0, 1)), vec3!(0, 0, 1));
+    }
+
+    #[test]
+    fn test_alpha() {
+        use crate::material::Specular;
+
+        // only the lower half of the sphere is opaque
+        struct Lower;
+        impl Texture for Lower {
+            fn value(&self, _u: f64, _v: f64, p: Vec3) -> Color {
+                if p.z < 0. { (1., 1., 1.) } else { (0., 0., 0.) }.into()
+            }
+        }
+        let ray = Ray::new(vec3!(0, 0, 2), vec3!(0, 0, -1));
+        let sphere = Object::new(Sphere::new(vec3!(0, 0, 0), 1.), Specular::new(1.));
+        assert_abs_diff_eq!(sphere.hit_by(&ray).unwrap().distance(), 1., epsilon = 1e-6);
+        let sphere = sphere.with_alpha(Lower);
+        let rec = sphere.hit_by(&ray).unwrap();
+        assert_abs_diff_eq!(rec.distance(), 3., epsilon = 1e-6);
+        assert_abs_diff_eq!(rec.pos(), vec3!(0, 0, -1), epsilon = 1e-6);
+
+        let square = Object::new(
+            Square::new(vec3!(0, 0, 0), vec3!(1, 0, 0), vec3!(0, 1, 0), 2.),
+            Specular::new(1.),
+        )
+        .with_alpha(0.);
+        assert!(square.hit_by(&ray).is_none());
+    }
+
+    #[test]
+    fn test_ray_differential() {
+        use crate::{
+            image::Image, material::Specular, ray::RayDifferential, texture::ImageTexture,
+        };
+
+        // parallel rays 0.1 apart on a square with 2 units per surface coordinate
+        let ray = Ray::new(vec3!(0, 0, 2), vec3!(0, 0, -1)).with_differential(RayDifferential {
+            rx_pos: vec3!(0.1, 0, 2),
+            rx_dir: vec3!(0, 0, -1),
+            ry_pos: vec3!(0, 0.1, 2),
+            ry_dir: vec3!(0, 0, -1),
+        });
+        let square = Object::new(
+            Square::new(vec3!(0, 0, 0), vec3!(1, 0, 0), vec3!(0, 1, 0), 2.),
+            Specular::new(1.),
+        );
+        let info = square.hit_by(&ray).unwrap().info;
+        assert_abs_diff_eq!(info.filter_width(), 0.05, epsilon = 1e-9);
+        let reflected = info.reflect().differential().unwrap();
+        assert_abs_diff_eq!(reflected.rx_dir, vec3!(0, 0, 1), epsilon = 1e-9);
+        // a convex mirror spreads them out
+        let sphere = Object::new(Sphere::new(vec3!(0, 0, 0), 1.), Specular::new(1.));
+        let info = sphere.hit_by(&ray).unwrap().info;
+        let reflected = info.reflect().differential().unwrap();
+        assert!(reflected.rx_dir.x > 0.1 && reflected.ry_dir.y > 0.1);
+
+        // wide footprints average a checker board out
+        let black = vec3!(0, 0, 0);
+        let white = vec3!(1, 1, 1);
+        let checker = ImageTexture::new(Image::from_pixels(2, 2, vec![white, black, black, white]));
+        assert_eq!(checker.levels(), 2);
+        let p = vec3!(0, 0, 0);
+        assert_abs_diff_eq!(checker.filtered(0.25, 0.25, p, 0.), white);
+        assert_abs_diff_eq!(checker.filtered(0.25, 0.25, p, 1.), vec3!(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn test_transformed() {
+        let unit = Aabb::new(vec3!(0, 0, 0), vec3!(1, 1, 1));
+        let ray = Ray::new(vec3!(0.5, 0.5, 3), vec3!(0, 0, -1));
+        let info = unit.hit_info(&ray).unwrap();
+        assert_abs_diff_eq!(info.distance(), 2.);
+        assert_abs_diff_eq!(info.normal(), vec3!(0, 0, 1));
+
+        // a 2x2x2 box at (0, 0, -1) turned upside down
+        let t = Transform::scale((2., 2., 2.))
+            .then(Transform::rotate((1., 0., 0.), 180.))
+            .then(Transform::translate((0., 2., 1.)));
+        let boxed = Transformed::new(unit, t);
+        let ray = Ray::new(vec3!(1, 1, 3), vec3!(0, 0, -1));
+        let info = boxed.hit_info(&ray).unwrap();
+        assert_abs_diff_eq!(info.distance(), 2., epsilon = 1e-9);
+        assert_abs_diff_eq!(info.normal(), vec3!(0, 0, 1), epsilon = 1e-9);
+        assert!(!info.is_to_outward());
+        let inside = Ray::new(vec3!(1, 1, 0), vec3!(0, 0, -1));
+        let info = boxed.hit_info(&inside).unwrap();
+        assert_abs_diff_eq!(info.distance(), 1., epsilon = 1e-9);
+        assert!(info.is_to_outward());
+        let bounds = boxed.bounds().unwrap();
+        assert_abs_diff_eq!(bounds.min, vec3!(0, 0, -1), epsilon = 1e-9);
+        assert_abs_diff_eq!(bounds.max, vec3!(2, 2, 1), epsilon = 1e-9);
+    }
 }
@@ -1,9 +1,10 @@
 use std::sync::Arc;
 
-use rand::prelude::*;
-
 use crate::{
+    material::fresnel_dielectric,
     object::World,
+    spectrum,
+    texture::Texture,
     util::*,
     Material
 };
@@ -12,6 +13,19 @@ use crate::{
 pub struct Ray {
     pub(crate) pos: Vec3,
     pub(crate) dir: Vec3,
+    pub(crate) weight: Color,
+    pub(crate) wavelengths: Option<Vec3>,
+    pub(crate) differential: Option<RayDifferential>,
+}
+
+/// rays offset by a pixel to the right and down from a camera ray,
+/// which follow it through specular bounces to track its footprint.
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub rx_pos: Vec3,
+    pub rx_dir: Vec3,
+    pub ry_pos: Vec3,
+    pub ry_dir: Vec3,
 }
 
 impl Ray {
@@ -31,9 +45,18 @@ impl Ray {
         Ray {
             pos,
             dir: dir.unit(),
+            weight: (1., 1., 1.).into(),
+            wavelengths: None,
+            differential: None,
         }
     }
 
+    /// scale the radiance carried back by this ray, e.g. by a bsdf sample weight.
+    pub fn with_weight<T: Into<Color>>(mut self, weight: T) -> Self {
+        self.weight = weight.into();
+        self
+    }
+
     pub fn pos(&self) -> Vec3 {
         self.pos
     }
@@ -41,114 +64,47 @@ impl Ray {
     pub fn dir(&self) -> Vec3 {
         self.dir
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub struct Camera {
-    pub pos: Vec3,
-    up: Vec3,
-    sight: Vec3,
-    sample_rate: u64,
-    focus_dist: f64,
-    aperture: f64,
-    fov: f64,
-    aspect: f64,
-}
+    pub fn weight(&self) -> Color {
+        self.weight
+    }
 
-impl Camera {
-    pub fn with_sample_rate(mut self, rate: u64) -> Self {
-        self.sample_rate = rate;
-        self
+    /// point at distance `t` along the ray.
+    pub fn at(&self, t: f64) -> Vec3 {
+        self.pos + t * self.dir
     }
 
-    pub fn with_focus_dist(mut self, focus_dist: f64) -> Self {
-        self.focus_dist = focus_dist;
-        self
+    // continue this path from `pos` towards `dir`, keeping its wavelengths
+    pub(crate) fn scattered(&self, pos: Vec3, dir: Vec3) -> Ray {
+        Ray {
+            wavelengths: self.wavelengths,
+            ..Ray::new(pos, dir)
+        }
     }
 
-    pub fn with_aperture(mut self, aperture: f64) -> Self {
-        self.aperture = aperture;
+    /// carry radiance at the wavelengths in nm of each channel instead of rgb.
+    pub fn with_wavelengths(mut self, lambda: Vec3) -> Self {
+        self.wavelengths = Some(lambda);
         self
     }
 
-    pub fn with_fov(mut self, deg: f64) -> Self {
-        self.fov = deg / 180. * PI;
-        self
+    pub fn wavelengths(&self) -> Option<Vec3> {
+        self.wavelengths
     }
 
-    pub fn with_aspect(mut self, aspect: f64) -> Self {
-        self.aspect = aspect;
+    pub fn with_differential(mut self, differential: RayDifferential) -> Self {
+        self.differential = Some(differential);
         self
     }
 
-    /// adjust this camera to look at `point`.
-    pub fn look(&mut self, point: Vec3) {
-        self.sight = (point - self.pos).unit();
-        let right = self.right();
-        self.up = right.cross(self.sight).unit();
-    }
-
-    /// return up direction of this camera.
-    pub fn up(&self) -> Vec3 {
-        self.up
-    }
-
-    /// return right direction of this camera.
-    pub fn right(&self) -> Vec3 {
-        self.sight.cross(self.up).unit()
-    }
-
-    /// return sight direction of this camera.
-    pub fn sight(&self) -> Vec3 {
-        self.sight
-    }
-
-    /// emit rays through a focus distance unit away square screen whose size is 2*focus_dist unit.
-    pub fn emit_rays(
-        &self,
-        width: u64,
-        height: u64,
-    ) -> impl Iterator<Item = (u64, u64, Ray)> + '_ {
-        let vh = 2. * (self.fov / 2.).tan() * self.focus_dist;
-        let vw = vh * self.aspect;
-        let pw = vw / width as f64 * self.right();
-        let ph = vh / height as f64 * self.up();
-        let center = self.pos + self.focus_dist * self.sight;
-        let bias = 0.5 * (pw - ph);
-        let top_left = center - vw * self.right() / 2. + vh * self.up() / 2. + bias;
-        (0..width)
-            .into_iter()
-            .flat_map(move |w| (0..height).into_iter().map(move |h| (w, h)))
-            .flat_map(move |(w, h)| {
-                (0..self.sample_rate).into_iter().map(move |_| {
-                    let mut rng = rand::thread_rng();
-                    let b = 0.5;
-                    let (rw, rh) = (rng.gen_range(-b, b), rng.gen_range(-b, b));
-                    let to = top_left + (w as f64 + rw) * pw - (h as f64 + rh) * ph;
-
-                    let rd = gen_point_in_disk(self.aperture / 2.);
-                    let offset = self.right() * rd.x + self.up() * rd.y;
-                    let from = self.pos + offset;
-
-                    (w, h, Ray::new(from, to - from))
-                })
-            })
+    pub fn differential(&self) -> Option<RayDifferential> {
+        self.differential
     }
 
-    /// create a camera which is at `pos` and look at `point`.
-    pub fn new<T: Into<Vec3>>(from: T, to: T) -> Camera {
-        let mut camera = Camera {
-            pos: from.into(),
-            up: Vec3::new(0., 0., 1.),
-            sight: Vec3::new(0., 0., 1.),
-            sample_rate: 1,
-            focus_dist: 1.,
-            aperture: 0.,
-            fov: 45.,
-            aspect: 1.,
-        };
-        camera.look(to.into());
-        camera
+    /// `color` as carried by this ray, upsampled to a spectrum in spectral mode.
+    pub fn tint(&self, color: Color) -> Color {
+        self.wavelengths
+            .map_or(color, |lambda| spectrum::upsample_at(color, lambda))
     }
 }
 
@@ -193,8 +149,7 @@ impl HitRecord {
     }
 
     pub fn specular_ray(&self) -> Ray {
-        let pos = self.pos();
-        Ray::new(pos, self.info.dir_out)
+        self.info.reflect()
     }
 
     pub fn diffuse_ray(&self) -> Ray {
@@ -203,7 +158,9 @@ impl HitRecord {
         let p = gen_point_in_sphere(1.);
         let t = o + p;
         let dir = (t - pos).unit();
-        Ray::new(pos, dir)
+        let mut ray = Ray::new(pos, dir);
+        ray.wavelengths = self.info.wavelengths;
+        ray
     }
 
     pub fn pos(&self) -> Vec3 {
@@ -227,6 +184,51 @@ pub struct HitInfo {
     dir_in: Vec3,
     dir_out: Vec3,
     outward: bool,
+    uv: (f64, f64),
+    tangent: Option<Vec3>,
+    wavelengths: Option<Vec3>,
+    differential: Option<HitDifferential>,
+}
+
+// where the offset rays of a differential hit the surface, with the normals facing them
+#[derive(Clone, Copy, Debug)]
+struct HitDifferential {
+    px: Vec3,
+    nx: Vec3,
+    dx: Vec3,
+    py: Vec3,
+    ny: Vec3,
+    dy: Vec3,
+    duv_dx: (f64, f64),
+    duv_dy: (f64, f64),
+}
+
+impl HitDifferential {
+    // offset rays leaving towards `dx` and `dy`, like `HitInfo::spawn`
+    fn spawn(&self, dx: Vec3, dy: Vec3) -> RayDifferential {
+        let (dx, dy) = (dx.unit(), dy.unit());
+        RayDifferential {
+            rx_pos: self.px + EPS * dx,
+            rx_dir: dx,
+            ry_pos: self.py + EPS * dy,
+            ry_dir: dy,
+        }
+    }
+}
+
+fn mirror(d: Vec3, n: Vec3) -> Vec3 {
+    d - 2. * d.dot(n) * n
+}
+
+// bend `d` through a surface with normal `n` facing against it, see `HitInfo::refract`
+fn bend(d: Vec3, n: Vec3, ratio: f64) -> Option<Vec3> {
+    let cos = d.dot(n);
+    let discriminant = 1.0 - ratio.powi(2) * (1.0 - cos.powi(2));
+    if discriminant > 0.0 {
+        Some(ratio * (d - n * cos) - n * discriminant.sqrt())
+    } else {
+        None
+    }
 }
 
 impl HitInfo {
@@ -247,13 +249,28 @@ impl HitInfo {
             dir_in,
             dir_out,
             outward,
+            uv: (0., 0.),
+            tangent: None,
+            wavelengths: None,
+            differential: None,
         }
     }
 
+    /// attach surface coordinate `(u, v)` used for texture lookup.
+    pub fn with_uv(mut self, u: f64, v: f64) -> HitInfo {
+        self.uv = (u, v);
+        self
+    }
+
     pub fn distance(&self) -> f64 {
         self.distance
     }
 
+    pub(crate) fn with_distance(mut self, distance: f64) -> HitInfo {
+        self.distance = distance;
+        self
+    }
+
     pub fn normal(&self) -> Vec3 {
         self.norm
     }
@@ -270,48 +287,148 @@ impl HitInfo {
         self.hit_point + EPS * self.dir_out
     }
 
+    pub fn uv(&self) -> (f64, f64) {
+        self.uv
+    }
+
+    /// attach the derivative of the position by `u`, which orients anisotropic materials.
+    pub fn with_tangent(mut self, dpdu: Vec3) -> HitInfo {
+        self.tangent = Some(dpdu);
+        self
+    }
+
+    pub fn tangent(&self) -> Option<Vec3> {
+        self.tangent
+    }
+
+    // shading frame with the first axis along the tangent, when the shape has one
+    pub(crate) fn frame(&self) -> Frame {
+        match self.tangent {
+            Some(tangent) => Frame::with_tangent(self.norm, tangent),
+            None => Frame::new(self.norm),
+        }
+    }
+
+    /// wavelengths carried by the incoming ray in spectral mode.
+    pub fn with_wavelengths(mut self, lambda: Option<Vec3>) -> HitInfo {
+        self.wavelengths = lambda;
+        self
+    }
+
+    pub fn wavelengths(&self) -> Option<Vec3> {
+        self.wavelengths
+    }
+
+    /// `color` as seen by the incoming ray, upsampled to a spectrum in spectral mode.
+    pub fn tint(&self, color: Color) -> Color {
+        self.wavelengths
+            .map_or(color, |lambda| spectrum::upsample_at(color, lambda))
+    }
+
+    /// follow the offset rays of `differential` to the surface, found by `hit`.
+    /// offset rays missing it hit the tangent plane instead.
+    pub(crate) fn with_differential<F>(mut self, differential: &RayDifferential, hit: F) -> HitInfo
+    where
+        F: Fn(&Ray) -> Option<HitInfo>,
+    {
+        let offset = |pos: Vec3, dir: Vec3| {
+            let ray = Ray::new(pos, dir);
+            if let Some(h) = hit(&ray) {
+                return Some((h.hit_point, h.norm, ray.dir, h.uv));
+            }
+            let cos = ray.dir.dot(self.norm);
+            if cos.abs() < EPS {
+                return None;
+            }
+            let t = (self.hit_point - pos).dot(self.norm) / cos;
+            Some((ray.at(t), self.norm, ray.dir, self.uv))
+        };
+        let duv = |(u, v): (f64, f64)| {
+            // surface coordinates may wrap around, like the longitude of a sphere
+            let wrap = |d: f64| if d.abs() > 0.5 { d - d.signum() } else { d };
+            (wrap(u - self.uv.0), wrap(v - self.uv.1))
+        };
+        let x = offset(differential.rx_pos, differential.rx_dir);
+        let y = offset(differential.ry_pos, differential.ry_dir);
+        self.differential = match (x, y) {
+            (Some((px, nx, dx, uvx)), Some((py, ny, dy, uvy))) => Some(HitDifferential {
+                px,
+                nx,
+                dx,
+                py,
+                ny,
+                dy,
+                duv_dx: duv(uvx),
+                duv_dy: duv(uvy),
+            }),
+            _ => None,
+        };
+        self
+    }
+
+    /// changes of the surface coordinates `(du, dv)` over a pixel to the right and down,
+    /// if the ray carried a differential.
+    pub fn uv_derivatives(&self) -> Option<((f64, f64), (f64, f64))> {
+        self.differential.map(|d| (d.duv_dx, d.duv_dy))
+    }
+
+    /// width in surface coordinates to filter textures over, 0 without a differential.
+    pub fn filter_width(&self) -> f64 {
+        self.uv_derivatives().map_or(0., |((ux, vx), (uy, vy))| {
+            let len = |u: f64, v: f64| (u * u + v * v).sqrt();
+            max!(len(ux, vx), len(uy, vy))
+        })
+    }
+
+    /// look `texture` up at this hit, filtered over the footprint of the ray.
+    pub fn texture<T: Texture + ?Sized>(&self, texture: &T) -> Color {
+        let (u, v) = self.uv;
+        texture.filtered(u, v, self.pos(), self.filter_width())
+    }
+
     pub fn is_to_outward(&self) -> bool {
         self.outward
     }
 
+    /// a ray leaving the hit point towards `dir`, offset to avoid hitting the surface again.
+    pub fn spawn(&self, dir: Vec3) -> Ray {
+        let dir = dir.unit();
+        let mut ray = Ray::new(self.hit_point + EPS * dir, dir);
+        ray.wavelengths = self.wavelengths;
+        ray
+    }
+
     pub fn ray_in(&self) -> Ray {
-        Ray {
-            pos: self.hit_point,
-            dir: self.dir_in,
-        }
+        let mut ray = Ray::new(self.hit_point, self.dir_in);
+        ray.wavelengths = self.wavelengths;
+        ray
     }
 
     pub fn reflect(&self) -> Ray {
-        Ray {
-            pos: self.pos(),
-            dir: self.dir_out,
-        }
+        let mut ray = Ray::new(self.pos(), self.dir_out);
+        ray.wavelengths = self.wavelengths;
+        ray.differential = self
+            .differential
+            .map(|d| d.spawn(mirror(d.dx, d.nx), mirror(d.dy, d.ny)));
+        ray
     }
 
     // see https://blog.csdn.net/yinhun2012/article/details/79472364 for details
     // ratio = inward material ior / outward material ior
     pub fn refract(&self, ratio: f64) -> Option<Ray> {
-        let uv = self.dir_in;
-        let n = self.norm;
-        let cos = uv.dot(n);
-
-        let discriminant = 1.0 - ratio.powi(2) * (1.0 - cos.powi(2));
-        if discriminant > 0.0 {
-            let dir = ratio * (uv - n * cos) - n * discriminant.sqrt();
-            Some(Ray {
-                pos: self.hit_point + EPS * dir,
-                dir,
-            })
-        } else {
-            None
-        }
+        let dir = bend(self.dir_in, self.norm, ratio)?;
+        let mut ray = self.spawn(dir);
+        ray.differential = self
+            .differential
+            .and_then(|d| Some(d.spawn(bend(d.dx, d.nx, ratio)?, bend(d.dy, d.ny, ratio)?)));
+        Some(ray)
     }
 
-    // Schlick's approximation
-    // see https://www.wikiwand.com/en/Schlick%27s_approximation for details
+    /// fresnel reflectance of an interface to a material of `ior` in vacuum,
+    /// for a ray entering or leaving the material.
     pub fn reflect_prob(&self, ior: f64) -> f64 {
-        let r0 = (1. - ior) / (1. + ior).powi(2);
+        let eta = if self.outward { 1. / ior } else { ior };
         let cos = self.dir_in.dot(self.norm).abs();
-        r0 + (1. - r0) * (1. - cos).powi(5)
+        fresnel_dielectric(cos, eta)
     }
 }
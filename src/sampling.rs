@@ -0,0 +1,164 @@
+use crate::util::{Vec3, PI};
+
+/// cosine weighted direction in the hemisphere around +z from `u` in [0, 1)^2, with density cos(theta) / pi.
+pub fn sample_cosine_hemisphere(u: (f64, f64)) -> Vec3 {
+    let (r, phi) = (u.0.sqrt(), 2. * PI * u.1);
+    vec3!(r * phi.cos(), r * phi.sin(), max!(1. - u.0, 0.).sqrt())
+}
+
+/// piecewise-constant distribution over [0, 1), built from function values at `n` equal steps.
+/// negative and non-finite values are taken as zero.
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Self {
+        let n = func.len();
+        assert!(n > 0, "empty distribution");
+        let func: Vec<f64> = func
+            .iter()
+            .map(|&f| if f.is_finite() && f > 0. { f } else { 0. })
+            .collect();
+        let mut cdf = vec![0.; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f64;
+        }
+        let integral = cdf[n];
+        if integral > 0. {
+            cdf.iter_mut().for_each(|c| *c /= integral);
+        } else {
+            // fall back to a uniform distribution
+            cdf.iter_mut()
+                .enumerate()
+                .for_each(|(i, c)| *c = i as f64 / n as f64);
+        }
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // index of the last cdf entry not greater than `u`, which skips zero-probability segments
+    fn find(&self, u: f64) -> usize {
+        let i = self.cdf.partition_point(|&c| c <= u);
+        min!(i.saturating_sub(1), self.count() - 1)
+    }
+
+    /// map `u` in [0, 1) to a point in [0, 1), returning the point, its density and the segment.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let i = self.find(u);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let du = if width > 0. {
+            (u - self.cdf[i]) / width
+        } else {
+            0.
+        };
+        let x = (i as f64 + du) / self.count() as f64;
+        (min!(x, 1. - f64::EPSILON), self.pdf(x), i)
+    }
+
+    /// map `u` in [0, 1) to a segment index, returning the index and its probability.
+    pub fn sample_discrete(&self, u: f64) -> (usize, f64) {
+        let i = self.find(u);
+        (i, self.pmf(i))
+    }
+
+    /// density of point `x` in [0, 1).
+    pub fn pdf(&self, x: f64) -> f64 {
+        let n = self.count();
+        let i = min!((x * n as f64) as usize, n - 1);
+        self.pmf(i) * n as f64
+    }
+
+    /// probability of segment `i`.
+    pub fn pmf(&self, i: usize) -> f64 {
+        self.cdf[i + 1] - self.cdf[i]
+    }
+}
+
+/// piecewise-constant distribution over [0, 1)^2, built from `nu * nv` function values stored row by row.
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Self {
+        assert!(nu > 0 && nv > 0, "empty distribution");
+        assert_eq!(func.len(), nu * nv, "function size mismatch");
+        let conditional: Vec<_> = func.chunks(nu).map(Distribution1D::new).collect();
+        let marginal: Vec<_> = conditional.iter().map(|d| d.integral()).collect();
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&marginal),
+        }
+    }
+
+    /// map `(u0, u1)` in [0, 1)^2 to a point `(u, v)` in [0, 1)^2, returning the point and its density.
+    pub fn sample(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    /// density of point `(u, v)` in [0, 1)^2.
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let nv = self.marginal.count();
+        let row = min!((v * nv as f64) as usize, nv - 1);
+        self.marginal.pdf(v) * self.conditional[row].pdf(u)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_distribution_1d() {
+        let d = Distribution1D::new(&[1., 0., 3.]);
+        assert_abs_diff_eq!(d.integral(), 4. / 3.);
+        assert_abs_diff_eq!(d.pmf(0), 0.25);
+        assert_abs_diff_eq!(d.pmf(1), 0.);
+        assert_abs_diff_eq!(d.pmf(2), 0.75);
+
+        let (x, pdf, i) = d.sample_continuous(0.125);
+        assert_eq!(i, 0);
+        assert_abs_diff_eq!(x, 1. / 6.);
+        assert_abs_diff_eq!(pdf, 0.75);
+
+        // the empty segment is never chosen
+        let (x, pdf, i) = d.sample_continuous(0.25);
+        assert_eq!(i, 2);
+        assert_abs_diff_eq!(x, 2. / 3.);
+        assert_abs_diff_eq!(pdf, 2.25);
+        assert_eq!(d.sample_discrete(0.9), (2, 0.75));
+
+        let uniform = Distribution1D::new(&[0., 0.]);
+        assert_abs_diff_eq!(uniform.pdf(0.3), 1.);
+    }
+
+    #[test]
+    fn test_distribution_2d() {
+        let d = Distribution2D::new(&[1., 1., 0., 2.], 2, 2);
+        assert_abs_diff_eq!(d.pdf(0.25, 0.25), 1.);
+        assert_abs_diff_eq!(d.pdf(0.25, 0.75), 0.);
+        assert_abs_diff_eq!(d.pdf(0.75, 0.75), 2.);
+        let ((u, v), pdf) = d.sample(0.5, 0.75);
+        assert!(u >= 0.5 && v >= 0.5);
+        assert_abs_diff_eq!(pdf, 2.);
+    }
+}
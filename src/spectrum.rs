@@ -0,0 +1,298 @@
+// spectral rendering with hero wavelength sampling, see
+// `Hero Wavelength Spectral Sampling` (Wilkie et al. 2014) for details.
+// in spectral mode each channel of a `Color` holds the value at one of three sampled wavelengths.
+
+use crate::util::{xyz_to_rgb, Color, Vec3};
+
+/// shortest sampled wavelength in nm
+pub const LAMBDA_MIN: f64 = 360.;
+/// longest sampled wavelength in nm
+pub const LAMBDA_MAX: f64 = 830.;
+
+/// wavelengths in nm standing in for the red, green and blue channels when rendering in rgb
+pub const RGB_WAVELENGTHS: Vec3 = Vec3 {
+    x: 630.,
+    y: 532.,
+    z: 465.,
+};
+
+// integral of the y matching function over the sampled range
+const CIE_Y_INTEGRAL: f64 = 106.922;
+
+// rgb of the equal energy spectrum, divided out so that a flat spectrum is white
+const WHITE: [f64; 3] = [1.200_268, 0.949_699, 0.908_296];
+
+/// sample a hero wavelength from `u` in [0, 1), with two more rotated by a third of the range.
+pub fn sample_wavelengths(u: f64) -> Vec3 {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let at = |i: f64| LAMBDA_MIN + ((u + i / 3.) % 1.) * range;
+    vec3!(at(0.), at(1.), at(2.))
+}
+
+fn gaussian(x: f64, mu: f64, sigma_lo: f64, sigma_hi: f64) -> f64 {
+    let sigma = if x < mu { sigma_lo } else { sigma_hi };
+    (-0.5 * ((x - mu) / sigma).powi(2)).exp()
+}
+
+/// cie 1931 color matching functions at `lambda` nm, using the multi-lobe fit of
+/// `Simple Analytic Approximations to the CIE XYZ Color Matching Functions` (Wyman et al. 2013).
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let l = lambda;
+    vec3!(
+        1.056 * gaussian(l, 599.8, 37.9, 31.0) + 0.362 * gaussian(l, 442.0, 16.0, 26.7)
+            - 0.065 * gaussian(l, 501.1, 20.4, 26.2),
+        0.821 * gaussian(l, 568.8, 46.9, 40.5) + 0.286 * gaussian(l, 530.9, 16.3, 31.1),
+        1.217 * gaussian(l, 437.0, 11.8, 36.0) + 0.681 * gaussian(l, 459.0, 26.0, 13.8)
+    )
+}
+
+fn xyz_to_white_balanced_rgb(xyz: Vec3) -> Color {
+    let rgb = xyz_to_rgb(xyz / CIE_Y_INTEGRAL);
+    vec3!(rgb.x / WHITE[0], rgb.y / WHITE[1], rgb.z / WHITE[2])
+}
+
+/// rgb estimate of the spectrum with `values` at the uniformly sampled wavelengths `lambda`.
+pub fn to_rgb(values: Vec3, lambda: Vec3) -> Color {
+    let xyz =
+        values.x * cie_xyz(lambda.x) + values.y * cie_xyz(lambda.y) + values.z * cie_xyz(lambda.z);
+    // divide by the uniform pdf and the number of samples
+    xyz_to_white_balanced_rgb(xyz * (LAMBDA_MAX - LAMBDA_MIN) / 3.)
+}
+
+/// rgb of the spectrum `f` by integrating it over the sampled range.
+pub fn integrate_rgb<F: Fn(f64) -> f64>(f: F) -> Color {
+    let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+    let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+    let xyz = (0..steps)
+        .map(|i| {
+            let l = LAMBDA_MIN + (i as f64 + 0.5) * step;
+            f(l) * cie_xyz(l)
+        })
+        .sum::<Vec3>();
+    xyz_to_white_balanced_rgb(xyz * step)
+}
+
+// reflectance spectra of `An RGB to Spectrum Conversion for Reflectances` (Smits 1999),
+// in 10 equal bins over [380, 720] nm
+const SMITS_WHITE: [f64; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const SMITS_MAGENTA: [f64; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const SMITS_BLUE: [f64; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// value at `lambda` nm of a smooth spectrum with color `rgb`.
+pub fn upsample(rgb: Color, lambda: f64) -> f64 {
+    let bin = ((lambda - 380.) / 34.).floor();
+    let i = max!(0., min!(9., bin)) as usize;
+    let Vec3 { x: r, y: g, z: b } = rgb;
+    let (w, c, m, y) = (
+        SMITS_WHITE[i],
+        SMITS_CYAN[i],
+        SMITS_MAGENTA[i],
+        SMITS_YELLOW[i],
+    );
+    let (red, green, blue) = (SMITS_RED[i], SMITS_GREEN[i], SMITS_BLUE[i]);
+    // white for the smallest component, then the secondary and primary colors for the rest
+    if r <= g && r <= b {
+        if g <= b {
+            r * w + (g - r) * c + (b - g) * blue
+        } else {
+            r * w + (b - r) * c + (g - b) * green
+        }
+    } else if g <= r && g <= b {
+        if r <= b {
+            g * w + (r - g) * m + (b - r) * blue
+        } else {
+            g * w + (b - g) * m + (r - b) * red
+        }
+    } else if r <= g {
+        b * w + (r - b) * y + (g - r) * green
+    } else {
+        b * w + (g - b) * y + (r - g) * red
+    }
+}
+
+/// values of the spectrum with color `rgb` at each of the wavelengths `lambda`.
+pub fn upsample_at(rgb: Color, lambda: Vec3) -> Vec3 {
+    vec3!(
+        upsample(rgb, lambda.x),
+        upsample(rgb, lambda.y),
+        upsample(rgb, lambda.z)
+    )
+}
+
+/// value at `lambda` nm of a physical quantity like a complex ior, given as `rgb` at
+/// `RGB_WAVELENGTHS`. it is interpolated linearly in between and held beyond, so unlike
+/// `upsample` it keeps values outside of [0, 1].
+pub fn interpolate_rgb(rgb: Color, lambda: f64) -> f64 {
+    let (r, g, b) = (RGB_WAVELENGTHS.x, RGB_WAVELENGTHS.y, RGB_WAVELENGTHS.z);
+    let lerp = |l0: f64, v0: f64, l1: f64, v1: f64| v0 + (v1 - v0) * (lambda - l0) / (l1 - l0);
+    if lambda <= b {
+        rgb.z
+    } else if lambda <= g {
+        lerp(b, rgb.z, g, rgb.y)
+    } else if lambda <= r {
+        lerp(g, rgb.y, r, rgb.x)
+    } else {
+        rgb.x
+    }
+}
+
+/// values of the quantity with `rgb` at each of the wavelengths `lambda`, see `interpolate_rgb`.
+pub fn interpolate_rgb_at(rgb: Color, lambda: Vec3) -> Vec3 {
+    vec3!(
+        interpolate_rgb(rgb, lambda.x),
+        interpolate_rgb(rgb, lambda.y),
+        interpolate_rgb(rgb, lambda.z)
+    )
+}
+
+/// spectral radiance of a black body at `temperature` kelvin for `lambda` nm, in W/(sr m^2 nm).
+pub fn blackbody(lambda: f64, temperature: f64) -> f64 {
+    if temperature <= 0. {
+        return 0.;
+    }
+    let (c, h, kb) = (299_792_458., 6.626_070_15e-34, 1.380_649e-23);
+    let l = lambda * 1e-9;
+    let radiance = 2. * h * c * c / (l.powi(5) * ((h * c / (l * kb * temperature)).exp() - 1.));
+    radiance * 1e-9
+}
+
+/// black body spectrum at `temperature` kelvin scaled to a peak of 1.
+pub fn blackbody_normalized(lambda: f64, temperature: f64) -> f64 {
+    // wien's displacement law
+    let peak = 2.897_771_955e6 / temperature;
+    blackbody(lambda, temperature) / blackbody(peak, temperature)
+}
+
+/// index of refraction, possibly varying with wavelength.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ior {
+    Constant(f64),
+    /// `a + b / lambda^2` with lambda in micrometer
+    Cauchy {
+        a: f64,
+        b: f64,
+    },
+    /// `sqrt(1 + sum(b * lambda^2 / (lambda^2 - c)))` with lambda in micrometer
+    Sellmeier {
+        b: [f64; 3],
+        c: [f64; 3],
+    },
+}
+
+impl Ior {
+    /// schott n-bk7 crown glass
+    pub fn bk7() -> Self {
+        Ior::Sellmeier {
+            b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            c: [6.000_698_67e-3, 2.001_791_44e-2, 103.560_653],
+        }
+    }
+
+    pub fn fused_silica() -> Self {
+        Ior::Sellmeier {
+            b: [0.696_166_3, 0.407_942_6, 0.897_479_4],
+            c: [4.679_148_26e-3, 1.351_206_31e-2, 97.934_002_5],
+        }
+    }
+
+    pub fn diamond() -> Self {
+        Ior::Sellmeier {
+            b: [4.3356, 0.3306, 0.],
+            c: [0.011_236, 0.030_625, 0.],
+        }
+    }
+
+    /// index of refraction at `lambda` nm.
+    pub fn at(&self, lambda: f64) -> f64 {
+        let l = lambda * 1e-3;
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy { a, b } => a + b / (l * l),
+            Ior::Sellmeier { b, c } => {
+                let l2 = l * l;
+                let sum: f64 = b.iter().zip(c.iter()).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1. + sum).sqrt()
+            }
+        }
+    }
+
+    /// index of refraction at the sodium d line, used when rendering in rgb.
+    pub fn nominal(&self) -> f64 {
+        self.at(587.6)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spectrum_to_rgb() {
+        // a flat spectrum is white, estimated with stratified hero wavelengths
+        let n = 1000;
+        let estimate = |rgb: Color| {
+            (0..n)
+                .map(|i| {
+                    let lambda = sample_wavelengths((i as f64 + 0.5) / n as f64);
+                    to_rgb(upsample_at(rgb, lambda), lambda)
+                })
+                .sum::<Vec3>()
+                / n as f64
+        };
+        assert_abs_diff_eq!(estimate(vec3!(1, 1, 1)), vec3!(1, 1, 1), epsilon = 1e-2);
+        assert_abs_diff_eq!(estimate(vec3!(1, 0, 0)), vec3!(1, 0, 0), epsilon = 2e-2);
+        assert_abs_diff_eq!(
+            estimate(vec3!(0.2, 0.5, 0.3)),
+            vec3!(0.2, 0.5, 0.3),
+            epsilon = 2e-2
+        );
+        assert_abs_diff_eq!(integrate_rgb(|_| 0.5), vec3!(0.5, 0.5, 0.5), epsilon = 1e-3);
+    }
+
+    #[test]
+    fn test_blackbody() {
+        assert_abs_diff_eq!(blackbody_normalized(2.897_771_955e6 / 5000., 5000.), 1.);
+        // a cooler body is redder
+        let warm = integrate_rgb(|l| blackbody_normalized(l, 2700.));
+        let cold = integrate_rgb(|l| blackbody_normalized(l, 10000.));
+        assert!(warm.x / warm.z > cold.x / cold.z);
+    }
+
+    #[test]
+    fn test_interpolate_rgb() {
+        let rgb = vec3!(4, 2.5, 1.5);
+        assert_abs_diff_eq!(interpolate_rgb_at(rgb, RGB_WAVELENGTHS), rgb);
+        assert_abs_diff_eq!(interpolate_rgb(rgb, 400.), 1.5);
+        assert_abs_diff_eq!(interpolate_rgb(rgb, 800.), 4.);
+        assert_abs_diff_eq!(interpolate_rgb(rgb, 581.), 3.25);
+    }
+
+    #[test]
+    fn test_ior() {
+        assert_abs_diff_eq!(Ior::bk7().nominal(), 1.5168, epsilon = 1e-4);
+        assert_abs_diff_eq!(Ior::fused_silica().nominal(), 1.4585, epsilon = 1e-4);
+        assert_abs_diff_eq!(Ior::diamond().nominal(), 2.417, epsilon = 2e-3);
+        // normal dispersion, blue bends more
+        let cauchy = Ior::Cauchy { a: 1.5, b: 0.004 };
+        assert!(cauchy.at(450.) > cauchy.at(650.));
+        assert!(Ior::bk7().at(450.) > Ior::bk7().at(650.));
+    }
+}
@@ -0,0 +1,234 @@
+use std::sync::Arc;
+
+use crate::{
+    image::Image,
+    util::{Color, Vec3},
+};
+
+pub trait Texture: Sync + Send {
+    /// color at surface coordinate `(u, v)` of point `p`.
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Color;
+
+    /// color averaged over about `width` in surface coordinates around `(u, v)`,
+    /// which avoids aliasing of fine details. defaults to the plain value.
+    fn filtered(&self, u: f64, v: f64, p: Vec3, _width: f64) -> Color {
+        self.value(u, v, p)
+    }
+}
+
+// a plain color is a constant texture
+impl Texture for Color {
+    fn value(&self, _u: f64, _v: f64, _p: Vec3) -> Color {
+        *self
+    }
+}
+
+// and a plain number a constant gray one
+impl Texture for f64 {
+    fn value(&self, _u: f64, _v: f64, _p: Vec3) -> Color {
+        (*self, *self, *self).into()
+    }
+}
+
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Color {
+        self.as_ref().value(u, v, p)
+    }
+
+    fn filtered(&self, u: f64, v: f64, p: Vec3, width: f64) -> Color {
+        self.as_ref().filtered(u, v, p, width)
+    }
+}
+
+/// gray texture from a single channel (0: red, 1: green, 2: blue) of `texture`,
+/// e.g. roughness and metallic packed into one glTF image.
+#[derive(Debug, Clone)]
+pub struct Channel<T> {
+    texture: T,
+    index: usize,
+}
+
+impl<T: Texture> Channel<T> {
+    pub fn new(texture: T, index: usize) -> Self {
+        assert!(index < 3, "channel index out of range");
+        Channel { texture, index }
+    }
+
+    fn pick(&self, c: Color) -> Color {
+        let x = [c.x, c.y, c.z][self.index];
+        (x, x, x).into()
+    }
+}
+
+impl<T: Texture> Texture for Channel<T> {
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Color {
+        self.pick(self.texture.value(u, v, p))
+    }
+
+    fn filtered(&self, u: f64, v: f64, p: Vec3, width: f64) -> Color {
+        self.pick(self.texture.filtered(u, v, p, width))
+    }
+}
+
+/// `texture` multiplied by a constant `factor`.
+#[derive(Debug, Clone)]
+pub struct Scaled<T> {
+    texture: T,
+    factor: Color,
+}
+
+impl<T: Texture> Scaled<T> {
+    pub fn new<C: Into<Color>>(texture: T, factor: C) -> Self {
+        Scaled {
+            texture,
+            factor: factor.into(),
+        }
+    }
+}
+
+impl<T: Texture> Texture for Scaled<T> {
+    fn value(&self, u: f64, v: f64, p: Vec3) -> Color {
+        self.factor * self.texture.value(u, v, p)
+    }
+
+    fn filtered(&self, u: f64, v: f64, p: Vec3, width: f64) -> Color {
+        self.factor * self.texture.filtered(u, v, p, width)
+    }
+}
+
+/// image mapped over [0, 1]^2 of the surface coordinates, with a mip map of
+/// successively halved copies for filtered lookups.
+#[derive(Debug, Clone)]
+pub struct ImageTexture {
+    image: Arc<Image>,
+    mips: Arc<Vec<Image>>,
+}
+
+impl ImageTexture {
+    pub fn new(image: Image) -> Self {
+        let mut mips: Vec<Image> = Vec::new();
+        loop {
+            let last = mips.last().unwrap_or(&image);
+            if last.width() == 1 && last.height() == 1 {
+                break;
+            }
+            let next = downsample(last);
+            mips.push(next);
+        }
+        ImageTexture {
+            image: Arc::new(image),
+            mips: Arc::new(mips),
+        }
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// `level` 0 is the image itself, each one after half as large down to a single pixel.
+    pub fn level(&self, level: usize) -> &Image {
+        match level {
+            0 => &self.image,
+            _ => &self.mips[min!(level, self.mips.len()) - 1],
+        }
+    }
+
+    pub fn levels(&self) -> usize {
+        self.mips.len() + 1
+    }
+}
+
+// halve the image with a box filter, which spans 2 + 1/k pixels of odd sizes 2k+1
+fn downsample(image: &Image) -> Image {
+    let (w, h) = (image.width(), image.height());
+    let (nw, nh) = (max!(w / 2, 1), max!(h / 2, 1));
+    let xs: Vec<_> = (0..nw).map(|x| box_taps(w, x)).collect();
+    let ys: Vec<_> = (0..nh).map(|y| box_taps(h, y)).collect();
+    let pixels = ys
+        .iter()
+        .flat_map(|ty| xs.iter().map(move |tx| (tx, ty)))
+        .map(|(tx, ty)| {
+            let mut sum = Color::new(0., 0., 0.);
+            for &(y, wy) in ty {
+                for &(x, wx) in tx {
+                    sum += wx * wy * image.get(x, y);
+                }
+            }
+            sum
+        })
+        .collect();
+    Image::from_pixels(nw, nh, pixels)
+}
+
+// source pixels and their weights in pixel `x` of a row of `n` pixels halved
+fn box_taps(n: usize, x: usize) -> Vec<(usize, f64)> {
+    if n == 1 {
+        return vec![(0, 1.)];
+    }
+    if n % 2 == 1 {
+        let (k, n) = ((n / 2) as f64, n as f64);
+        let x0 = x as f64;
+        return vec![
+            (2 * x, (k - x0) / n),
+            (2 * x + 1, k / n),
+            (2 * x + 2, (x0 + 1.) / n),
+        ];
+    }
+    vec![(2 * x, 0.5), (2 * x + 1, 0.5)]
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: Vec3) -> Color {
+        self.image.sample(u, v)
+    }
+
+    fn filtered(&self, u: f64, v: f64, p: Vec3, width: f64) -> Color {
+        // the level where `width` spans about a pixel, blending the two nearest ones
+        let size = max!(self.image.width(), self.image.height()) as f64;
+        let level = (width * size).log2();
+        if level.is_nan() || level <= 0. {
+            return self.value(u, v, p);
+        }
+        let last = (self.levels() - 1) as f64;
+        if level >= last {
+            return self.level(self.levels() - 1).sample(u, v);
+        }
+        let (i, t) = (level.floor(), level - level.floor());
+        let a = self.level(i as usize).sample(u, v);
+        let b = self.level(i as usize + 1).sample(u, v);
+        a * (1. - t) + b * t
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_downsample() {
+        // 3x3 pixels average into one, even sizes average pairs
+        let pixels = (0..9).map(|i| vec3!(i, 0, 0)).collect();
+        let image = downsample(&Image::from_pixels(3, 3, pixels));
+        assert_eq!((image.width(), image.height()), (1, 1));
+        assert_abs_diff_eq!(image.get(0, 0), vec3!(4, 0, 0), epsilon = 1e-9);
+        let pixels = (0..4).map(|i| vec3!(i, 0, 0)).collect();
+        let image = downsample(&Image::from_pixels(4, 1, pixels));
+        assert_abs_diff_eq!(image.get(0, 0), vec3!(0.5, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(image.get(1, 0), vec3!(2.5, 0, 0), epsilon = 1e-9);
+
+        // the middle pixel of 5 is split between both halves, keeping the mean
+        let pixels = vec![
+            vec3!(1, 0, 0),
+            vec3!(1, 0, 0),
+            vec3!(6, 0, 0),
+            vec3!(0, 0, 0),
+            vec3!(0, 0, 0),
+        ];
+        let image = downsample(&Image::from_pixels(5, 1, pixels));
+        assert_abs_diff_eq!(image.get(0, 0), vec3!(2, 0, 0), epsilon = 1e-9);
+        assert_abs_diff_eq!(image.get(1, 0), vec3!(1.2, 0, 0), epsilon = 1e-9);
+        let levels = ImageTexture::new(Image::from_pixels(5, 3, vec![vec3!(1, 2, 3); 15]));
+        assert_eq!(levels.levels(), 3);
+        assert_abs_diff_eq!(levels.level(2).get(0, 0), vec3!(1, 2, 3), epsilon = 1e-9);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::util::{Vec3, PI};
+
+type Matrix = [[f64; 3]; 3];
+
+const IDENTITY: Matrix = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn apply(m: &Matrix, v: Vec3) -> Vec3 {
+    vec3!(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z
+    )
+}
+
+fn transpose(m: &Matrix) -> Matrix {
+    let mut t = [[0.; 3]; 3];
+    for (i, row) in t.iter_mut().enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x = m[j][i];
+        }
+    }
+    t
+}
+
+/// affine transform made of scaling, rotation and translation, keeping its inverse along.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    m: Matrix,
+    inv: Matrix,
+    t: Vec3,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform {
+            m: IDENTITY,
+            inv: IDENTITY,
+            t: (0., 0., 0.).into(),
+        }
+    }
+
+    pub fn translate<T: Into<Vec3>>(delta: T) -> Self {
+        Transform {
+            t: delta.into(),
+            ..Transform::identity()
+        }
+    }
+
+    /// scale by each component of `s` along the axes.
+    pub fn scale<T: Into<Vec3>>(s: T) -> Self {
+        let s = s.into();
+        Transform {
+            m: [[s.x, 0., 0.], [0., s.y, 0.], [0., 0., s.z]],
+            inv: [[1. / s.x, 0., 0.], [0., 1. / s.y, 0.], [0., 0., 1. / s.z]],
+            ..Transform::identity()
+        }
+    }
+
+    /// rotate counterclockwise around `axis` by `deg` degree.
+    pub fn rotate<T: Into<Vec3>>(axis: T, deg: f64) -> Self {
+        let a = axis.into().unit();
+        let (sin, cos) = (deg / 180. * PI).sin_cos();
+        let c = 1. - cos;
+        let m = [
+            [
+                cos + a.x * a.x * c,
+                a.x * a.y * c - a.z * sin,
+                a.x * a.z * c + a.y * sin,
+            ],
+            [
+                a.y * a.x * c + a.z * sin,
+                cos + a.y * a.y * c,
+                a.y * a.z * c - a.x * sin,
+            ],
+            [
+                a.z * a.x * c - a.y * sin,
+                a.z * a.y * c + a.x * sin,
+                cos + a.z * a.z * c,
+            ],
+        ];
+        Transform {
+            m,
+            inv: transpose(&m),
+            ..Transform::identity()
+        }
+    }
+
+    /// apply this transform first and `next` after it.
+    pub fn then(self, next: Transform) -> Self {
+        Transform {
+            m: mul(&next.m, &self.m),
+            inv: mul(&self.inv, &next.inv),
+            t: apply(&next.m, self.t) + next.t,
+        }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Transform {
+            m: self.inv,
+            inv: self.m,
+            t: -apply(&self.inv, self.t),
+        }
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        apply(&self.m, p) + self.t
+    }
+
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        apply(&self.m, v)
+    }
+
+    /// transform a surface normal, which stays perpendicular to transformed tangents.
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        apply(&transpose(&self.inv), n).unit()
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transform() {
+        let t = Transform::scale((2., 1., 1.))
+            .then(Transform::rotate((0., 0., 1.), 90.))
+            .then(Transform::translate((1., 2., 3.)));
+        let p = vec3!(1, 1, 0);
+        assert_abs_diff_eq!(t.point(p), vec3!(0, 4, 3), epsilon = 1e-9);
+        assert_abs_diff_eq!(t.inverse().point(t.point(p)), p, epsilon = 1e-9);
+        assert_abs_diff_eq!(t.vector(vec3!(1, 0, 0)), vec3!(0, 2, 0), epsilon = 1e-9);
+        // normals of a stretched plane stay perpendicular to it
+        let shear = Transform::scale((1., 3., 1.)).then(Transform::rotate((1., 0., 0.), 30.));
+        let (tangent, n) = (vec3!(0, 1, -1), vec3!(0, 1, 1).unit());
+        assert_abs_diff_eq!(
+            shear.vector(tangent).dot(shear.normal(n)),
+            0.,
+            epsilon = 1e-9
+        );
+    }
+}
@@ -381,6 +381,21 @@ macro_rules! min {
 
 pub type Color = Vec3;
 
+/// relative luminance of a linear sRGB color.
+pub fn luminance(c: Color) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/// convert CIE XYZ to linear sRGB.
+pub fn xyz_to_rgb(xyz: Vec3) -> Color {
+    let Vec3 { x, y, z } = xyz;
+    vec3!(
+        3.240_454_2 * x - 1.537_138_5 * y - 0.498_531_4 * z,
+        -0.969_266_0 * x + 1.876_010_8 * y + 0.041_556_0 * z,
+        0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z
+    )
+}
+
 pub(crate) fn gen_point_in_sphere(radius: f64) -> Vec3 {
     let mut rng = rand::thread_rng();
     let r = radius;
@@ -396,11 +411,57 @@ pub(crate) fn gen_point_in_sphere(radius: f64) -> Vec3 {
 
 pub(crate) fn gen_point_in_disk(radius: f64) -> Vec3 {
     let mut rng = rand::thread_rng();
-    let theta = rng.gen_range(0., PI);
-    let r = rng.gen_range(0., 1.);
+    let theta = rng.gen_range(0., 2. * PI);
+    let r = rng.gen_range(0., 1f64).sqrt();
     radius * r * vec3!(theta.cos(), theta.sin(), 0.)
 }
 
+/// build two unit vectors which together with `n` form an orthonormal basis.
+pub(crate) fn coordinate_system(n: Vec3) -> (Vec3, Vec3) {
+    let sign = 1f64.copysign(n.z);
+    let a = -1. / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        vec3!(1. + sign * n.x * n.x * a, sign * b, -sign * n.x),
+        vec3!(b, sign + n.y * n.y * a, -n.y),
+    )
+}
+
+/// orthonormal shading frame with `n` as the local z axis.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Frame {
+    s: Vec3,
+    t: Vec3,
+    n: Vec3,
+}
+
+impl Frame {
+    pub(crate) fn new(n: Vec3) -> Self {
+        let (s, t) = coordinate_system(n);
+        Frame { s, t, n }
+    }
+
+    // frame around `n` with its first axis along `tangent` projected onto the surface,
+    // or an arbitrary one where the tangent vanishes
+    pub(crate) fn with_tangent(n: Vec3, tangent: Vec3) -> Self {
+        let s = tangent - tangent.dot(n) * n;
+        if s.len2() < 1e-12 {
+            return Frame::new(n);
+        }
+        let s = s.unit();
+        let t = n.cross(s);
+        Frame { s, t, n }
+    }
+
+    pub(crate) fn to_local(self, v: Vec3) -> Vec3 {
+        vec3!(v.dot(self.s), v.dot(self.t), v.dot(self.n))
+    }
+
+    pub(crate) fn to_world(self, v: Vec3) -> Vec3 {
+        v.x * self.s + v.y * self.t + v.z * self.n
+    }
+}
+
 pub trait ChunkIter<T, I: Iterator<Item=T>> {
     fn chunks(self, size: usize) -> Chunks<T, I>;
 }
@@ -481,7 +542,7 @@ mod test {
         assert_abs_diff_eq!(
             vec![vec3!(1, 2, 3), vec3!(10, 20, 30), vec3!(100, 200, 300)]
                 .into_iter()
-                .sum(),
+                .sum::<Vec3>(),
             vec3!(111, 222, 333)
         );
     }
@@ -491,6 +552,24 @@ mod test {
         assert_abs_diff_eq!(5., min!(max!(0., 10., 20., 30.), min!(6., 9., 7.), 5.));
     }
 
+    #[test]
+    fn test_coordinate_system() {
+        let normals = [
+            vec3!(0, 0, 1),
+            vec3!(0, 0, -1),
+            vec3!(1, 2, 3).unit(),
+            vec3!(-3, 1, -2).unit(),
+        ];
+        for &n in normals.iter() {
+            let (s, t) = coordinate_system(n);
+            assert_abs_diff_eq!(s.len(), 1.);
+            assert_abs_diff_eq!(t.len(), 1.);
+            assert_abs_diff_eq!(s.dot(t), 0.);
+            assert_abs_diff_eq!(s.dot(n), 0.);
+            assert_abs_diff_eq!(t.dot(n), 0.);
+        }
+    }
+
     #[test]
     fn test_gen_point_in_sphere() {
         (0..100000).for_each(|_| {